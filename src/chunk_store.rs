@@ -0,0 +1,292 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Stores chunks as files named after them in a single directory.  Unlike the `chunk_store` crate,
+//! whose directories are deleted when the store is dropped, a store opened in a given directory
+//! picks up the chunks left there by the last one, so chunks survive a vault restart along with
+//! the persisted account tables which refer to them.
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use sodiumoxide::randombytes;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use xor_name::XorName;
+
+/// Errors which can occur in a chunk store.
+#[derive(Debug)]
+pub enum Error {
+    /// The chunk would take the store beyond its maximum space.
+    StorageLimitHit,
+    /// No chunk is held under the given name.
+    ChunkNotFound,
+    /// I/O error reading or writing a chunk.
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+pub struct ChunkStore {
+    dir: PathBuf,
+    max_space: u64,
+    used_space: u64,
+    // Whether the directory is removed when the store is dropped.
+    temporary: bool,
+}
+
+impl ChunkStore {
+    /// Opens the store kept in `dir`, creating it if need be, along with any chunks already in it.
+    pub fn new(dir: PathBuf, max_space: u64) -> Result<ChunkStore, Error> {
+        try!(fs::create_dir_all(&dir));
+        let mut chunk_store = ChunkStore {
+            dir: dir,
+            max_space: max_space,
+            used_space: 0,
+            temporary: false,
+        };
+        for (_, path) in try!(chunk_store.chunk_paths()) {
+            chunk_store.used_space += try!(fs::metadata(path)).len();
+        }
+        if chunk_store.used_space > max_space {
+            warn!("Chunk store {:?} already holds {} bytes, more than its maximum of {}",
+                  chunk_store.dir,
+                  chunk_store.used_space,
+                  max_space);
+        }
+        Ok(chunk_store)
+    }
+
+    /// Creates an empty store in a new directory under the system temp dir, which is removed when
+    /// the store is dropped.
+    pub fn new_temporary(prefix: &str, max_space: u64) -> Result<ChunkStore, Error> {
+        let dir_name = format!("{}-{}", prefix, randombytes::randombytes(8).to_hex());
+        let dir = env::temp_dir().join(dir_name);
+        let mut chunk_store = try!(ChunkStore::new(dir, max_space));
+        chunk_store.temporary = true;
+        Ok(chunk_store)
+    }
+
+    /// Stores `value` under `name`, replacing any chunk already held under it.
+    pub fn put(&mut self, name: &XorName, value: &[u8]) -> Result<(), Error> {
+        let old_size = try!(self.chunk_size(name));
+        let used_space = self.used_space.saturating_sub(old_size);
+        if used_space.saturating_add(value.len() as u64) > self.max_space {
+            return Err(Error::StorageLimitHit);
+        }
+
+        // Written to a temporary file first, so that a crash part way through never leaves a
+        // truncated chunk behind.
+        let temp_path = self.dir.join(format!("{}.tmp", name.0.to_hex()));
+        {
+            let mut file = try!(File::create(&temp_path));
+            try!(file.write_all(value));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&temp_path, self.path(name)));
+        self.used_space = used_space + value.len() as u64;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &XorName) -> Result<Vec<u8>, Error> {
+        let mut file = match File::open(self.path(name)) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::ChunkNotFound)
+            }
+            Err(error) => return Err(Error::Io(error)),
+        };
+        let mut value = Vec::new();
+        let _ = try!(file.read_to_end(&mut value));
+        Ok(value)
+    }
+
+    /// Removes the chunk held under `name`.  Does nothing if there's no such chunk.
+    pub fn delete(&mut self, name: &XorName) -> Result<(), Error> {
+        if !self.has_chunk(name) {
+            return Ok(());
+        }
+        let size = try!(self.chunk_size(name));
+        try!(fs::remove_file(self.path(name)));
+        self.used_space = self.used_space.saturating_sub(size);
+        Ok(())
+    }
+
+    pub fn has_chunk(&self, name: &XorName) -> bool {
+        self.path(name).is_file()
+    }
+
+    /// Returns the names of all the chunks held.
+    pub fn names(&self) -> Vec<XorName> {
+        match self.chunk_paths() {
+            Ok(chunk_paths) => chunk_paths.into_iter().map(|(name, _)| name).collect(),
+            Err(error) => {
+                error!("Failed to list chunk store {:?}: {:?}", self.dir, error);
+                vec![]
+            }
+        }
+    }
+
+    pub fn has_space(&self, required_space: u64) -> bool {
+        self.used_space.saturating_add(required_space) <= self.max_space
+    }
+
+    pub fn max_space(&self) -> u64 {
+        self.max_space
+    }
+
+    pub fn used_space(&self) -> u64 {
+        self.used_space
+    }
+
+    fn path(&self, name: &XorName) -> PathBuf {
+        self.dir.join(name.0.to_hex())
+    }
+
+    // Returns the size of the chunk held under `name`, or 0 if there isn't one.
+    fn chunk_size(&self, name: &XorName) -> Result<u64, Error> {
+        match fs::metadata(self.path(name)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(error) => Err(Error::Io(error)),
+        }
+    }
+
+    // Lists the chunks in the directory.  Anything else, e.g. a temporary file left by a crash,
+    // is skipped.
+    fn chunk_paths(&self) -> Result<Vec<(XorName, PathBuf)>, Error> {
+        let mut chunk_paths = vec![];
+        for entry in try!(fs::read_dir(&self.dir)) {
+            let path = try!(entry).path();
+            let bytes = match path.file_name().and_then(|file_name| file_name.to_str()) {
+                Some(file_name) => {
+                    match file_name.from_hex() {
+                        Ok(bytes) => bytes,
+                        Err(_) => continue,
+                    }
+                }
+                None => continue,
+            };
+            if bytes.len() != 64 || !path.is_file() {
+                continue;
+            }
+            let mut name = [0u8; 64];
+            for (dst, src) in name.iter_mut().zip(bytes) {
+                *dst = src;
+            }
+            chunk_paths.push((XorName(name), path));
+        }
+        Ok(chunk_paths)
+    }
+}
+
+impl Drop for ChunkStore {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::random;
+    use rustc_serialize::hex::ToHex;
+    use sodiumoxide::randombytes;
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use utils::generate_random_vec_u8;
+    use xor_name::XorName;
+
+    fn temp_dir() -> PathBuf {
+        env::temp_dir().join(format!("safe_vault_chunk_store_{}",
+                                     randombytes::randombytes(8).to_hex()))
+    }
+
+    #[test]
+    fn put_get_delete() {
+        let mut chunk_store = unwrap_result!(ChunkStore::new(temp_dir(), 1000));
+        let name = random::<XorName>();
+        let value = generate_random_vec_u8(600);
+        unwrap_result!(chunk_store.put(&name, &value));
+        assert!(chunk_store.has_chunk(&name));
+        assert_eq!(unwrap_result!(chunk_store.get(&name)), value);
+        assert_eq!(chunk_store.used_space(), 600);
+        assert_eq!(chunk_store.names(), vec![name]);
+
+        // Replacing the chunk only counts the new value
+        let value = generate_random_vec_u8(800);
+        unwrap_result!(chunk_store.put(&name, &value));
+        assert_eq!(unwrap_result!(chunk_store.get(&name)), value);
+        assert_eq!(chunk_store.used_space(), 800);
+
+        // Nothing is stored beyond the maximum space
+        match chunk_store.put(&random(), &generate_random_vec_u8(201)) {
+            Err(Error::StorageLimitHit) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(chunk_store.names().len(), 1);
+
+        unwrap_result!(chunk_store.delete(&name));
+        assert!(!chunk_store.has_chunk(&name));
+        assert_eq!(chunk_store.used_space(), 0);
+        match chunk_store.get(&name) {
+            Err(Error::ChunkNotFound) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        // Deleting it again is harmless
+        unwrap_result!(chunk_store.delete(&name));
+        let _ = fs::remove_dir_all(&chunk_store.dir);
+    }
+
+    #[test]
+    fn reopen_existing_store() {
+        let dir = temp_dir();
+        let name = random::<XorName>();
+        let value = generate_random_vec_u8(100);
+        {
+            let mut chunk_store = unwrap_result!(ChunkStore::new(dir.clone(), 1000));
+            unwrap_result!(chunk_store.put(&name, &value));
+        }
+
+        // Anything which isn't a chunk, e.g. a temporary file left by a crash, is ignored
+        let temp_name = format!("{}.tmp", random::<XorName>().0.to_hex());
+        let _ = unwrap_result!(File::create(dir.join(temp_name)));
+
+        let chunk_store = unwrap_result!(ChunkStore::new(dir.clone(), 1000));
+        assert_eq!(chunk_store.names(), vec![name]);
+        assert_eq!(unwrap_result!(chunk_store.get(&name)), value);
+        assert_eq!(chunk_store.used_space(), 100);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn temporary_store_is_removed() {
+        let chunk_store = unwrap_result!(ChunkStore::new_temporary("safe_vault_chunk_store", 1000));
+        let dir = chunk_store.dir.clone();
+        assert!(dir.is_dir());
+        drop(chunk_store);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use docopt::{self, Docopt};
+use safe_vault::{self, Config, InternalError};
+use std::path::Path;
+
+static USAGE: &'static str = "
+Usage:
+  safe_vault [options]
+
+Options:
+  -c <path>, --config <path>        Read the vault config from <path> rather than the default
+                                    config file.
+  -d <dir>, --data-dir <dir>        Directory under which the chunk stores are created.
+  -s <bytes>, --max-space <bytes>   Maximum disk space in bytes for each chunk store.
+  -l <level>, --log-level <level>   Log level; one of error, warn, info, debug or trace.
+  --status-port <port>              Serve a JSON status snapshot on this localhost port.
+  --metrics-port <port>             Serve Prometheus-format metrics on this localhost port
+                                    (requires the metrics feature).
+  --mock-routing                    Run over mock routing rather than joining the network
+                                    (requires the use-mock-routing feature).
+  -V, --version                     Display version info and exit.
+  -h, --help                        Display this help message and exit.
+
+Flags take precedence over values set in the config file.
+";
+
+const LOG_LEVELS: [&'static str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, RustcDecodable)]
+pub struct Args {
+    flag_config: Option<String>,
+    flag_data_dir: Option<String>,
+    flag_max_space: Option<u64>,
+    flag_log_level: Option<String>,
+    flag_status_port: Option<u16>,
+    flag_metrics_port: Option<u16>,
+    flag_mock_routing: bool,
+}
+
+impl Args {
+    /// Parses the command line, exiting the process on `--help`, `--version` or invalid input.
+    pub fn parse() -> Args {
+        let args: Args = Docopt::new(USAGE)
+                             .and_then(|docopt| {
+                                 docopt.version(Some(safe_vault::name_and_version())).decode()
+                             })
+                             .unwrap_or_else(|error| error.exit());
+        if let Some(ref log_level) = args.flag_log_level {
+            if !LOG_LEVELS.contains(&&log_level[..]) {
+                docopt::Error::Argv(format!("Invalid log level \"{}\"", log_level)).exit();
+            }
+        }
+        args
+    }
+
+    pub fn log_level(&self) -> Option<&str> {
+        self.flag_log_level.as_ref().map(|log_level| &log_level[..])
+    }
+
+    /// Reads the config file (either the one given by `--config` or the default one) and applies
+    /// any overriding flags to it.  An explicitly given config file must be valid.
+    pub fn config(&self) -> Result<Config, InternalError> {
+        let config = match self.flag_config {
+            Some(ref path) => try!(safe_vault::read_config_file_from(Path::new(path))),
+            None => {
+                match safe_vault::read_config_file() {
+                    Ok(config) => config,
+                    Err(error) => {
+                        warn!("Couldn't read the default vault config file ({:?}).  Using default \
+                               config.",
+                              error);
+                        Config::default()
+                    }
+                }
+            }
+        };
+        Ok(self.apply_flags(config))
+    }
+
+    fn apply_flags(&self, mut config: Config) -> Config {
+        if let Some(ref data_dir) = self.flag_data_dir {
+            config.data_dir = Some(data_dir.clone());
+        }
+        if let Some(max_space) = self.flag_max_space {
+            config.pmid_node_max_space = Some(max_space);
+            config.structured_data_manager_max_space = Some(max_space);
+            config.mpid_manager_max_space = Some(max_space);
+        }
+        if let Some(status_port) = self.flag_status_port {
+            config.status_port = Some(status_port);
+        }
+        if let Some(metrics_port) = self.flag_metrics_port {
+            config.metrics_port = Some(metrics_port);
+        }
+        if self.flag_mock_routing {
+            config.use_mock_routing = Some(true);
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Args, USAGE};
+    use docopt::Docopt;
+    use safe_vault::{Config, InternalError};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn parse(argv: &[&str]) -> Args {
+        let argv = ["safe_vault"].iter().chain(argv.iter()).map(|arg| arg.to_string());
+        unwrap_result!(Docopt::new(USAGE).and_then(|docopt| docopt.argv(argv).decode()))
+    }
+
+    fn write_config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("safe_vault_cli_{}.json", name));
+        let mut file = unwrap_result!(File::create(&path));
+        unwrap_result!(file.write_all(contents.as_bytes()));
+        path
+    }
+
+    #[test]
+    fn flags_override_config_file() {
+        let path = write_config_file("precedence",
+                                     "{ \"data_dir\": \"/from/file\", \"pmid_node_max_space\": 1, \
+                                      \"mpid_manager_max_space\": 2, \"status_port\": 3 }");
+        let path_arg = path.to_string_lossy().into_owned();
+
+        // Values only in the config file are kept
+        let config = unwrap_result!(parse(&["--config", &path_arg[..]]).config());
+        assert_eq!(config.data_dir, Some(String::from("/from/file")));
+        assert_eq!(config.pmid_node_max_space, Some(1));
+        assert_eq!(config.status_port, Some(3));
+
+        // Flags take precedence
+        let args = parse(&["--config",
+                           &path_arg[..],
+                           "--data-dir",
+                           "/from/flag",
+                           "--max-space",
+                           "5",
+                           "--mock-routing"]);
+        let config = unwrap_result!(args.config());
+        assert_eq!(config,
+                   Config {
+                       data_dir: Some(String::from("/from/flag")),
+                       pmid_node_max_space: Some(5),
+                       structured_data_manager_max_space: Some(5),
+                       mpid_manager_max_space: Some(5),
+                       status_port: Some(3),
+                       use_mock_routing: Some(true),
+                       ..Config::default()
+                   });
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn invalid_explicit_config_is_an_error() {
+        let path = write_config_file("invalid", "{ \"status_port\": \"not a port\" }");
+        let path_arg = path.to_string_lossy().into_owned();
+        match parse(&["--config", &path_arg[..]]).config() {
+            Err(InternalError::ConfigDecoding(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let _ = fs::remove_file(path);
+
+        let path = env::temp_dir().join("safe_vault_cli_missing.json");
+        let path_arg = path.to_string_lossy().into_owned();
+        match parse(&["--config", &path_arg[..]]).config() {
+            Err(InternalError::Io(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
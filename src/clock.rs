@@ -0,0 +1,62 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Source of the current time for the personas' timeouts, so that tests can control it.
+
+use time::SteadyTime;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use time::Duration;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SteadyTime;
+}
+
+/// Reads the system's monotonic clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SteadyTime {
+        SteadyTime::now()
+    }
+}
+
+/// Clock which only moves when told to.
+#[cfg(test)]
+pub struct MockClock {
+    now: Mutex<SteadyTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock { now: Mutex::new(SteadyTime::now()) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = unwrap_result!(self.now.lock());
+        *now = *now + duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SteadyTime {
+        *unwrap_result!(self.now.lock())
+    }
+}
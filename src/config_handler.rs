@@ -0,0 +1,181 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use config_file_handler::{self, FileHandler};
+use default_chunk_store;
+use error::InternalError;
+use personas::immutable_data_manager;
+use refresh_accumulator;
+use rustc_serialize::json;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Vault configuration, read from `<exe name>.vault.config` in any of the locations searched by
+/// `config_file_handler`.  Every field is optional; missing ones fall back to the defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Config {
+    /// Directory under which the chunk stores are created.  If `None`, the system temp dir is used.
+    pub data_dir: Option<String>,
+    /// Maximum disk space in bytes for the PmidNode's chunk store.
+    pub pmid_node_max_space: Option<u64>,
+    /// Maximum disk space in bytes for the StructuredDataManager's chunk store.
+    pub structured_data_manager_max_space: Option<u64>,
+    /// Maximum disk space in bytes for each of the MpidManager's inbox and outbox chunk stores.
+    pub mpid_manager_max_space: Option<u64>,
+    /// If set, a JSON status snapshot is served on this localhost port.
+    pub status_port: Option<u16>,
+    /// If set, Prometheus-format metrics are served on this localhost port.  Requires the vault
+    /// to be built with the `metrics` feature.
+    pub metrics_port: Option<u16>,
+    /// Seconds the ImmutableDataManager waits for PmidNodes to respond to a Get before treating
+    /// them as failed.
+    pub immutable_data_manager_get_timeout_secs: Option<u64>,
+    /// Number of close group members which must send the same refresh for an account before it's
+    /// taken over.
+    pub refresh_quorum: Option<usize>,
+    /// Run over mock routing rather than joining the real network.  Requires the vault to be
+    /// built with the `use-mock-routing` feature.
+    pub use_mock_routing: Option<bool>,
+}
+
+impl Config {
+    /// The data dir, if one is configured.
+    pub fn data_dir(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(PathBuf::from)
+    }
+
+    /// Maximum space for the PmidNode's chunk store, or the default if unset.
+    pub fn pmid_node_max_space(&self) -> u64 {
+        self.pmid_node_max_space.unwrap_or(default_chunk_store::DEFAULT_MAX_SPACE)
+    }
+
+    /// Maximum space for the StructuredDataManager's chunk store, or the default if unset.
+    pub fn structured_data_manager_max_space(&self) -> u64 {
+        self.structured_data_manager_max_space.unwrap_or(default_chunk_store::DEFAULT_MAX_SPACE)
+    }
+
+    /// Maximum space for each of the MpidManager's chunk stores, or the default if unset.
+    pub fn mpid_manager_max_space(&self) -> u64 {
+        self.mpid_manager_max_space.unwrap_or(default_chunk_store::DEFAULT_MAX_SPACE)
+    }
+
+    /// The ImmutableDataManager's Get timeout in seconds, or the default if unset.
+    pub fn immutable_data_manager_get_timeout_secs(&self) -> u64 {
+        self.immutable_data_manager_get_timeout_secs
+            .unwrap_or(immutable_data_manager::DEFAULT_GET_TIMEOUT_SECS)
+    }
+
+    /// The refresh quorum, or the default if unset.
+    pub fn refresh_quorum(&self) -> usize {
+        self.refresh_quorum.unwrap_or(refresh_accumulator::DEFAULT_QUORUM)
+    }
+
+    /// Whether to run over mock routing, which defaults to false.
+    pub fn use_mock_routing(&self) -> bool {
+        self.use_mock_routing.unwrap_or(false)
+    }
+}
+
+/// Reads the vault config file.
+pub fn read_config_file() -> Result<Config, InternalError> {
+    let file_handler = try!(FileHandler::new(&try!(get_file_name())));
+    Ok(try!(file_handler.read_file::<Config>()))
+}
+
+/// Reads the vault config from an explicitly specified file.
+pub fn read_config_file_from(path: &Path) -> Result<Config, InternalError> {
+    let mut contents = String::new();
+    let _ = try!(try!(File::open(path)).read_to_string(&mut contents));
+    Ok(try!(json::decode::<Config>(&contents)))
+}
+
+fn get_file_name() -> Result<OsString, InternalError> {
+    let mut name = try!(config_file_handler::exe_file_stem());
+    name.push(".vault.config");
+    Ok(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use default_chunk_store;
+    use error::InternalError;
+    use personas::immutable_data_manager;
+    use refresh_accumulator;
+    use rustc_serialize::hex::ToHex;
+    use rustc_serialize::json;
+    use sodiumoxide::randombytes;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn write_temp_file(contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("safe_vault_config_{}.json",
+                                                randombytes::randombytes(8).to_hex()));
+        let mut file = unwrap_result!(File::create(&path));
+        unwrap_result!(file.write_all(contents.as_bytes()));
+        path
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            data_dir: Some(String::from("/tmp/safe_vault")),
+            pmid_node_max_space: Some(1),
+            structured_data_manager_max_space: Some(2),
+            mpid_manager_max_space: Some(3),
+            status_port: Some(4),
+            metrics_port: Some(5),
+            immutable_data_manager_get_timeout_secs: Some(6),
+            refresh_quorum: Some(7),
+            use_mock_routing: Some(true),
+        };
+        let path = write_temp_file(&unwrap_result!(json::encode(&config)));
+        assert_eq!(unwrap_result!(read_config_file_from(&path)), config);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let path = write_temp_file("{ \"pmid_node_max_space\": 1024 }");
+        let config = unwrap_result!(read_config_file_from(&path));
+        assert_eq!(config, Config { pmid_node_max_space: Some(1024), ..Config::default() });
+        assert_eq!(config.pmid_node_max_space(), 1024);
+        assert_eq!(config.data_dir(), None);
+        assert_eq!(config.structured_data_manager_max_space(),
+                   default_chunk_store::DEFAULT_MAX_SPACE);
+        assert_eq!(config.mpid_manager_max_space(), default_chunk_store::DEFAULT_MAX_SPACE);
+        assert_eq!(config.immutable_data_manager_get_timeout_secs(),
+                   immutable_data_manager::DEFAULT_GET_TIMEOUT_SECS);
+        assert_eq!(config.refresh_quorum(), refresh_accumulator::DEFAULT_QUORUM);
+        assert!(!config.use_mock_routing());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn malformed_file_is_an_error() {
+        let path = write_temp_file("{ \"pmid_node_max_space\": \"lots\" }");
+        match read_config_file_from(&path) {
+            Err(InternalError::ConfigDecoding(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let _ = fs::remove_file(path);
+    }
+}
@@ -18,11 +18,19 @@
 //! Default parameters for ChunkStore.
 
 use chunk_store::{ChunkStore, Error};
+use std::path::Path;
 
 const PREFIX: &'static str = "safe-vault";
-const SIZE: u64 = 1073741824;
+// Directory under the data dir holding each persona's chunk store.
+const CHUNK_STORE_DIR: &'static str = "chunk_stores";
+pub const DEFAULT_MAX_SPACE: u64 = 1073741824;
 
-// Construct a new ChunkStore initialised with the default parameters.
-pub fn new() -> Result<ChunkStore, Error> {
-    ChunkStore::new(PREFIX, SIZE)
+// Open the ChunkStore called `name` under `data_dir`, which can hold up to `max_space` bytes.  It
+// keeps the chunks from the last run, so they match the account tables loaded from the state
+// store.  Without a data dir, a new store is created in the system temp dir and dropped on exit.
+pub fn new(data_dir: Option<&Path>, name: &str, max_space: u64) -> Result<ChunkStore, Error> {
+    match data_dir {
+        Some(data_dir) => ChunkStore::new(data_dir.join(CHUNK_STORE_DIR).join(name), max_space),
+        None => ChunkStore::new_temporary(PREFIX, max_space),
+    }
 }
@@ -16,35 +16,76 @@
 // relating to use of the SAFE Network Software.
 
 use chunk_store;
+use config_file_handler;
 use mpid_messaging;
 use maidsafe_utilities::serialisation::SerialisationError;
 use routing::{Authority, InterfaceError, MessageId, RoutingError, RoutingMessage};
+use rustc_serialize::json;
 use std::io;
 use types::Refresh;
+use xor_name::XorName;
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+/// Errors which are serialised and returned to clients in failure responses.
+#[derive(Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum ClientError {
+    /// The client has no account with its MaidManagers.
     NoSuchAccount,
+    /// The client already has an account.
     AccountExists,
+    /// The requested data isn't held.
     NoSuchData,
+    /// The data being stored is already held.
     DataExists,
+    /// The client's account doesn't have enough space left.
     LowBalance,
+    /// The request wasn't completed in time.
+    Timeout,
+    /// The vault doesn't have room to store the data.
+    NotEnoughSpace,
+    /// The vault deleted this Sacrificial copy to make room for other data.
+    HadToClearSacrificial,
 }
 
+/// Errors which can occur in the vault.
 #[derive(Debug)]
 pub enum InternalError {
+    /// A response arrived for a request we have no record of.
     FailedToFindCachedRequest(MessageId),
+    /// The request was rejected; the client has been told why.
     Client(ClientError),
+    /// No persona handles this message.
     UnknownMessageType(RoutingMessage),
+    /// No persona handles this refresh.
     UnknownRefreshType(Authority, Authority, Refresh),
+    /// The response doesn't match the request it answers.
     InvalidResponse,
+    /// We aren't in the close group of the name concerned.
     NotInCloseGroup,
+    /// A refresh was sent by this node, which isn't in the close group of the refreshed name.
+    RefreshSenderNotInCloseGroup(XorName),
+    /// A refresh from this node names a different account to the group it was sent from.
+    RefreshNameMismatch(XorName),
+    /// Error from a chunk store.
     ChunkStore(chunk_store::Error),
+    /// Error locating or reading the config file.
+    ConfigFileHandler(config_file_handler::Error),
+    /// The config file isn't valid.
+    ConfigDecoding(json::DecoderError),
+    /// Error from the MPID messaging crate.
     MpidMessaging(mpid_messaging::Error),
+    /// Error serialising or parsing a message or account.
     Serialisation(SerialisationError),
+    /// Error from the routing node's API.
     Routing(InterfaceError),
+    /// Error creating the routing node.
     RoutingInternal(RoutingError),
+    /// I/O error, e.g. from the state store or the local servers.
     Io(io::Error),
+    /// The persisted persona state is in a format this vault can't read or migrate.
+    UnsupportedStateVersion(u32),
+    /// Mock routing was requested, but the vault was built without the `use-mock-routing`
+    /// feature.
+    MockRoutingUnavailable,
 }
 
 impl From<ClientError> for InternalError {
@@ -59,6 +100,18 @@ impl From<chunk_store::Error> for InternalError {
     }
 }
 
+impl From<config_file_handler::Error> for InternalError {
+    fn from(error: config_file_handler::Error) -> InternalError {
+        InternalError::ConfigFileHandler(error)
+    }
+}
+
+impl From<json::DecoderError> for InternalError {
+    fn from(error: json::DecoderError) -> InternalError {
+        InternalError::ConfigDecoding(error)
+    }
+}
+
 impl From<mpid_messaging::Error> for InternalError {
     fn from(error: mpid_messaging::Error) -> InternalError {
         InternalError::MpidMessaging(error)
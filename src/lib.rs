@@ -0,0 +1,93 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! SAFE Vault provides the interface to SAFE routing.
+//!
+//! Besides the `safe_vault` executable, vaults can be embedded in-process using `VaultBuilder`:
+//!
+//! ```no_run
+//! use safe_vault::VaultBuilder;
+//!
+//! let mut builder = VaultBuilder::new().data_dir("/tmp/vault_0");
+//! let stop_handle = builder.stop_handle();
+//! let mut vault = builder.build().unwrap();
+//! // Calling `stop_handle.stop()` from another thread makes `run` return.
+//! # stop_handle.stop();
+//! vault.run().unwrap();
+//! ```
+
+#![doc(html_logo_url =
+           "https://raw.githubusercontent.com/maidsafe/QA/master/Images/maidsafe_logo.png",
+       html_favicon_url = "http://maidsafe.net/img/favicon.ico",
+       html_root_url = "http://maidsafe.github.io/safe_vault")]
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(bad_style, exceeding_bitshifts, mutable_transmutes, no_mangle_const_items,
+          unknown_crate_types, warnings)]
+#![deny(deprecated, drop_with_repr_extern, improper_ctypes, missing_docs,
+        non_shorthand_field_patterns, overflowing_literals, plugin_as_library,
+        private_no_mangle_fns, private_no_mangle_statics, stable_features, unconditional_recursion,
+        unknown_lints, unsafe_code, unused, unused_allocation, unused_attributes,
+        unused_comparisons, unused_features, unused_parens, while_true)]
+#![warn(trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+        unused_qualifications, unused_results)]
+#![allow(box_pointers, fat_ptr_transmutes, missing_copy_implementations,
+         missing_debug_implementations, variant_size_differences)]
+
+#![cfg_attr(feature="clippy", feature(plugin))]
+#![cfg_attr(feature="clippy", plugin(clippy))]
+#![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate maidsafe_utilities;
+extern crate mpid_messaging;
+extern crate config_file_handler;
+#[cfg(any(test, feature = "use-mock-routing"))]
+extern crate kademlia_routing_table;
+#[cfg(any(test, feature = "use-mock-routing"))]
+extern crate rand;
+extern crate routing;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate time;
+extern crate xor_name;
+
+mod chunk_store;
+mod clock;
+mod config_handler;
+mod default_chunk_store;
+mod error;
+mod local_server;
+mod metrics;
+mod mock_routing;
+mod personas;
+mod refresh_accumulator;
+mod routing_api;
+mod state_store;
+mod status;
+mod types;
+mod utils;
+mod vault;
+mod worker;
+
+pub use config_handler::{Config, read_config_file, read_config_file_from};
+pub use error::{ClientError, InternalError};
+pub use utils::{log_version, name_and_version};
+pub use vault::{ConfigHandle, StopHandle, Vault, VaultBuilder};
@@ -0,0 +1,163 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Minimal HTTP server bound to localhost which answers every request with the same generated
+//! body.  Used for the status and metrics endpoints.
+
+use error::InternalError;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// How long a client gets to send its request, or to read our response, before it's dropped.  The
+// server handles one connection at a time, so an idle client mustn't be able to hold it up.
+const CLIENT_TIMEOUT_SECS: u64 = 5;
+
+/// A running server.  It's stopped, and its port released, when this is dropped.
+pub struct LocalServer {
+    name: &'static str,
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    joiner: Option<JoinHandle<()>>,
+}
+
+impl LocalServer {
+    /// The address actually bound, which differs from the requested port if that was 0.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // The listening thread is blocked accepting connections, so give it one to wake it up.
+        let _ = TcpStream::connect(&self.address);
+        if let Some(joiner) = self.joiner.take() {
+            let _ = joiner.join();
+        }
+        info!("{} server on {} stopped", self.name, self.address);
+    }
+}
+
+/// Starts serving on `127.0.0.1:<port>`, calling `body` to generate each response.
+pub fn start<F>(name: &'static str,
+                port: u16,
+                content_type: &'static str,
+                body: F)
+                -> Result<LocalServer, InternalError>
+    where F: Fn() -> String + Send + 'static
+{
+    let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+    let address = try!(listener.local_addr());
+    info!("{} server listening on {}", name, address);
+    let stopping = Arc::new(AtomicBool::new(false));
+    let thread_stopping = stopping.clone();
+    let joiner = thread!(name, move || {
+        for stream in listener.incoming() {
+            if thread_stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Err(error) = respond(stream, content_type, &body) {
+                        warn!("{} server failed to respond: {:?}", name, error);
+                    }
+                }
+                Err(error) => warn!("{} server failed to accept connection: {:?}", name, error),
+            }
+        }
+    });
+    Ok(LocalServer {
+        name: name,
+        address: address,
+        stopping: stopping,
+        joiner: Some(joiner),
+    })
+}
+
+fn respond<F>(mut stream: TcpStream, content_type: &str, body: &F) -> Result<(), InternalError>
+    where F: Fn() -> String
+{
+    let timeout = Some(Duration::from_secs(CLIENT_TIMEOUT_SECS));
+    try!(stream.set_read_timeout(timeout));
+    try!(stream.set_write_timeout(timeout));
+
+    // Every request gets the same response, so just consume what the client sent.  The body is
+    // only generated once the request has arrived.
+    let mut request = [0u8; 1024];
+    let _ = try!(stream.read(&mut request));
+
+    let body = body();
+    let response = format!("HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                           content_type,
+                           body.len(),
+                           body);
+    Ok(try!(stream.write_all(response.as_bytes())))
+}
+
+// Fetches the body served at `address`, for use by the servers' tests.
+#[cfg(test)]
+pub fn get(address: &SocketAddr) -> String {
+    let mut stream = unwrap_result!(TcpStream::connect(address));
+    unwrap_result!(stream.write_all(b"GET / HTTP/1.0\r\n\r\n"));
+    let mut response = String::new();
+    let _ = unwrap_result!(stream.read_to_string(&mut response));
+    let body_start = unwrap_option!(response.find("\r\n\r\n"), "Response has no body") + 4;
+    response[body_start..].to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn body_is_generated_per_request() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let body_count = count.clone();
+        let server = unwrap_result!(start("Test", 0, "text/plain", move || {
+            format!("{}", body_count.fetch_add(1, Ordering::SeqCst))
+        }));
+        assert_eq!(get(&server.address()), "0");
+        assert_eq!(get(&server.address()), "1");
+    }
+
+    #[test]
+    fn idle_client_does_not_block_server() {
+        let server = unwrap_result!(start("Test", 0, "text/plain", || "body".to_owned()));
+        // This client connects but never sends a request, so is dropped once it times out.
+        let _idle = unwrap_result!(TcpStream::connect(&server.address()));
+        assert_eq!(get(&server.address()), "body");
+    }
+
+    #[test]
+    fn port_is_released_once_stopped() {
+        let server = unwrap_result!(start("Test", 0, "text/plain", || "first".to_owned()));
+        let port = server.address().port();
+        drop(server);
+
+        // A new server, e.g. for a vault restarted in the same process, can take the same port
+        let server = unwrap_result!(start("Test", port, "text/plain", || "second".to_owned()));
+        assert_eq!(get(&server.address()), "second");
+    }
+}
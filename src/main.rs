@@ -15,8 +15,8 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-//! SAFE Vault provides the interface to SAFE routing.
-//! The resulting executable is the Vault node for the SAFE network.
+//! The Vault node for the SAFE network.  This is a thin wrapper around the `safe_vault` library
+//! which parses the command line and runs a vault until it's stopped with Ctrl+C.
 
 #![doc(html_logo_url =
            "https://raw.githubusercontent.com/maidsafe/QA/master/Images/maidsafe_logo.png",
@@ -41,36 +41,47 @@
 #![cfg_attr(feature="clippy", plugin(clippy))]
 #![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
 
+extern crate ctrlc;
+extern crate docopt;
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate maidsafe_utilities;
-extern crate mpid_messaging;
-extern crate chunk_store;
-extern crate config_file_handler;
-extern crate ctrlc;
-#[cfg(all(test, feature = "use-mock-routing"))]
-extern crate kademlia_routing_table;
-extern crate lru_time_cache;
-#[cfg(all(test, feature = "use-mock-routing"))]
-extern crate rand;
-extern crate routing;
 extern crate rustc_serialize;
-extern crate sodiumoxide;
-extern crate time;
-extern crate xor_name;
+extern crate safe_vault;
 
-mod default_chunk_store;
-mod error;
-mod mock_routing;
-mod personas;
-mod types;
-mod utils;
-mod vault;
+mod cli;
+
+use ctrlc::CtrlC;
+use safe_vault::VaultBuilder;
+use std::{env, process};
 
 /// Runs a SAFE Network vault.
 pub fn main() {
+    let args = cli::Args::parse();
+    if let Some(log_level) = args.log_level() {
+        env::set_var("RUST_LOG", log_level);
+    }
     maidsafe_utilities::log::init(false);
-    utils::handle_version();
-    vault::Vault::run();
+    safe_vault::log_version();
+
+    let config = match args.config() {
+        Ok(config) => config,
+        Err(error) => {
+            error!("Failed to load vault config: {:?}", error);
+            process::exit(1);
+        }
+    };
+    info!("Running with {:?}", config);
+
+    let mut builder = VaultBuilder::new().config(config);
+
+    // Handle Ctrl+C to properly stop the vault instance.
+    let stop_handle = builder.stop_handle();
+    CtrlC::set_handler(move || stop_handle.stop());
+
+    if let Err(error) = builder.build().and_then(|mut vault| vault.run()) {
+        error!("Vault failed to run: {:?}", error);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,271 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Request-handling metrics, exported in Prometheus text format.  These are only collected when
+//! the crate is built with the `metrics` feature; otherwise `Metrics` is a no-op.
+
+pub use self::detail::Metrics;
+
+#[cfg(feature = "metrics")]
+mod detail {
+    use error::InternalError;
+    use local_server::{self, LocalServer};
+    use std::collections::BTreeMap;
+    use std::fmt::Write;
+    use std::sync::{Arc, Mutex};
+
+    const PROMETHEUS_CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+    #[derive(Default)]
+    struct Registry {
+        // Metric name -> rendered label set -> value
+        counters: BTreeMap<&'static str, BTreeMap<String, u64>>,
+        gauges: BTreeMap<&'static str, BTreeMap<String, u64>>,
+    }
+
+    impl Registry {
+        fn render(&self) -> String {
+            let mut output = String::new();
+            Self::render_family(&mut output, "counter", &self.counters);
+            Self::render_family(&mut output, "gauge", &self.gauges);
+            output
+        }
+
+        fn render_family(output: &mut String,
+                         metric_type: &str,
+                         family: &BTreeMap<&'static str, BTreeMap<String, u64>>) {
+            for (name, values) in family.iter() {
+                let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+                for (labels, value) in values.iter() {
+                    let _ = writeln!(output, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Metrics {
+        registry: Arc<Mutex<Registry>>,
+        // The port to serve the metrics on, if any.
+        port: Option<u16>,
+    }
+
+    impl Metrics {
+        /// Creates the metrics registry, to be exported on `port` once `serve` is called.
+        pub fn new(port: Option<u16>) -> Result<Metrics, InternalError> {
+            Ok(Metrics {
+                registry: Arc::new(Mutex::new(Registry::default())),
+                port: port,
+            })
+        }
+
+        /// Starts exporting the metrics on localhost if a port was given.  They're served until
+        /// the returned server is dropped.
+        pub fn serve(&self) -> Result<Option<LocalServer>, InternalError> {
+            let port = match self.port {
+                Some(port) => port,
+                None => return Ok(None),
+            };
+            let registry = self.registry.clone();
+            let render = move || unwrap_result!(registry.lock()).render();
+            let content_type = PROMETHEUS_CONTENT_TYPE;
+            Ok(Some(try!(local_server::start("Metrics", port, content_type, render))))
+        }
+
+        /// Returns true if the metrics are exported, so are worth keeping up to date.
+        pub fn is_exported(&self) -> bool {
+            self.port.is_some()
+        }
+
+        pub fn count_request(&self, persona: &str, kind: &str, succeeded: bool) {
+            self.increment("safe_vault_requests_total",
+                           format!("persona=\"{}\",kind=\"{}\",result=\"{}\"",
+                                   persona,
+                                   kind,
+                                   Self::result_label(succeeded)),
+                           1);
+        }
+
+        pub fn count_response(&self, persona: &str, kind: &str, succeeded: bool) {
+            self.increment("safe_vault_responses_total",
+                           format!("persona=\"{}\",kind=\"{}\",result=\"{}\"",
+                                   persona,
+                                   kind,
+                                   Self::result_label(succeeded)),
+                           1);
+        }
+
+        pub fn count_refreshes_sent(&self, persona: &str, count: usize) {
+            self.increment("safe_vault_refreshes_sent_total",
+                           format!("persona=\"{}\"", persona),
+                           count as u64);
+        }
+
+        pub fn count_refresh_received(&self, persona: &str, succeeded: bool) {
+            self.increment("safe_vault_refreshes_received_total",
+                           format!("persona=\"{}\",result=\"{}\"",
+                                   persona,
+                                   Self::result_label(succeeded)),
+                           1);
+        }
+
+        pub fn count_refresh_rejected(&self, reason: &str) {
+            self.increment("safe_vault_refreshes_rejected_total",
+                           format!("reason=\"{}\"", reason),
+                           1);
+        }
+
+        pub fn count_churn(&self, kind: &str) {
+            self.increment("safe_vault_churn_events_total",
+                           format!("kind=\"{}\"", kind),
+                           1);
+        }
+
+        pub fn set_chunk_store_space(&self, chunk_store: &str, used_space: u64, max_space: u64) {
+            let labels = format!("chunk_store=\"{}\"", chunk_store);
+            let mut registry = unwrap_result!(self.registry.lock());
+            let _ = registry.gauges
+                            .entry("safe_vault_chunk_store_used_bytes")
+                            .or_insert_with(BTreeMap::new)
+                            .insert(labels.clone(), used_space);
+            let _ = registry.gauges
+                            .entry("safe_vault_chunk_store_max_bytes")
+                            .or_insert_with(BTreeMap::new)
+                            .insert(labels, max_space);
+        }
+
+        fn increment(&self, name: &'static str, labels: String, count: u64) {
+            let mut registry = unwrap_result!(self.registry.lock());
+            *registry.counters
+                     .entry(name)
+                     .or_insert_with(BTreeMap::new)
+                     .entry(labels)
+                     .or_insert(0) += count;
+        }
+
+        fn result_label(succeeded: bool) -> &'static str {
+            if succeeded {
+                "success"
+            } else {
+                "failure"
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::Metrics;
+        use local_server;
+
+        #[test]
+        fn render_prometheus_text() {
+            let metrics = unwrap_result!(Metrics::new(None));
+            metrics.count_request("maid_manager", "put", true);
+            metrics.count_request("maid_manager", "put", true);
+            metrics.count_request("maid_manager", "put", false);
+            metrics.set_chunk_store_space("pmid_node", 10, 100);
+
+            let output = unwrap_result!(metrics.registry.lock()).render();
+            assert!(output.contains("# TYPE safe_vault_requests_total counter\n"));
+            assert!(output.contains("safe_vault_requests_total{persona=\"maid_manager\",\
+                                     kind=\"put\",result=\"success\"} 2\n"));
+            assert!(output.contains("safe_vault_requests_total{persona=\"maid_manager\",\
+                                     kind=\"put\",result=\"failure\"} 1\n"));
+            assert!(output.contains("# TYPE safe_vault_chunk_store_used_bytes gauge\n"));
+            assert!(output.contains("safe_vault_chunk_store_max_bytes{chunk_store=\"pmid_node\"} \
+                                     100\n"));
+        }
+
+        #[test]
+        fn serve_over_http() {
+            let metrics = unwrap_result!(Metrics::new(Some(0)));
+            assert!(metrics.is_exported());
+            metrics.count_churn("node_added");
+
+            let server = unwrap_option!(unwrap_result!(metrics.serve()),
+                                        "Metrics should be served");
+            let output = local_server::get(&server.address());
+            assert!(output.contains("safe_vault_churn_events_total{kind=\"node_added\"} 1\n"));
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod detail {
+    use error::InternalError;
+    use local_server::LocalServer;
+
+    #[derive(Clone)]
+    pub struct Metrics;
+
+    impl Metrics {
+        pub fn new(port: Option<u16>) -> Result<Metrics, InternalError> {
+            if port.is_some() {
+                warn!("Metrics port set, but this vault was built without the \"metrics\" \
+                       feature.");
+            }
+            Ok(Metrics)
+        }
+
+        pub fn serve(&self) -> Result<Option<LocalServer>, InternalError> {
+            Ok(None)
+        }
+
+        pub fn count_request(&self, _persona: &str, _kind: &str, _succeeded: bool) {}
+
+        pub fn count_response(&self, _persona: &str, _kind: &str, _succeeded: bool) {}
+
+        pub fn count_refreshes_sent(&self, _persona: &str, _count: usize) {}
+
+        pub fn count_refresh_received(&self, _persona: &str, _succeeded: bool) {}
+
+        pub fn count_refresh_rejected(&self, _reason: &str) {}
+
+        pub fn count_churn(&self, _kind: &str) {}
+
+        pub fn set_chunk_store_space(&self,
+                                     _chunk_store: &str,
+                                     _used_space: u64,
+                                     _max_space: u64) {
+        }
+
+        pub fn is_exported(&self) -> bool {
+            false
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::Metrics;
+        use std::net::{TcpListener, TcpStream};
+
+        #[test]
+        fn port_is_ignored() {
+            // Find a free port, then release it for the metrics to (not) bind.
+            let port = unwrap_result!(unwrap_result!(TcpListener::bind("127.0.0.1:0")).local_addr())
+                           .port();
+            let metrics = unwrap_result!(Metrics::new(Some(port)));
+            assert!(!metrics.is_exported());
+            assert!(unwrap_result!(metrics.serve()).is_none());
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+
+            // Counting is a no-op, but mustn't fail.
+            metrics.count_request("maid_manager", "put", true);
+            metrics.set_chunk_store_space("pmid_node", 10, 100);
+        }
+    }
+}
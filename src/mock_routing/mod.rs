@@ -15,7 +15,7 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-#![cfg(all(test, feature = "use-mock-routing"))]
+#![cfg(any(test, feature = "use-mock-routing"))]
 
 #![allow(unused)]
 
@@ -25,6 +25,7 @@ use self::mock_routing_impl::MockRoutingNodeImpl;
 use routing::{Authority, Data, DataRequest, Event, ImmutableData, ImmutableDataType,
               InterfaceError, MessageId, RequestContent, RequestMessage, ResponseContent,
               ResponseMessage, RoutingError};
+use routing_api::RoutingApi;
 use sodiumoxide::crypto::hash::sha512;
 use sodiumoxide::crypto::sign::PublicKey;
 use std::sync::{Arc, Mutex, mpsc};
@@ -128,144 +129,143 @@ impl MockRoutingNode {
         unwrap_result!(self.pimpl.lock()).refresh_requests_given()
     }
 
+    fn client_authority(client_address: XorName, client_pub_key: PublicKey) -> Authority {
+        Authority::Client {
+            client_key: client_pub_key,
+            proxy_node_name: client_address,
+        }
+    }
+}
 
-
-    // -----------  the following methods are expected to be API functions   ------------- //
-    pub fn send_get_request(&self,
-                            src: Authority,
-                            dst: Authority,
-                            data_request: DataRequest,
-                            id: MessageId)
-                            -> Result<(), InterfaceError> {
+impl RoutingApi for MockRoutingNode {
+    fn send_get_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data_request: DataRequest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_get_request(src, dst, data_request, id)
     }
 
-    pub fn send_put_request(&self,
-                            src: Authority,
-                            dst: Authority,
-                            data: Data,
-                            id: MessageId)
-                            -> Result<(), InterfaceError> {
+    fn send_put_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_put_request(src, dst, data, id)
     }
 
-    pub fn send_post_request(&self,
-                             src: Authority,
-                             dst: Authority,
-                             data: Data,
-                             id: MessageId)
-                             -> Result<(), InterfaceError> {
+    fn send_post_request(&self,
+                         src: Authority,
+                         dst: Authority,
+                         data: Data,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_post_request(src, dst, data, id)
     }
 
-    pub fn send_delete_request(&self,
-                               src: Authority,
-                               dst: Authority,
-                               data: Data,
-                               id: MessageId)
-                               -> Result<(), InterfaceError> {
+    fn send_delete_request(&self,
+                           src: Authority,
+                           dst: Authority,
+                           data: Data,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_delete_request(src, dst, data, id)
     }
 
-    pub fn send_get_success(&self,
-                            src: Authority,
-                            dst: Authority,
-                            data: Data,
-                            id: MessageId)
-                            -> Result<(), InterfaceError> {
+    fn send_get_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_get_success(src, dst, data, id)
     }
 
-    pub fn send_get_failure(&self,
-                            src: Authority,
-                            dst: Authority,
-                            request: RequestMessage,
-                            external_error_indicator: Vec<u8>,
-                            id: MessageId)
-                            -> Result<(), InterfaceError> {
+    fn send_get_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock())
             .send_get_failure(src, dst, request, external_error_indicator, id)
     }
 
-    pub fn send_put_success(&self,
-                            src: Authority,
-                            dst: Authority,
-                            request_hash: sha512::Digest,
-                            id: MessageId)
-                            -> Result<(), InterfaceError> {
+    fn send_put_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request_hash: sha512::Digest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_put_success(src, dst, request_hash, id)
     }
 
-    pub fn send_put_failure(&self,
-                            src: Authority,
-                            dst: Authority,
-                            request: RequestMessage,
-                            external_error_indicator: Vec<u8>,
-                            id: MessageId)
-                            -> Result<(), InterfaceError> {
+    fn send_put_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock())
             .send_put_failure(src, dst, request, external_error_indicator, id)
     }
 
-    pub fn send_post_success(&self,
-                             src: Authority,
-                             dst: Authority,
-                             request_hash: sha512::Digest,
-                             id: MessageId)
-                             -> Result<(), InterfaceError> {
+    fn send_post_success(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request_hash: sha512::Digest,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_post_success(src, dst, request_hash, id)
     }
 
-    pub fn send_post_failure(&self,
-                             src: Authority,
-                             dst: Authority,
-                             request: RequestMessage,
-                             external_error_indicator: Vec<u8>,
-                             id: MessageId)
-                             -> Result<(), InterfaceError> {
+    fn send_post_failure(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request: RequestMessage,
+                         external_error_indicator: Vec<u8>,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock())
             .send_post_failure(src, dst, request, external_error_indicator, id)
     }
 
-    pub fn send_delete_success(&self,
-                               src: Authority,
-                               dst: Authority,
-                               request_hash: sha512::Digest,
-                               id: MessageId)
-                               -> Result<(), InterfaceError> {
+    fn send_delete_success(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request_hash: sha512::Digest,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_delete_success(src, dst, request_hash, id)
     }
 
-    pub fn send_delete_failure(&self,
-                               src: Authority,
-                               dst: Authority,
-                               request: RequestMessage,
-                               external_error_indicator: Vec<u8>,
-                               id: MessageId)
-                               -> Result<(), InterfaceError> {
+    fn send_delete_failure(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request: RequestMessage,
+                           external_error_indicator: Vec<u8>,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock())
             .send_delete_failure(src, dst, request, external_error_indicator, id)
     }
 
-    pub fn send_refresh_request(&self,
-                                src: Authority,
-                                content: Vec<u8>)
-                                -> Result<(), InterfaceError> {
+    fn send_refresh_request(&self,
+                            src: Authority,
+                            content: Vec<u8>)
+                            -> Result<(), InterfaceError> {
         unwrap_result!(self.pimpl.lock()).send_refresh_request(src, content)
     }
 
-    pub fn close_group(&self, name: XorName) -> Result<Option<Vec<XorName>>, InterfaceError> {
+    fn close_group(&self, name: XorName) -> Result<Option<Vec<XorName>>, InterfaceError> {
         unwrap_result!(self.pimpl.lock()).close_group(name)
     }
 
-    pub fn name(&self) -> Result<XorName, InterfaceError> {
+    fn name(&self) -> Result<XorName, InterfaceError> {
         unwrap_result!(self.pimpl.lock()).name()
     }
-
-    fn client_authority(client_address: XorName, client_pub_key: PublicKey) -> Authority {
-        Authority::Client {
-            client_key: client_pub_key,
-            proxy_node_name: client_address,
-        }
-    }
 }
@@ -15,24 +15,33 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-// TODO remove this
-#![allow(unused)]
-
+use clock::Clock;
+use config_handler::Config;
 use error::{ClientError, InternalError};
-use lru_time_cache::LruCache;
 use maidsafe_utilities::serialisation;
+use personas::Persona;
 use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
               RequestContent, RequestMessage, ResponseContent, ResponseMessage};
+use refresh_accumulator::{self, Accumulated, RefreshAccumulator};
+use routing_api::RoutingApi;
 use sodiumoxide::crypto::hash::sha512;
+use state_store::StateStore;
+use status::Status;
 use std::cmp::{self, Ordering};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use time::{Duration, SteadyTime};
-use types::{Refresh, RefreshValue};
-use vault::RoutingNode;
+use types::{Action, Refresh, RefreshValue};
+use utils;
 use xor_name::{self, XorName};
 
 pub const REPLICANTS: usize = 6;
 pub const MIN_REPLICANTS: usize = 6;
+// Beyond this many holders, the ones furthest from the chunk are asked to delete it
+pub const MAX_HOLDERS: usize = 8;
+pub const DEFAULT_GET_TIMEOUT_SECS: u64 = 60;
+// How long a PmidNode which has reported it's out of space is passed over when choosing holders
+const FULL_PMID_NODE_TIMEOUT_SECS: i64 = 600;
 
 // This is the name of a PmidNode which has been chosen to store the data on.  It is assumed to be
 // `Good` (can return the data) until it fails a Get request, at which time it is deemed `Failed`.
@@ -75,12 +84,33 @@ struct MetadataForGetRequest {
     pub pmid_nodes: Vec<QueriedDataHolder>,
     pub creation_timestamp: SteadyTime,
     pub data: Option<ImmutableData>,
+    // None if we're fetching the data to replicate it, since accounts don't record the data's type
+    pub data_type: Option<ImmutableDataType>,
+    // Set once no holder could provide the data and the Backup and Sacrificial copies are requested
+    pub recovery_timestamp: Option<SteadyTime>,
+    // Some(true) if that copy was returned, Some(false) if it couldn't be - None if not known yet
     pub backup_ok: Option<bool>,
     pub sacrificial_ok: Option<bool>,
 }
 
 impl MetadataForGetRequest {
-    pub fn new(request: &RequestMessage, pmid_nodes: &Account) -> MetadataForGetRequest {
+    pub fn new(request: &RequestMessage,
+               pmid_nodes: &Account,
+               now: SteadyTime)
+               -> MetadataForGetRequest {
+        let data_type = match request.content {
+            RequestContent::Get(DataRequest::Immutable(_, ref data_type), _) => data_type.clone(),
+            _ => unreachable!("Logic error"),
+        };
+
+        let mut metadata = MetadataForGetRequest::for_replication(pmid_nodes, now);
+        metadata.requests.push(request.clone());
+        metadata.data_type = Some(data_type);
+        metadata
+    }
+
+    // Used when we fetch the data ourselves, to store it on new holders.
+    pub fn for_replication(pmid_nodes: &Account, now: SteadyTime) -> MetadataForGetRequest {
         // We only want to try and get data from "good" holders
         let good_nodes = pmid_nodes.iter()
                                    .filter_map(|data_holder| {
@@ -94,43 +124,86 @@ impl MetadataForGetRequest {
                                    .collect();
 
         MetadataForGetRequest {
-            requests: vec![request.clone(); 1],
+            requests: vec![],
             pmid_nodes: good_nodes,
-            creation_timestamp: SteadyTime::now(),
+            creation_timestamp: now,
             data: None,
+            data_type: None,
+            recovery_timestamp: None,
             backup_ok: None,
             sacrificial_ok: None,
         }
     }
+
+    // Only Normal copies are recovered from the other copies, and only once per Get
+    pub fn can_recover(&self) -> bool {
+        self.data_type == Some(ImmutableDataType::Normal) && self.recovery_timestamp.is_none()
+    }
 }
 
 pub type Account = HashSet<DataHolder>;  // Collection of PmidNodes holding a copy of the chunk
 
-const LRU_CACHE_SIZE: usize = 1000;
+const STATE_NAME: &'static str = "immutable_data_manager_accounts";
+const FARMING_RATE_STATE_NAME: &'static str = "immutable_data_manager_farming_rate";
+const SACRIFICIAL_HOLDERS_STATE_NAME: &'static str = "immutable_data_manager_sacrificial_holders";
+// The farming rate never drops below its starting value.
+const MIN_FARMING_RATE: u64 = 1;
 
 pub struct ImmutableDataManager {
     // <Data name, PmidNodes holding a copy of the data>
     accounts: HashMap<XorName, Account>,
     // key is chunk_name
-    ongoing_gets: LruCache<XorName, MetadataForGetRequest>,
+    ongoing_gets: HashMap<XorName, MetadataForGetRequest>,
+    // How long queried PmidNodes have to respond before they're deemed `Failed`.  Also how long
+    // PmidNodes have to confirm they've stored a Sacrificial copy.
+    get_timeout: Duration,
+    clock: Arc<Clock>,
+    // Number of chunks which couldn't be recovered from any of their copies
+    unrecoverable_count: usize,
+    // Our own view of the group's farming rate
+    farming_rate: u64,
+    // <Group member, the farming rate it last reported>
+    group_farming_rates: HashMap<XorName, u64>,
+    // <PmidNode, when it was last reported as out of space>
+    full_pmid_nodes: HashMap<XorName, SteadyTime>,
+    // <(Sacrificial copy, PmidNode asked to store it), when it was asked>
+    pending_sacrificial_puts: HashMap<(XorName, XorName), SteadyTime>,
+    // (Sacrificial copy, PmidNode) for each copy which has raised the farming rate
+    confirmed_sacrificial_holders: HashSet<(XorName, XorName)>,
+    refresh_accumulator: RefreshAccumulator<Account>,
 }
 
 impl ImmutableDataManager {
-    pub fn new() -> ImmutableDataManager {
+    pub fn new(config: &Config, clock: Arc<Clock>) -> ImmutableDataManager {
         ImmutableDataManager {
             accounts: HashMap::new(),
-            ongoing_gets: LruCache::with_expiry_duration_and_capacity(Duration::minutes(5),
-                                                                      LRU_CACHE_SIZE),
+            ongoing_gets: HashMap::new(),
+            get_timeout: Duration::seconds(config.immutable_data_manager_get_timeout_secs() as i64),
+            refresh_accumulator: RefreshAccumulator::new(config.refresh_quorum(), clock.clone()),
+            clock: clock,
+            unrecoverable_count: 0,
+            farming_rate: MIN_FARMING_RATE,
+            group_farming_rates: HashMap::new(),
+            full_pmid_nodes: HashMap::new(),
+            pending_sacrificial_puts: HashMap::new(),
+            confirmed_sacrificial_holders: HashSet::new(),
         }
     }
 
-    pub fn handle_get(&mut self,
-                      routing_node: &RoutingNode,
-                      request: &RequestMessage)
-                      -> Result<(), InternalError> {
-        let (data_name, message_id) = match request.content {
-            RequestContent::Get(DataRequest::Immutable(ref data_name, _), ref message_id) => {
-                (data_name.clone(), message_id.clone())
+    /// The group's farming rate: the median of our own value and those reported by the rest of
+    /// the group.
+    pub fn farming_rate(&self) -> u64 {
+        let mut rates = self.group_farming_rates.values().cloned().collect::<Vec<_>>();
+        rates.push(self.farming_rate);
+        rates.sort();
+        rates[rates.len() / 2]
+    }
+
+    pub fn handle_get(&mut self, request: &RequestMessage) -> Result<Vec<Action>, InternalError> {
+        let (data_name, data_type, message_id) = match request.content {
+            RequestContent::Get(DataRequest::Immutable(ref data_name, ref data_type),
+                                ref message_id) => {
+                (data_name.clone(), data_type.clone(), message_id.clone())
             }
             _ => unreachable!("Error in vault demuxing"),
         };
@@ -139,83 +212,95 @@ impl ImmutableDataManager {
         let pmid_nodes = match self.accounts.get(&data_name) {
             Some(account) => account,
             None => {
-                let src = request.dst.clone();
-                let dst = request.src.clone();
-                let error = ClientError::NoSuchData;
-                let external_error_indicator = try!(serialisation::serialise(&error));
-                let _ = routing_node.send_get_failure(src,
-                                                      dst,
-                                                      request.clone(),
-                                                      external_error_indicator,
-                                                      message_id);
-                return Err(InternalError::Client(error));
+                return Ok(vec![try!(Self::get_failure(request.clone(), &ClientError::NoSuchData))]);
             }
         };
 
         {
             // If there's already a cached get request, handle it here and return
             if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
-                return Ok(Self::reply_with_data_else_cache_request(routing_node,
-                                                                   request,
+                if metadata.data_type.is_none() {
+                    metadata.data_type = Some(data_type);
+                }
+                return Ok(Self::reply_with_data_else_cache_request(request,
                                                                    &message_id,
                                                                    metadata));
             }
         }
 
         // This is new cache entry
-        let entry = MetadataForGetRequest::new(request, pmid_nodes);
+        let entry = MetadataForGetRequest::new(request, pmid_nodes, self.clock.now());
+        let mut actions = vec![];
         for good_node in entry.pmid_nodes.iter() {
             let src = request.dst.clone();
             let dst = Authority::ManagedNode(good_node.name().clone());
-            let data_request = DataRequest::Immutable(data_name.clone(),
-                                                      ImmutableDataType::Normal);
-            debug!("ImmutableDataManager {:?} sending get {:?} to {:?}",
-                   routing_node.name(),
-                   data_name,
-                   dst);
-            let _ = routing_node.send_get_request(src, dst, data_request, message_id.clone());
+            let data_request = DataRequest::Immutable(data_name.clone(), data_type.clone());
+            debug!("ImmutableDataManager sending get {:?} to {:?}", data_name, dst);
+            actions.push(Action::request(src,
+                                         dst,
+                                         RequestContent::Get(data_request, message_id.clone())));
         }
         let _ = self.ongoing_gets.insert(data_name, entry);
 
-        Ok(())
+        Ok(actions)
     }
 
     pub fn handle_put(&mut self,
-                      routing_node: &RoutingNode,
+                      routing_node: &RoutingApi,
                       data: &ImmutableData,
                       message_id: &MessageId)
-                      -> Result<(), InternalError> {
+                      -> Result<Vec<Action>, InternalError> {
         // If the data already exists, there's no more to do.
         let data_name = data.name();
         if self.accounts.contains_key(&data_name) {
-            return Ok(());
+            return Ok(vec![]);
         }
 
         // Choose the PmidNodes to store the data on, and add them in a new database entry.
+        let full_pmid_nodes = self.full_pmid_nodes.keys().collect();
         let target_pmid_nodes = try!(Self::choose_target_pmid_nodes(routing_node,
                                                                     &data_name,
-                                                                    vec![]));
+                                                                    full_pmid_nodes));
         debug!("ImmutableDataManager chosen {:?} as pmid_nodes for chunk {:?}",
                target_pmid_nodes,
                data_name);
         let _ = self.accounts.insert(data_name, target_pmid_nodes.clone());
 
+        // Each Sacrificial copy raises the farming rate once its PmidNode confirms it's stored.
+        if *data.get_type_tag() == ImmutableDataType::Sacrificial {
+            let now = self.clock.now();
+            for pmid_node in target_pmid_nodes.iter() {
+                let _ = self.pending_sacrificial_puts
+                            .insert((data_name, pmid_node.name().clone()), now);
+            }
+        }
+
         // Send the message on to the PmidNodes' managers.
+        let mut actions = vec![];
         for pmid_node in target_pmid_nodes {
             let src = Authority::NaeManager(data_name);
             let dst = Authority::NodeManager(pmid_node.name().clone());
-            let _ = routing_node.send_put_request(src,
-                                                  dst,
-                                                  Data::Immutable(data.clone()),
-                                                  message_id.clone());
+            let content = RequestContent::Put(Data::Immutable(data.clone()), message_id.clone());
+            actions.push(Action::request(src, dst, content));
         }
-        Ok(())
+
+        // The Normal copy's managers pass the Backup and Sacrificial copies on to their managers.
+        if *data.get_type_tag() == ImmutableDataType::Normal {
+            for data_type in vec![ImmutableDataType::Backup, ImmutableDataType::Sacrificial] {
+                let copy = ImmutableData::new(data_type, data.value().clone());
+                let src = Authority::NaeManager(data_name);
+                let dst = Authority::NaeManager(copy.name());
+                let content = RequestContent::Put(Data::Immutable(copy), message_id.clone());
+                actions.push(Action::request(src, dst, content));
+            }
+        }
+        Ok(actions)
     }
 
     pub fn handle_get_success(&mut self,
-                              routing_node: &RoutingNode,
+                              routing_node: &RoutingApi,
                               response: &ResponseMessage)
-                              -> Result<(), InternalError> {
+                              -> Result<Vec<Action>, InternalError> {
         let (data, message_id) = match response.content {
             ResponseContent::GetSuccess(Data::Immutable(ref data), ref message_id) => {
                 (data, message_id)
@@ -226,16 +311,16 @@ impl ImmutableDataManager {
 
         let mut result = Err(InternalError::FailedToFindCachedRequest(message_id.clone()));
         if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
-            result = Ok(());
             // Reply to any unanswered requests
+            let mut actions = vec![];
             while let Some(request) = metadata.requests.pop() {
                 let src = request.dst.clone();
                 let dst = request.src;
-                let _ = routing_node.send_get_success(src,
-                                                      dst,
-                                                      Data::Immutable(data.clone()),
-                                                      message_id.clone());
+                let content = ResponseContent::GetSuccess(Data::Immutable(data.clone()),
+                                                          message_id.clone());
+                actions.push(Action::response(src, dst, content));
             }
+            result = Ok(actions);
 
             // Mark the responder as "good"
             let predicate = |elt: &QueriedDataHolder| {
@@ -258,17 +343,17 @@ impl ImmutableDataManager {
             }
         }
 
-        try!(self.check_and_replicate(routing_node, &data_name));
-        result
+        let mut actions = try!(result);
+        actions.extend(try!(self.check_and_replicate(routing_node, &data_name)));
+        Ok(actions)
     }
 
     pub fn handle_get_failure(&mut self,
-                              routing_node: &RoutingNode,
+                              routing_node: &RoutingApi,
                               pmid_node: &XorName,
                               message_id: &MessageId,
-                              request: &RequestMessage,
-                              external_error_indicator: &Vec<u8>)
-                              -> Result<(), InternalError> {
+                              request: &RequestMessage)
+                              -> Result<Vec<Action>, InternalError> {
         let data_name = match request.content {
             RequestContent::Get(ref data_request, _) => data_request.name(),
             _ => {
@@ -298,105 +383,417 @@ impl ImmutableDataManager {
             }
         }
 
-        // Mark the responder as "failed" in the account if it was previously marked "good"
-        if let Some(pmid_nodes) = self.accounts.get_mut(&data_name) {
-            if pmid_nodes.remove(&DataHolder::Good(pmid_node.clone())) {
-                pmid_nodes.insert(DataHolder::Failed(pmid_node.clone()));
+        Self::mark_holder_failed(&mut self.accounts, &data_name, pmid_node);
+
+        let actions = try!(self.check_and_replicate(routing_node, &data_name));
+        result.map(|()| actions)
+    }
+
+    /// Handles a Backup or Sacrificial copy returned by its managers while recovering the Normal
+    /// copy.  The first copy to arrive is sent to any waiting clients and stored on new holders.
+    pub fn handle_recovered_copy(&mut self,
+                                 routing_node: &RoutingApi,
+                                 data: &ImmutableData,
+                                 message_id: &MessageId)
+                                 -> Result<Vec<Action>, InternalError> {
+        // All copies hold the same value, so the Normal copy's name identifies the cached get.
+        let normal_data = ImmutableData::new(ImmutableDataType::Normal, data.value().clone());
+        let data_name = normal_data.name();
+
+        let mut actions = vec![];
+        match self.ongoing_gets.get_mut(&data_name) {
+            Some(metadata) => {
+                match *data.get_type_tag() {
+                    ImmutableDataType::Backup => metadata.backup_ok = Some(true),
+                    ImmutableDataType::Sacrificial => metadata.sacrificial_ok = Some(true),
+                    ImmutableDataType::Normal => return Err(InternalError::InvalidResponse),
+                }
+                if metadata.data.is_none() {
+                    info!("ImmutableDataManager recovered {:?} from its {:?} copy",
+                          data_name,
+                          data.get_type_tag());
+                    while let Some(request) = metadata.requests.pop() {
+                        actions.push(Self::get_success(request, &normal_data));
+                    }
+                    metadata.data = Some(normal_data);
+                }
+            }
+            None => return Err(InternalError::FailedToFindCachedRequest(message_id.clone())),
+        }
+
+        actions.extend(try!(self.check_and_replicate(routing_node, &data_name)));
+        Ok(actions)
+    }
+
+    /// Handles the managers of a Backup or Sacrificial copy failing to return it.
+    pub fn handle_recovery_failure(&mut self,
+                                   routing_node: &RoutingApi,
+                                   message_id: &MessageId,
+                                   request: &RequestMessage)
+                                   -> Result<Vec<Action>, InternalError> {
+        // We sent the request from the group managing the Normal copy
+        let data_name = request.src.name().clone();
+        let data_type = match request.content {
+            RequestContent::Get(DataRequest::Immutable(_, ref data_type), _) => data_type.clone(),
+            _ => {
+                warn!("Request type doesn't correspond to response type: {:?}",
+                      request);
+                return Err(InternalError::InvalidResponse);
+            }
+        };
+
+        match self.ongoing_gets.get_mut(&data_name) {
+            Some(metadata) => {
+                match data_type {
+                    ImmutableDataType::Backup => metadata.backup_ok = Some(false),
+                    ImmutableDataType::Sacrificial => metadata.sacrificial_ok = Some(false),
+                    ImmutableDataType::Normal => return Err(InternalError::InvalidResponse),
+                }
+            }
+            None => return Err(InternalError::FailedToFindCachedRequest(message_id.clone())),
+        }
+
+        self.check_and_replicate(routing_node, &data_name)
+    }
+
+    /// Fails any queried PmidNodes which haven't responded to a Get within the timeout, and any
+    /// Backup or Sacrificial copies which haven't arrived within the timeout of being requested.
+    /// Clients still waiting on the data are sent a `GetFailure` once it can't be recovered, and
+    /// replacement holders are chosen if a copy of the data did arrive.
+    pub fn fail_expired_gets(&mut self,
+                             routing_node: &RoutingApi)
+                             -> Result<Vec<Action>, InternalError> {
+        let now = self.clock.now();
+        let get_timeout = self.get_timeout;
+        let expired_names = self.ongoing_gets
+                                .iter()
+                                .filter(|&(_, metadata)| {
+                                    let start = metadata.recovery_timestamp
+                                                        .unwrap_or(metadata.creation_timestamp);
+                                    start + get_timeout <= now
+                                })
+                                .map(|(data_name, _)| data_name.clone())
+                                .collect::<Vec<_>>();
+        let mut actions = vec![];
+        for data_name in expired_names {
+            if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
+                if metadata.recovery_timestamp.is_none() {
+                    for queried_data_holder in metadata.pmid_nodes.iter_mut() {
+                        let pmid_node = match *queried_data_holder {
+                            QueriedDataHolder::PendingResponse(ref name) => name.clone(),
+                            QueriedDataHolder::Responded(_) => continue,
+                        };
+                        debug!("ImmutableDataManager timed out waiting on {:?} for {:?}",
+                               pmid_node,
+                               data_name);
+                        Self::mark_holder_failed(&mut self.accounts, &data_name, &pmid_node);
+                        *queried_data_holder =
+                            QueriedDataHolder::Responded(DataHolder::Failed(pmid_node));
+                    }
+                } else {
+                    debug!("ImmutableDataManager timed out recovering {:?}", data_name);
+                    if metadata.backup_ok.is_none() {
+                        metadata.backup_ok = Some(false);
+                    }
+                    if metadata.sacrificial_ok.is_none() {
+                        metadata.sacrificial_ok = Some(false);
+                    }
+                }
+
+                // Only requests which haven't been sent the data are still cached here
+                if !metadata.can_recover() {
+                    while let Some(request) = metadata.requests.pop() {
+                        actions.push(try!(Self::get_failure(request, &ClientError::Timeout)));
+                    }
+                }
             }
+            actions.extend(try!(self.check_and_replicate(routing_node, &data_name)));
         }
+        Ok(actions)
+    }
 
-        try!(self.check_and_replicate(routing_node, &data_name));
-        result
+    /// Handles a PmidNode confirming that it has stored a Sacrificial copy, which raises the
+    /// farming rate.  The rate is dropped again if the copy is later reported as deleted.
+    pub fn handle_put_success(&mut self, pmid_node: &XorName, data_name: &XorName) -> Vec<Action> {
+        let holder = (*data_name, pmid_node.clone());
+        if self.pending_sacrificial_puts.remove(&holder).is_some() {
+            let _ = self.confirmed_sacrificial_holders.insert(holder);
+            self.farming_rate += 1;
+            debug!("ImmutableDataManager raised farming rate to {} as {:?} stored {:?}",
+                   self.farming_rate,
+                   pmid_node,
+                   data_name);
+        }
+        vec![]
     }
 
-    pub fn handle_refresh(&mut self, data_name: XorName, account: Account) {
-        let _ = self.accounts.insert(data_name, account);
+    /// Handles a PmidNode's managers reporting that it has not stored, or has had to delete, a
+    /// Sacrificial copy.
+    pub fn handle_sacrifice(&mut self,
+                            pmid_node: &XorName,
+                            request: &RequestMessage,
+                            external_error_indicator: &Vec<u8>)
+                            -> Result<Vec<Action>, InternalError> {
+        let data_name = match request.content {
+            RequestContent::Put(Data::Immutable(ref data), _) => data.name(),
+            _ => unreachable!("Error in vault demuxing"),
+        };
+        try!(self.record_if_full(pmid_node, external_error_indicator));
+
+        // Sacrificial copies aren't replicated; the holder is just dropped from the account
+        let removed = match self.accounts.get_mut(&data_name) {
+            Some(pmid_nodes) => {
+                pmid_nodes.remove(&DataHolder::Good(pmid_node.clone())) |
+                pmid_nodes.remove(&DataHolder::Failed(pmid_node.clone()))
+            }
+            None => false,
+        };
+        // A copy which was never confirmed as stored, even if it's no longer pending, hasn't
+        // raised the rate
+        let holder = (data_name, pmid_node.clone());
+        let _ = self.pending_sacrificial_puts.remove(&holder);
+        let confirmed = self.confirmed_sacrificial_holders.remove(&holder);
+        if !removed || !confirmed {
+            return Ok(vec![]);
+        }
+
+        self.farming_rate = cmp::max(MIN_FARMING_RATE, self.farming_rate - 1);
+        debug!("ImmutableDataManager lowered farming rate to {} as {:?} lost {:?}",
+               self.farming_rate,
+               pmid_node,
+               data_name);
+        Ok(vec![])
+    }
+
+    /// Handles a PmidNode's managers reporting that it has failed to store a Normal or Backup
+    /// copy.  The PmidNode is dropped from the account, and the copy is put to the closest node
+    /// which isn't already holding it.
+    pub fn handle_put_failure(&mut self,
+                              routing_node: &RoutingApi,
+                              pmid_node: &XorName,
+                              request: &RequestMessage,
+                              external_error_indicator: &Vec<u8>)
+                              -> Result<Vec<Action>, InternalError> {
+        let data = match request.content {
+            RequestContent::Put(Data::Immutable(ref data), _) => data,
+            _ => unreachable!("Error in vault demuxing"),
+        };
+        let data_name = data.name();
+        try!(self.record_if_full(pmid_node, external_error_indicator));
+
+        let mut actions = vec![];
+        if let Some(pmid_nodes) = self.accounts.get_mut(&data_name) {
+            if !pmid_nodes.remove(&DataHolder::Good(pmid_node.clone())) {
+                // We already know about this failure
+                return Ok(actions);
+            }
+            let _ = pmid_nodes.insert(DataHolder::Failed(pmid_node.clone()));
+
+            let full_pmid_nodes = self.full_pmid_nodes.keys().collect();
+            match try!(Self::replicate_to(routing_node, &data_name, pmid_nodes, full_pmid_nodes)) {
+                Some(new_pmid_node) => {
+                    debug!("ImmutableDataManager replicating {:?} to {:?} in place of {:?}",
+                           data_name,
+                           new_pmid_node,
+                           pmid_node);
+                    let _ = pmid_nodes.insert(DataHolder::Good(new_pmid_node.clone()));
+                    let src = Authority::NaeManager(data_name);
+                    let dst = Authority::NodeManager(new_pmid_node);
+                    let content = RequestContent::Put(Data::Immutable(data.clone()),
+                                                      MessageId::new());
+                    actions.push(Action::request(src, dst, content));
+                }
+                None => warn!("Failed to find nodes to replicate {:?} to.", data_name),
+            }
+            actions.extend(Self::prune_holders(data, pmid_nodes));
+        }
+        Ok(actions)
     }
 
-    pub fn handle_node_added(&mut self, routing_node: &RoutingNode, _node_added: XorName) {
-        self.handle_churn(routing_node)
+    /// Handles a node leaving the network.  It's marked as failed in the account of every chunk it
+    /// was holding, and each chunk is fetched from its remaining holders to be stored on a new one.
+    pub fn replace_lost_holder(&mut self,
+                               routing_node: &RoutingApi,
+                               node_lost: &XorName)
+                               -> Vec<Action> {
+        let now = self.clock.now();
+        let lost_holder = DataHolder::Good(node_lost.clone());
+        let mut actions = vec![];
+        for (data_name, pmid_nodes) in self.accounts.iter_mut() {
+            if !pmid_nodes.remove(&lost_holder) {
+                continue;
+            }
+            let _ = pmid_nodes.insert(DataHolder::Failed(node_lost.clone()));
+
+            // A Get already under way replicates the chunk once it times out waiting on the lost
+            // node.  Chunks we're no longer close to are left to their new managers.
+            if self.ongoing_gets.contains_key(data_name) {
+                continue;
+            }
+            match routing_node.close_group(data_name.clone()) {
+                Ok(Some(_)) => (),
+                _ => continue,
+            }
+
+            let metadata = MetadataForGetRequest::for_replication(pmid_nodes, now);
+            if metadata.pmid_nodes.is_empty() {
+                warn!("ImmutableDataManager has no holders left for {:?}", data_name);
+                continue;
+            }
+            debug!("ImmutableDataManager fetching {:?} to replace {:?} as a holder",
+                   data_name,
+                   node_lost);
+            let message_id = MessageId::new();
+            for good_node in metadata.pmid_nodes.iter() {
+                let src = Authority::NaeManager(data_name.clone());
+                let dst = Authority::ManagedNode(good_node.name().clone());
+                // PmidNodes look chunks up by name alone
+                let data_request = DataRequest::Immutable(data_name.clone(),
+                                                          ImmutableDataType::Normal);
+                actions.push(Action::request(src,
+                                             dst,
+                                             RequestContent::Get(data_request,
+                                                                 message_id.clone())));
+            }
+            let _ = self.ongoing_gets.insert(data_name.clone(), metadata);
+        }
+        actions
     }
 
-    pub fn handle_node_lost(&mut self, routing_node: &RoutingNode, _node_lost: XorName) {
-        self.handle_churn(routing_node)
+    /// Takes over the account once a quorum of the group has sent matching refreshes for it.
+    pub fn handle_refresh(&mut self,
+                          data_name: XorName,
+                          sender: XorName,
+                          account: Account)
+                          -> Vec<Action> {
+        match self.refresh_accumulator.add(data_name, sender, account) {
+            Accumulated::Agreed(account) => {
+                let _ = self.accounts.insert(data_name, account);
+                vec![]
+            }
+            Accumulated::Conflicted => {
+                vec![refresh_accumulator::sync_request(Authority::NaeManager(data_name),
+                                                       self.name())]
+            }
+            Accumulated::Pending => vec![],
+        }
     }
 
-    pub fn handle_churn(&mut self, routing_node: &RoutingNode) {
-        for (data_name, pmid_nodes) in self.accounts.iter() {
+    fn account_refresh(&self, data_name: &XorName) -> Option<Action> {
+        self.accounts.get(data_name).map(|pmid_nodes| {
             let src = Authority::NaeManager(data_name.clone());
-            let refresh = Refresh::new(data_name,
-                                       RefreshValue::ImmutableDataManagerAccount(pmid_nodes.clone()));
-            if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
-                debug!("ImmutableDataManager sending refresh for account {:?}",
-                       src.name());
-                let _ = routing_node.send_refresh_request(src, serialised_refresh);
-            }
-        }
-
-        // // If the churn_node exists in the previous DM's nodes_in_table,
-        // // but not in this reported close_group, it indicates such node is leaving the group.
-        // // However, it is not to say the node is offline, as it may still connected with other
-        // let node_leaving = !close_group.contains(churn_node) /*&& self.nodes_in_table.contains(churn_node)*/;
-        // let on_going_gets = self.database.handle_churn(routing, churn_node, node_leaving);
-
-        // for entry in on_going_gets.iter() {
-        //     if self.failed_pmid_nodes.contains_key(&entry.0) {
-        //         match self.failed_pmid_nodes.get_mut(&entry.0) {
-        //             Some(ref mut pmid_nodes) => pmid_nodes.push(churn_node.clone()),
-        //             None => error!("Failed to insert failed_pmid_node in the cache."),
-        //         };
-        //     } else {
-        //         let _ = self.failed_pmid_nodes.insert(entry.0.clone(), vec![churn_node.clone()]);
-        //     }
-        //     for pmid_node in entry.1.iter() {
-        //         let _ = self.ongoing_gets
-        //                     .insert((entry.0.clone(), pmid_node.clone()), SteadyTime::now());
-        //     }
-        // }
-        // // close_group[0] is supposed to be the vault id
-        // let data_manager_stats = Stats::new(close_group[0].clone(), self.resource_index);
-        // if let Ok(serialised_stats) = serialise(&[data_manager_stats.clone()]) {
-        //     let _ = routing.send_refresh_request(STATS_TAG,
-        //                                          Authority::NaeManager(churn_node.clone()),
-        //                                          serialised_stats,
-        //                                          churn_node.clone());
-        // }
+            let value = RefreshValue::ImmutableDataManagerAccount(pmid_nodes.clone());
+            let refresh = Refresh::new(data_name, value);
+            debug!("ImmutableDataManager sending refresh for account {:?}", src.name());
+            Action::SendRefresh(src, refresh)
+        })
+    }
+
+    // Settles on the group's farming rate, forgets members which have left the group, and returns
+    // a refresh sending the rate on to the group as it now stands.
+    fn refresh_farming_rate(&mut self, routing_node: &RoutingApi) -> Option<Action> {
+        let our_name = match routing_node.name() {
+            Ok(our_name) => our_name,
+            Err(_) => return None,
+        };
+        if let Ok(Some(close_group)) = routing_node.close_group(our_name.clone()) {
+            self.group_farming_rates.retain(|member, _| close_group.contains(member));
+        }
+        self.farming_rate = self.farming_rate();
+        let value = RefreshValue::ImmutableDataManagerFarmingRate(self.farming_rate);
+        let refresh = Refresh::new(&our_name, value);
+        Some(Action::SendRefresh(Authority::NaeManager(our_name), refresh))
+    }
+
+    /// Records the farming rate reported by another member of the group.  Our own refresh is
+    /// ignored, as our rate is already counted.
+    pub fn handle_farming_rate_refresh(&mut self,
+                                       routing_node: &RoutingApi,
+                                       group_member: XorName,
+                                       farming_rate: u64) {
+        if let Ok(our_name) = routing_node.name() {
+            if group_member == our_name {
+                return;
+            }
+        }
+        let _ = self.group_farming_rates.insert(group_member, farming_rate);
+    }
+
+    /// Stops passing over PmidNodes which were reported as out of space long enough ago.
+    pub fn expire_full_pmid_nodes(&mut self) {
+        let now = self.clock.now();
+        let timeout = Duration::seconds(FULL_PMID_NODE_TIMEOUT_SECS);
+        self.full_pmid_nodes.retain(|_, reported_at| now - *reported_at < timeout);
+    }
+
+    /// Gives up on PmidNodes which haven't confirmed storing a Sacrificial copy in time.
+    pub fn expire_pending_sacrificial_puts(&mut self) {
+        let now = self.clock.now();
+        let get_timeout = self.get_timeout;
+        self.pending_sacrificial_puts.retain(|_, asked_at| *asked_at + get_timeout > now);
+    }
+
+    // Remembers the PmidNode if the failure is down to it being out of space, so that it isn't
+    // chosen to hold any more data for a while.
+    fn record_if_full(&mut self,
+                      pmid_node: &XorName,
+                      external_error_indicator: &Vec<u8>)
+                      -> Result<(), InternalError> {
+        let error = try!(serialisation::deserialise::<ClientError>(external_error_indicator));
+        if error == ClientError::NotEnoughSpace {
+            debug!("ImmutableDataManager passing over {:?} as it is full", pmid_node);
+            let _ = self.full_pmid_nodes.insert(pmid_node.clone(), self.clock.now());
+        }
+        Ok(())
+    }
+
+    fn is_sacrificial_put(request: &RequestMessage) -> bool {
+        match request.content {
+            RequestContent::Put(Data::Immutable(ref data), _) => {
+                *data.get_type_tag() == ImmutableDataType::Sacrificial
+            }
+            _ => false,
+        }
     }
 
-    fn reply_with_data_else_cache_request(routing_node: &RoutingNode,
-                                          request: &RequestMessage,
+    fn reply_with_data_else_cache_request(request: &RequestMessage,
                                           message_id: &MessageId,
-                                          metadata: &mut MetadataForGetRequest) {
+                                          metadata: &mut MetadataForGetRequest)
+                                          -> Vec<Action> {
         // If we've already received the chunk, send it to the new requester.  Otherwise
         // add the request to the others for later handling.
         match metadata.data {
             Some(ref data) => {
                 let src = request.dst.clone();
                 let dst = request.src.clone();
-                let _ = routing_node.send_get_success(src,
-                                                      dst,
-                                                      Data::Immutable(data.clone()),
-                                                      message_id.clone());
+                let content = ResponseContent::GetSuccess(Data::Immutable(data.clone()),
+                                                          message_id.clone());
+                vec![Action::response(src, dst, content)]
             }
             None => {
                 metadata.requests.push(request.clone());
+                vec![]
             }
         }
     }
 
     fn check_and_replicate(&mut self,
-                           routing_node: &RoutingNode,
+                           routing_node: &RoutingApi,
                            data_name: &XorName)
-                           -> Result<(), InternalError> {
+                           -> Result<Vec<Action>, InternalError> {
+        let now = self.clock.now();
+        let mut actions = vec![];
         let mut finished = false;
+        let mut unrecoverable = false;
         let mut new_pmid_nodes = HashSet::<DataHolder>::new();
+        let mut fetched_data = None;
         if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
             // Count the good holders, but just return from this function if any queried holders
             // haven't responded yet
             let mut good_holder_count = 0;
             for queried_data_holder in metadata.pmid_nodes.iter() {
                 match queried_data_holder {
-                    &QueriedDataHolder::PendingResponse(_) => return Ok(()),
+                    &QueriedDataHolder::PendingResponse(_) => return Ok(actions),
                     &QueriedDataHolder::Responded(DataHolder::Good(_)) => good_holder_count += 1,
                     &QueriedDataHolder::Responded(DataHolder::Failed(_)) => (),
                 }
@@ -404,6 +801,7 @@ impl ImmutableDataManager {
 
             if good_holder_count >= MIN_REPLICANTS {
                 // We can now delete this cached get request with no need for further action
+                fetched_data = metadata.data.clone();
                 finished = true;
             } else if let Some(ref data) = metadata.data {
                 // Put to new close peers and delete this cached get request
@@ -420,6 +818,16 @@ impl ImmutableDataManager {
                         _ => unreachable!(),
                     }
                 }
+                // Nor do we want holders which have failed in the past, e.g. by leaving the network
+                if let Some(pmid_nodes) = self.accounts.get(data_name) {
+                    nodes_to_exclude.extend(pmid_nodes.iter().filter_map(|data_holder| {
+                        match *data_holder {
+                            DataHolder::Failed(ref name) => Some(name),
+                            DataHolder::Good(_) => None,
+                        }
+                    }));
+                }
+                nodes_to_exclude.extend(self.full_pmid_nodes.keys());
                 let target_pmid_nodes = try!(Self::choose_target_pmid_nodes(routing_node,
                                                                             data_name,
                                                                             nodes_to_exclude));
@@ -427,34 +835,103 @@ impl ImmutableDataManager {
                 for new_pmid_node in target_pmid_nodes.difference(&good_nodes).into_iter() {
                     let src = Authority::NaeManager(data_name.clone());
                     let dst = Authority::NodeManager(new_pmid_node.name().clone());
-                    new_pmid_nodes.insert(new_pmid_node.clone());
-                    let _ = routing_node.send_put_request(src,
-                                                          dst,
-                                                          Data::Immutable(data.clone()),
-                                                          message_id.clone());
+                    let _ = new_pmid_nodes.insert(new_pmid_node.clone());
+                    let content = RequestContent::Put(Data::Immutable(data.clone()),
+                                                      message_id.clone());
+                    actions.push(Action::request(src, dst, content));
                 }
+                fetched_data = Some(data.clone());
+                finished = true;
+            } else if metadata.can_recover() {
+                // Recover the data from backup and sacrificial locations
+                debug!("ImmutableDataManager requesting other copies of {:?}", data_name);
+                metadata.recovery_timestamp = Some(now);
+                for (data_type, copy_name) in Self::other_copies(data_name) {
+                    let src = Authority::NaeManager(data_name.clone());
+                    let dst = Authority::NaeManager(copy_name.clone());
+                    let data_request = DataRequest::Immutable(copy_name, data_type);
+                    actions.push(Action::request(src,
+                                                 dst,
+                                                 RequestContent::Get(data_request,
+                                                                     MessageId::new())));
+                }
+            } else if (metadata.backup_ok.is_some() && metadata.sacrificial_ok.is_some()) ||
+                      metadata.data_type != Some(ImmutableDataType::Normal) {
+                // None of the copies can be retrieved
+                while let Some(request) = metadata.requests.pop() {
+                    actions.push(try!(Self::get_failure(request, &ClientError::NoSuchData)));
+                }
+                unrecoverable = metadata.data_type == Some(ImmutableDataType::Normal);
                 finished = true;
-            } else {
-                // Recover the data from backup and/or sacrificial locations
-                metadata.pmid_nodes.clear();
-                // TODO - actually retrieve the data
             }
         }
 
+        if unrecoverable {
+            error!("ImmutableDataManager failed to recover {:?} from any of its copies",
+                   data_name);
+            self.unrecoverable_count += 1;
+        }
+
         if finished {
             let _ = self.ongoing_gets.remove(data_name);
         }
 
-        if !new_pmid_nodes.is_empty() {
+        // Now that every holder has been checked, tidy up the account
+        if let Some(data) = fetched_data {
             if let Some(pmid_nodes) = self.accounts.get_mut(data_name) {
                 *pmid_nodes = pmid_nodes.union(&new_pmid_nodes).cloned().collect();
+                actions.extend(Self::prune_holders(&data, pmid_nodes));
             }
         }
 
-        Ok(())
+        Ok(actions)
+    }
+
+    // Marks the PmidNode as "failed" in the account if it was previously marked "good".
+    fn mark_holder_failed(accounts: &mut HashMap<XorName, Account>,
+                          data_name: &XorName,
+                          pmid_node: &XorName) {
+        if let Some(pmid_nodes) = accounts.get_mut(data_name) {
+            if pmid_nodes.remove(&DataHolder::Good(pmid_node.clone())) {
+                let _ = pmid_nodes.insert(DataHolder::Failed(pmid_node.clone()));
+            }
+        }
+    }
+
+    // The names and types of the Backup and Sacrificial copies of the chunk named `data_name`.
+    fn other_copies(data_name: &XorName) -> Vec<(ImmutableDataType, XorName)> {
+        let backup_name = XorName(sha512::hash(&data_name.0[..]).0);
+        let sacrificial_name = XorName(sha512::hash(&backup_name.0[..]).0);
+        vec![(ImmutableDataType::Backup, backup_name),
+             (ImmutableDataType::Sacrificial, sacrificial_name)]
+    }
+
+    fn get_success(request: RequestMessage, data: &ImmutableData) -> Action {
+        let message_id = match request.content {
+            RequestContent::Get(_, ref message_id) => message_id.clone(),
+            _ => unreachable!("Logic error"),
+        };
+        let content = ResponseContent::GetSuccess(Data::Immutable(data.clone()), message_id);
+        Action::response(request.dst, request.src, content)
+    }
+
+    fn get_failure(request: RequestMessage, error: &ClientError) -> Result<Action, InternalError> {
+        let message_id = match request.content {
+            RequestContent::Get(_, ref message_id) => message_id.clone(),
+            _ => unreachable!("Logic error"),
+        };
+        let src = request.dst.clone();
+        let dst = request.src.clone();
+        let external_error_indicator = try!(serialisation::serialise(error));
+        let content = ResponseContent::GetFailure {
+            id: message_id,
+            request: request,
+            external_error_indicator: external_error_indicator,
+        };
+        Ok(Action::response(src, dst, content))
     }
 
-    fn choose_target_pmid_nodes(routing_node: &RoutingNode,
+    fn choose_target_pmid_nodes(routing_node: &RoutingApi,
                                 data_name: &XorName,
                                 nodes_to_exclude: Vec<&XorName>)
                                 -> Result<HashSet<DataHolder>, InternalError> {
@@ -473,23 +950,55 @@ impl ImmutableDataManager {
         }
     }
 
-    #[allow(unused)]
-    fn replicate_to(&mut self, _name: &XorName) -> Option<XorName> {
-        // let pmid_nodes = self.database.get_pmid_nodes(name);
-        // if pmid_nodes.len() < MIN_REPLICANTS && pmid_nodes.len() > 0 {
-        //     let mut close_group = routing.close_group_including_self();
-        //     Self::sort_from_target(&mut close_group, &name);
-        //     for close_grp_it in close_group.iter() {
-        //         if pmid_nodes.iter().find(|a| **a == *close_grp_it).is_none() {
-        //             debug!("node {:?} replicating chunk {:?} to a new node {:?}",
-        //                    routing.name(),
-        //                    name,
-        //                    close_grp_it);
-        //             return Some(close_grp_it.clone());
-        //         }
-        //     }
-        // }
-        None
+    // The closest node to the data which isn't already one of its holders, nor excluded.
+    fn replicate_to(routing_node: &RoutingApi,
+                    data_name: &XorName,
+                    pmid_nodes: &Account,
+                    nodes_to_exclude: Vec<&XorName>)
+                    -> Result<Option<XorName>, InternalError> {
+        let mut close_group = match try!(routing_node.close_group(data_name.clone())) {
+            Some(close_group) => close_group,
+            None => return Err(InternalError::NotInCloseGroup),
+        };
+        Self::sort_from_target(&mut close_group, data_name);
+        Ok(close_group.into_iter().find(|name| {
+            !pmid_nodes.iter().any(|holder| holder.name() == name) &&
+            !nodes_to_exclude.contains(&name)
+        }))
+    }
+
+    // Drops the failed holders from the account, along with any good ones beyond the `MAX_HOLDERS`
+    // closest to the chunk.  The surplus holders are asked, via their managers, to delete it.
+    fn prune_holders(data: &ImmutableData, pmid_nodes: &mut Account) -> Vec<Action> {
+        let data_name = data.name();
+        let mut good_nodes = pmid_nodes.iter()
+                                       .filter_map(|data_holder| {
+                                           match *data_holder {
+                                               DataHolder::Good(ref name) => Some(name.clone()),
+                                               DataHolder::Failed(_) => None,
+                                           }
+                                       })
+                                       .collect::<Vec<_>>();
+        Self::sort_from_target(&mut good_nodes, &data_name);
+        let surplus_nodes = if good_nodes.len() > MAX_HOLDERS {
+            good_nodes.split_off(MAX_HOLDERS)
+        } else {
+            vec![]
+        };
+        *pmid_nodes = good_nodes.into_iter().map(DataHolder::Good).collect();
+
+        surplus_nodes.into_iter()
+                     .map(|pmid_node| {
+                         debug!("ImmutableDataManager dropping {:?} as a holder of {:?}",
+                                pmid_node,
+                                data_name);
+                         let src = Authority::NaeManager(data_name.clone());
+                         let dst = Authority::NodeManager(pmid_node);
+                         let content = RequestContent::Delete(Data::Immutable(data.clone()),
+                                                              MessageId::new());
+                         Action::request(src, dst, content)
+                     })
+                     .collect()
     }
 
     fn sort_from_target(names: &mut Vec<XorName>, target: &XorName) {
@@ -500,86 +1009,234 @@ impl ImmutableDataManager {
             }
         });
     }
+}
+
+impl Persona for ImmutableDataManager {
+    fn name(&self) -> &'static str {
+        "immutable_data_manager"
+    }
+
+    fn handle_request(&mut self,
+                      routing_node: &RoutingApi,
+                      request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        match (&request.src, &request.dst, &request.content) {
+            (&Authority::Client{ .. },
+             &Authority::NaeManager(_),
+             &RequestContent::Get(DataRequest::Immutable(_, _), _)) |
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &RequestContent::Get(DataRequest::Immutable(_, _), _)) => {
+                Some(self.handle_get(request))
+            }
+            (&Authority::ClientManager(_),
+             &Authority::NaeManager(_),
+             &RequestContent::Put(Data::Immutable(ref data), ref message_id)) |
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &RequestContent::Put(Data::Immutable(ref data), ref message_id)) => {
+                Some(self.handle_put(routing_node, data, message_id))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_response(&mut self,
+                       routing_node: &RoutingApi,
+                       response: &ResponseMessage)
+                       -> Option<Result<Vec<Action>, InternalError>> {
+        match (&response.src, &response.dst, &response.content) {
+            (&Authority::ManagedNode(_),
+             &Authority::NaeManager(_),
+             &ResponseContent::GetSuccess(Data::Immutable(_), _)) => {
+                Some(self.handle_get_success(routing_node, response))
+            }
+            (&Authority::ManagedNode(ref pmid_node),
+             &Authority::NaeManager(_),
+             &ResponseContent::GetFailure{ ref id, ref request, .. }) => {
+                Some(self.handle_get_failure(routing_node, pmid_node, id, request))
+            }
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &ResponseContent::GetSuccess(Data::Immutable(ref data), ref message_id)) => {
+                Some(self.handle_recovered_copy(routing_node, data, message_id))
+            }
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &ResponseContent::GetFailure{ ref id, ref request, .. }) => {
+                Some(self.handle_recovery_failure(routing_node, id, request))
+            }
+            (&Authority::ManagedNode(ref pmid_node),
+             &Authority::NaeManager(ref data_name),
+             &ResponseContent::PutSuccess(..)) => {
+                Some(Ok(self.handle_put_success(pmid_node, data_name)))
+            }
+            (&Authority::NodeManager(ref pmid_node),
+             &Authority::NaeManager(_),
+             &ResponseContent::PutFailure{ ref request, ref external_error_indicator, .. })
+                if Self::is_sacrificial_put(request) => {
+                Some(self.handle_sacrifice(pmid_node, request, external_error_indicator))
+            }
+            (&Authority::NodeManager(ref pmid_node),
+             &Authority::NaeManager(_),
+             &ResponseContent::PutFailure{ ref request, ref external_error_indicator, .. }) => {
+                Some(self.handle_put_failure(routing_node,
+                                             pmid_node,
+                                             request,
+                                             external_error_indicator))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_refresh_request(&mut self,
+                              routing_node: &RoutingApi,
+                              src: &Authority,
+                              dst: &Authority,
+                              sender: &XorName,
+                              refresh: &Refresh)
+                              -> Option<Result<Vec<Action>, InternalError>> {
+        match (src, dst, &refresh.value) {
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &RefreshValue::ImmutableDataManagerAccount(ref account)) => {
+                Some(Ok(self.handle_refresh(refresh.name, sender.clone(), account.clone())))
+            }
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &RefreshValue::ImmutableDataManagerFarmingRate(farming_rate)) => {
+                self.handle_farming_rate_refresh(routing_node, *sender, farming_rate);
+                Some(Ok(vec![]))
+            }
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &RefreshValue::SyncRequest(ref persona)) if persona == self.name() => {
+                Some(Ok(self.account_refresh(&refresh.name).into_iter().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_churn(&mut self, routing_node: &RoutingApi) -> Vec<Action> {
+        let mut actions = vec![];
+        actions.extend(self.refresh_farming_rate(routing_node));
+        for data_name in self.accounts.keys() {
+            actions.extend(self.account_refresh(data_name));
+        }
+        actions
+    }
 
-    // fn remove_pmid_node_from_account(&mut self, data_name: &XorName, pmid_node: &XorName) {
-    //     if let Some(account) = self.accounts.get_mut(data_name) {
-    //         let _ = account.remove(pmid_node);
-    //     }
-    // }
-
-    // fn handle_failed_request_for_data(&mut self,
-    //                                   data: ::routing::data::Data,
-    //                                   pmid_node: XorName,
-    //                                   our_authority: Authority) {
-    //     // Validate that the Data is ImmutableData.
-    //     let immutable_data = match data {
-    //         ::routing::data::Data::Immutable(immutable_data) => immutable_data,
-    //         _ => return,
-    //     };
-
-    //     // giving more weight when failed in storing a Normal immutable data
-    //     // i.e increasing slowly but dropping quickly
-    //     self.resource_index = ::std::cmp::max(1, self.resource_index - 4);
-
-    //     let data_name = immutable_data.name();
-    //     self.database.remove_pmid_node(&data_name, pmid_node);
-    //     match *immutable_data.get_type_tag() {
-    //         ImmutableDataType::Normal => {
-    //             match self.replicate_to(&data_name) {
-    //                 Some(pmid_node) => {
-    //                     self.database.add_pmid_node(&data_name, pmid_node.clone());
-    //                     let location = Authority::NodeManager(pmid_node);
-    //                     let content = ::routing::data::Data::Immutable(immutable_data);
-    //                     self.routing.send_put_request(our_authority, location, content);
-    //                 }
-    //                 None => {
-    //                     warn!("Failed to find nodes to replicate data to.");
-    //                 }
-    //             }
-    //         }
-    //         // Don't need to replicate Backup or Sacrificial chunks
-    //         _ => {}
-    //     }
-    // }
-
-    // fn handle_had_to_clear_sacrificial(&mut self, data_name: &XorName, pmid_node: &XorName) {
-    //     self.remove_pmid_node_from_account(data_name, pmid_node);
-    // }
+    fn handle_churn_of(&mut self,
+                       routing_node: &RoutingApi,
+                       churned_node: &XorName)
+                       -> Vec<Action> {
+        let mut actions = vec![];
+        actions.extend(self.refresh_farming_rate(routing_node));
+        for data_name in self.accounts.keys() {
+            if utils::is_affected_by_churn(routing_node, data_name, churned_node) {
+                actions.extend(self.account_refresh(data_name));
+            }
+        }
+        actions
+    }
+
+    fn handle_node_lost(&mut self, routing_node: &RoutingApi, node_lost: XorName) -> Vec<Action> {
+        // Mark the lost node as failed before the accounts are sent out in the refreshes
+        let mut actions = self.replace_lost_holder(routing_node, &node_lost);
+        actions.extend(self.handle_churn_of(routing_node, &node_lost));
+        actions
+    }
+
+    fn handle_tick(&mut self, routing_node: &RoutingApi) -> Result<Vec<Action>, InternalError> {
+        self.expire_full_pmid_nodes();
+        self.expire_pending_sacrificial_puts();
+        self.refresh_accumulator.expire();
+        self.fail_expired_gets(routing_node)
+    }
+
+    fn load_state(&mut self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        if let Some(accounts) = try!(state_store.load(STATE_NAME)) {
+            self.accounts = accounts;
+        }
+        if let Some(farming_rate) = try!(state_store.load(FARMING_RATE_STATE_NAME)) {
+            self.farming_rate = farming_rate;
+        }
+        // Kept with the rate, so copies lost after a restart still lower it
+        if let Some(holders) = try!(state_store.load(SACRIFICIAL_HOLDERS_STATE_NAME)) {
+            self.confirmed_sacrificial_holders = holders;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        try!(state_store.save(STATE_NAME, &self.accounts));
+        try!(state_store.save(FARMING_RATE_STATE_NAME, &self.farming_rate));
+        state_store.save(SACRIFICIAL_HOLDERS_STATE_NAME,
+                         &self.confirmed_sacrificial_holders)
+    }
+
+    fn update_status(&self, status: &mut Status) {
+        status.immutable_data_manager_account_count = self.accounts.len();
+        status.immutable_data_manager_ongoing_get_count = self.ongoing_gets.len();
+        status.immutable_data_manager_unrecoverable_count = self.unrecoverable_count;
+        status.immutable_data_manager_farming_rate = self.farming_rate();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.ongoing_gets.is_empty()
+    }
 }
 
 
 
-#[cfg(all(test, feature = "use-mock-routing"))]
+#[cfg(test)]
 mod test {
     use super::*;
-    use maidsafe_utilities::log;
+    use clock::MockClock;
+    use config_handler::Config;
+    use error::ClientError;
+    use maidsafe_utilities::{log, serialisation};
+    use mock_routing::MockRoutingNode;
+    use personas::Persona;
     use rand::random;
     use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
-                  RequestContent, RequestMessage};
+                  RequestContent, RequestMessage, ResponseContent, ResponseMessage};
+    use routing_api::RoutingApi;
+    use sodiumoxide::crypto::hash::sha512;
     use sodiumoxide::crypto::sign;
-    use std::sync::mpsc;
-    use utils::generate_random_vec_u8;
-    use vault::RoutingNode;
+    use std::sync::{Arc, mpsc};
+    use time::Duration;
+    use types::{Action, Refresh, RefreshValue};
+    use utils::{self, generate_random_vec_u8};
+    use worker::send_actions;
+    use xor_name::XorName;
 
     struct Environment {
         pub our_authority: Authority,
-        pub routing: RoutingNode,
+        pub routing: MockRoutingNode,
+        pub clock: Arc<MockClock>,
         pub immutable_data_manager: ImmutableDataManager,
         pub data: ImmutableData,
     }
 
     fn environment_setup() -> Environment {
+        environment_setup_for(ImmutableDataType::Normal)
+    }
+
+    fn environment_setup_for(data_type: ImmutableDataType) -> Environment {
         log::init(false);
-        let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0));
-        let immutable_data_manager = ImmutableDataManager::new();
+        let routing = unwrap_result!(MockRoutingNode::new(mpsc::channel().0));
+        let clock = Arc::new(MockClock::new());
+        let immutable_data_manager = ImmutableDataManager::new(&Config::default(), clock.clone());
         loop {
             // Create random ImmutableData until we get one we're close to.
             let value = generate_random_vec_u8(1024);
-            let data = ImmutableData::new(ImmutableDataType::Normal, value);
+            let data = ImmutableData::new(data_type.clone(), value);
             if unwrap_result!(routing.close_group(data.name())).is_some() {
                 return Environment {
                     our_authority: Authority::NaeManager(data.name().clone()),
                     routing: routing,
+                    clock: clock,
                     immutable_data_manager: immutable_data_manager,
                     data: data,
                 };
@@ -587,48 +1244,553 @@ mod test {
         }
     }
 
+    fn client_get_request(env: &Environment) -> RequestMessage {
+        let keys = sign::gen_keypair();
+        let from = random();
+        let client = Authority::Client {
+            client_key: keys.0,
+            proxy_node_name: from,
+        };
+        RequestMessage {
+            src: client,
+            dst: env.our_authority.clone(),
+            content: RequestContent::Get(DataRequest::Immutable(env.data.name().clone(),
+                                                                ImmutableDataType::Normal),
+                                         MessageId::new()),
+        }
+    }
+
     #[test]
     fn handle_put_get() {
         let mut env = environment_setup();
         {
             let message_id = MessageId::new();
-            unwrap_result!(env.immutable_data_manager
-                              .handle_put(&env.routing, &env.data, &message_id));
-            let put_requests = env.routing.put_requests_given();
-            assert_eq!(put_requests.len(), REPLICANTS);
-            for i in 0..put_requests.len() {
-                assert_eq!(put_requests[i].src, env.our_authority);
-                assert_eq!(put_requests[i].content,
-                           RequestContent::Put(Data::Immutable(env.data.clone()),
-                                               message_id.clone()));
+            let actions = unwrap_result!(env.immutable_data_manager
+                                            .handle_put(&env.routing, &env.data, &message_id));
+            assert_eq!(actions.len(), REPLICANTS + 2);
+            let mut copies = vec![];
+            for action in &actions {
+                let put_request = match *action {
+                    Action::SendRequest(ref put_request) => put_request,
+                    _ => panic!("Unexpected action {:?}", action),
+                };
+                assert_eq!(put_request.src, env.our_authority);
+                match put_request.dst {
+                    Authority::NodeManager(_) => {
+                        assert_eq!(put_request.content,
+                                   RequestContent::Put(Data::Immutable(env.data.clone()),
+                                                       message_id.clone()));
+                    }
+                    Authority::NaeManager(ref copy_name) => {
+                        // The Backup and Sacrificial copies go to their own managers
+                        match put_request.content {
+                            RequestContent::Put(Data::Immutable(ref copy), _) => {
+                                assert_eq!(copy.name(), *copy_name);
+                                assert_eq!(copy.value(), env.data.value());
+                                copies.push(copy.get_type_tag().clone());
+                            }
+                            _ => panic!("Unexpected request {:?}", put_request),
+                        }
+                    }
+                    _ => panic!("Unexpected request {:?}", put_request),
+                }
             }
+            assert_eq!(copies,
+                       vec![ImmutableDataType::Backup, ImmutableDataType::Sacrificial]);
         }
         {
-            let keys = sign::gen_keypair();
-            let from = random();
-            let client = Authority::Client {
-                client_key: keys.0,
-                proxy_node_name: from,
-            };
+            let request = client_get_request(&env);
+            let actions = unwrap_result!(env.immutable_data_manager.handle_get(&request));
+            assert_eq!(actions.len(), REPLICANTS);
+            for action in &actions {
+                match *action {
+                    Action::SendRequest(ref get_request) => {
+                        assert_eq!(get_request.src, env.our_authority);
+                        assert_eq!(get_request.content, request.content);
+                    }
+                    _ => panic!("Unexpected action {:?}", action),
+                }
+            }
+        }
+    }
 
-            let message_id = MessageId::new();
-            let content =
-                RequestContent::Get(DataRequest::Immutable(env.data.name().clone(),
-                                                           ImmutableDataType::Normal),
-                                    message_id);
-            let request = RequestMessage {
-                src: client.clone(),
-                dst: env.our_authority.clone(),
-                content: content.clone(),
+    #[test]
+    fn handle_put_of_backup_copy() {
+        let mut env = environment_setup_for(ImmutableDataType::Backup);
+        let message_id = MessageId::new();
+        let actions = unwrap_result!(env.immutable_data_manager
+                                        .handle_put(&env.routing, &env.data, &message_id));
+
+        // Only the Normal copy's managers create the other copies
+        assert_eq!(actions.len(), REPLICANTS);
+        for action in &actions {
+            match *action {
+                Action::SendRequest(ref put_request) => {
+                    match put_request.dst {
+                        Authority::NodeManager(_) => (),
+                        _ => panic!("Unexpected request {:?}", put_request),
+                    }
+                    assert_eq!(put_request.content,
+                               RequestContent::Put(Data::Immutable(env.data.clone()),
+                                                   message_id.clone()));
+                }
+                _ => panic!("Unexpected action {:?}", action),
+            }
+        }
+        assert!(env.immutable_data_manager.accounts.contains_key(&env.data.name()));
+    }
+
+    // Puts the data, then lets a client's Get for it time out at every PmidNode.  Returns the
+    // client's request and the resulting requests for the Backup and Sacrificial copies.
+    fn get_with_unresponsive_holders(env: &mut Environment)
+                                     -> (RequestMessage, Vec<RequestMessage>) {
+        let message_id = MessageId::new();
+        let _ = unwrap_result!(env.immutable_data_manager
+                                  .handle_put(&env.routing, &env.data, &message_id));
+        let request = client_get_request(env);
+        let _ = unwrap_result!(env.immutable_data_manager.handle_get(&request));
+
+        // Nothing happens until the PmidNodes have had their chance to respond.
+        env.clock.advance(Duration::seconds(DEFAULT_GET_TIMEOUT_SECS as i64 - 1));
+        assert!(unwrap_result!(env.immutable_data_manager.fail_expired_gets(&env.routing))
+                    .is_empty());
+
+        // Then every holder is marked as failed, and the other copies are requested.
+        env.clock.advance(Duration::seconds(2));
+        let actions = unwrap_result!(env.immutable_data_manager.fail_expired_gets(&env.routing));
+        assert_eq!(actions.len(), 2);
+        let recovery_requests = actions.iter()
+                                       .map(|action| {
+                                           match *action {
+                                               Action::SendRequest(ref request) => request.clone(),
+                                               _ => panic!("Unexpected action {:?}", action),
+                                           }
+                                       })
+                                       .collect::<Vec<_>>();
+        let data_types = vec![ImmutableDataType::Backup, ImmutableDataType::Sacrificial];
+        for (recovery_request, data_type) in recovery_requests.iter().zip(data_types) {
+            let copy = ImmutableData::new(data_type.clone(), env.data.value().clone());
+            assert_eq!(recovery_request.src, env.our_authority);
+            assert_eq!(recovery_request.dst, Authority::NaeManager(copy.name()));
+            match recovery_request.content {
+                RequestContent::Get(DataRequest::Immutable(ref name, ref requested_type), _) => {
+                    assert_eq!(*name, copy.name());
+                    assert_eq!(*requested_type, data_type);
+                }
+                _ => panic!("Unexpected request {:?}", recovery_request),
+            }
+        }
+
+        let account = &env.immutable_data_manager.accounts[&env.data.name()];
+        assert_eq!(account.len(), REPLICANTS);
+        assert!(account.iter().all(|holder| match *holder {
+            DataHolder::Failed(_) => true,
+            DataHolder::Good(_) => false,
+        }));
+        assert!(!env.immutable_data_manager.is_idle());
+        (request, recovery_requests)
+    }
+
+    #[test]
+    fn pending_get_times_out() {
+        let mut env = environment_setup();
+        let (request, _) = get_with_unresponsive_holders(&mut env);
+
+        // Neither of the other copies arrives either, so the client is told the Get failed.
+        env.clock.advance(Duration::seconds(DEFAULT_GET_TIMEOUT_SECS as i64 + 1));
+        let actions = unwrap_result!(env.immutable_data_manager.fail_expired_gets(&env.routing));
+        assert_eq!(actions.len(), 1);
+        let response = match actions[0] {
+            Action::SendResponse(ref response) => response.clone(),
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        };
+        assert_eq!(response.src, env.our_authority);
+        assert_eq!(response.dst, request.src);
+        match response.content {
+            ResponseContent::GetFailure{ request: ref failed_request,
+                                         ref external_error_indicator,
+                                         .. } => {
+                assert_eq!(*failed_request, request);
+                match unwrap_result!(serialisation::deserialise::<ClientError>(
+                        external_error_indicator)) {
+                    ClientError::Timeout => (),
+                    error => panic!("Unexpected error {:?}", error),
+                }
+            }
+            _ => panic!("Unexpected response {:?}", response),
+        }
+        assert_eq!(env.immutable_data_manager.unrecoverable_count, 1);
+        assert!(env.immutable_data_manager.is_idle());
+    }
+
+    #[test]
+    fn recover_from_backup_copy() {
+        let mut env = environment_setup();
+        let (request, recovery_requests) = get_with_unresponsive_holders(&mut env);
+        let backup_message_id = match recovery_requests[0].content {
+            RequestContent::Get(_, ref message_id) => message_id.clone(),
+            _ => unreachable!(),
+        };
+
+        // The Backup copy's managers return it, so the client gets the data and it's stored on
+        // new holders in place of the failed ones.
+        let backup = ImmutableData::new(ImmutableDataType::Backup, env.data.value().clone());
+        let actions = unwrap_result!(env.immutable_data_manager
+                                        .handle_recovered_copy(&env.routing,
+                                                               &backup,
+                                                               &backup_message_id));
+        let mut client_responses = 0;
+        for action in &actions {
+            match *action {
+                Action::SendResponse(ref response) => {
+                    assert_eq!(response.dst, request.src);
+                    match response.content {
+                        ResponseContent::GetSuccess(Data::Immutable(ref data), _) => {
+                            assert_eq!(*data, env.data)
+                        }
+                        _ => panic!("Unexpected response {:?}", response),
+                    }
+                    client_responses += 1;
+                }
+                Action::SendRequest(ref put_request) => {
+                    match put_request.content {
+                        RequestContent::Put(Data::Immutable(ref data), _) => {
+                            assert_eq!(*data, env.data)
+                        }
+                        _ => panic!("Unexpected request {:?}", put_request),
+                    }
+                }
+                _ => panic!("Unexpected action {:?}", action),
+            }
+        }
+        assert_eq!(client_responses, 1);
+        assert_eq!(env.immutable_data_manager.unrecoverable_count, 0);
+        assert!(env.immutable_data_manager.is_idle());
+    }
+
+    #[test]
+    fn put_failure_replicates_to_new_holder() {
+        let mut env = environment_setup();
+        let message_id = MessageId::new();
+        let actions = unwrap_result!(env.immutable_data_manager
+                                        .handle_put(&env.routing, &env.data, &message_id));
+        let put_request = match actions[0] {
+            Action::SendRequest(ref put_request) => put_request.clone(),
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        };
+        let failed_pmid_node = put_request.dst.name().clone();
+        let error = unwrap_result!(serialisation::serialise(&ClientError::NoSuchData));
+        let put_failure = ResponseMessage {
+            src: put_request.dst.clone(),
+            dst: env.our_authority.clone(),
+            content: ResponseContent::PutFailure {
+                id: message_id,
+                request: put_request,
+                external_error_indicator: error,
+            },
+        };
+
+        let result = env.immutable_data_manager.handle_response(&env.routing, &put_failure);
+        let actions = unwrap_result!(unwrap_option!(result, "Put failure not handled"));
+        assert_eq!(actions.len(), 1);
+        let new_pmid_node = match actions[0] {
+            Action::SendRequest(ref request) => {
+                assert_eq!(request.src, env.our_authority);
+                match request.content {
+                    RequestContent::Put(Data::Immutable(ref data), _) => {
+                        assert_eq!(*data, env.data)
+                    }
+                    _ => panic!("Unexpected request {:?}", request),
+                }
+                match request.dst {
+                    Authority::NodeManager(ref name) => name.clone(),
+                    _ => panic!("Unexpected request {:?}", request),
+                }
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        };
+        assert!(new_pmid_node != failed_pmid_node);
+        {
+            let account = &env.immutable_data_manager.accounts[&env.data.name()];
+            assert_eq!(account.len(), REPLICANTS);
+            assert!(!account.iter().any(|holder| *holder.name() == failed_pmid_node));
+            assert!(account.contains(&DataHolder::Good(new_pmid_node)));
+        }
+
+        // A repeated failure is ignored
+        let result = env.immutable_data_manager.handle_response(&env.routing, &put_failure);
+        assert!(unwrap_result!(unwrap_option!(result, "Put failure not handled")).is_empty());
+    }
+
+    // The PmidNodes which the data is put to.
+    fn put_destinations(env: &mut Environment) -> Vec<XorName> {
+        let _ = env.immutable_data_manager.accounts.remove(&env.data.name());
+        let actions = unwrap_result!(env.immutable_data_manager
+                                        .handle_put(&env.routing, &env.data, &MessageId::new()));
+        let mut destinations = vec![];
+        for action in actions {
+            if let Action::SendRequest(RequestMessage { dst: Authority::NodeManager(name), .. }) =
+                   action {
+                destinations.push(name);
+            }
+        }
+        destinations
+    }
+
+    #[test]
+    fn full_pmid_node_is_passed_over() {
+        let mut env = environment_setup();
+        let message_id = MessageId::new();
+        let actions = unwrap_result!(env.immutable_data_manager
+                                        .handle_put(&env.routing, &env.data, &message_id));
+        let put_request = match actions[0] {
+            Action::SendRequest(ref put_request) => put_request.clone(),
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        };
+        let full_pmid_node = put_request.dst.name().clone();
+        let error = unwrap_result!(serialisation::serialise(&ClientError::NotEnoughSpace));
+        let put_failure = ResponseMessage {
+            src: put_request.dst.clone(),
+            dst: env.our_authority.clone(),
+            content: ResponseContent::PutFailure {
+                id: message_id,
+                request: put_request,
+                external_error_indicator: error,
+            },
+        };
+        let result = env.immutable_data_manager.handle_response(&env.routing, &put_failure);
+        let _ = unwrap_result!(unwrap_option!(result, "Put failure not handled"));
+
+        // The full node isn't chosen for new data
+        let destinations = put_destinations(&mut env);
+        assert_eq!(destinations.len(), REPLICANTS);
+        assert!(!destinations.contains(&full_pmid_node));
+
+        // Once it has had time to free some space, it's chosen again
+        env.clock.advance(Duration::seconds(FULL_PMID_NODE_TIMEOUT_SECS + 1));
+        let _ = unwrap_result!(env.immutable_data_manager.handle_tick(&env.routing));
+        assert!(put_destinations(&mut env).contains(&full_pmid_node));
+    }
+
+    #[test]
+    fn lost_holder_is_replaced() {
+        let mut env = environment_setup();
+        let _ = unwrap_result!(env.immutable_data_manager
+                                  .handle_put(&env.routing, &env.data, &MessageId::new()));
+        let holders = env.immutable_data_manager.accounts[&env.data.name()]
+                          .iter()
+                          .map(|data_holder| data_holder.name().clone())
+                          .collect::<Vec<_>>();
+        let lost_node = holders[0].clone();
+
+        // The chunk is fetched from the remaining holders
+        let actions = env.immutable_data_manager.handle_node_lost(&env.routing, lost_node.clone());
+        let get_requests = actions.iter()
+                                  .filter_map(|action| {
+                                      match *action {
+                                          Action::SendRequest(ref request) => Some(request.clone()),
+                                          _ => None,
+                                      }
+                                  })
+                                  .collect::<Vec<_>>();
+        assert_eq!(get_requests.len(), REPLICANTS - 1);
+        assert!(env.immutable_data_manager.accounts[&env.data.name()]
+                   .contains(&DataHolder::Failed(lost_node.clone())));
+
+        // Once they've all returned it, it's put to a new holder
+        let mut put_actions = vec![];
+        for get_request in get_requests {
+            assert!(get_request.dst != Authority::ManagedNode(lost_node.clone()));
+            let message_id = match get_request.content {
+                RequestContent::Get(_, ref message_id) => message_id.clone(),
+                _ => panic!("Unexpected request {:?}", get_request),
             };
-            env.immutable_data_manager.handle_get(&env.routing, &request);
-            let get_requests = env.routing.get_requests_given();
-            assert_eq!(get_requests.len(), REPLICANTS);
-            for i in 0..get_requests.len() {
-                assert_eq!(get_requests[i].src, env.our_authority);
-                assert_eq!(get_requests[i].content, content);
+            let get_success = ResponseMessage {
+                src: get_request.dst.clone(),
+                dst: get_request.src.clone(),
+                content: ResponseContent::GetSuccess(Data::Immutable(env.data.clone()), message_id),
+            };
+            let result = env.immutable_data_manager.handle_response(&env.routing, &get_success);
+            put_actions.extend(unwrap_result!(unwrap_option!(result, "Get success not handled")));
+        }
+        assert_eq!(put_actions.len(), 1);
+        let new_pmid_node = match put_actions[0] {
+            Action::SendRequest(RequestMessage { dst: Authority::NodeManager(ref name), .. }) => {
+                name.clone()
+            }
+            _ => panic!("Unexpected action {:?}", put_actions[0]),
+        };
+        assert!(!holders.contains(&new_pmid_node));
+        let account = &env.immutable_data_manager.accounts[&env.data.name()];
+        assert_eq!(account.len(), REPLICANTS);
+        assert!(!account.contains(&DataHolder::Failed(lost_node)));
+        assert!(account.contains(&DataHolder::Good(new_pmid_node)));
+        assert!(env.immutable_data_manager.ongoing_gets.is_empty());
+    }
+
+    #[test]
+    fn prune_failed_and_surplus_holders() {
+        let env = environment_setup();
+        let data_name = env.data.name();
+        let close_group = unwrap_option!(unwrap_result!(env.routing.close_group(data_name.clone())),
+                                         "Not close to the data");
+        let far_nodes = vec![random::<XorName>(), random::<XorName>()];
+        let mut account = close_group.iter()
+                                     .chain(far_nodes.iter())
+                                     .map(|name| DataHolder::Good(name.clone()))
+                                     .collect::<Account>();
+        let failed_node = random::<XorName>();
+        let _ = account.insert(DataHolder::Failed(failed_node.clone()));
+
+        let mut expected_holders = close_group.iter()
+                                              .chain(far_nodes.iter())
+                                              .cloned()
+                                              .collect::<Vec<_>>();
+        ImmutableDataManager::sort_from_target(&mut expected_holders, &data_name);
+        let expected_surplus = expected_holders.split_off(MAX_HOLDERS);
+
+        // The furthest holders are asked to delete the chunk, and only the closest are kept
+        let actions = ImmutableDataManager::prune_holders(&env.data, &mut account);
+        assert_eq!(actions.len(), expected_surplus.len());
+        for (action, surplus_node) in actions.iter().zip(expected_surplus) {
+            match *action {
+                Action::SendRequest(ref request) => {
+                    assert_eq!(request.src, Authority::NaeManager(data_name.clone()));
+                    assert_eq!(request.dst, Authority::NodeManager(surplus_node));
+                    match request.content {
+                        RequestContent::Delete(Data::Immutable(ref data), _) => {
+                            assert_eq!(*data, env.data)
+                        }
+                        _ => panic!("Unexpected request {:?}", request),
+                    }
+                }
+                _ => panic!("Unexpected action {:?}", action),
+            }
+        }
+        assert_eq!(account,
+                   expected_holders.into_iter().map(DataHolder::Good).collect::<Account>());
+    }
+
+    #[test]
+    fn farming_rate_tracks_sacrificial_copies() {
+        let mut env = environment_setup_for(ImmutableDataType::Sacrificial);
+        assert_eq!(env.immutable_data_manager.farming_rate(), MIN_FARMING_RATE);
+
+        // Sending a Sacrificial copy out doesn't raise the rate by itself
+        let message_id = MessageId::new();
+        let actions = unwrap_result!(env.immutable_data_manager
+                                        .handle_put(&env.routing, &env.data, &message_id));
+        assert_eq!(env.immutable_data_manager.farming_rate(), MIN_FARMING_RATE);
+        let put_requests = actions.iter()
+                                  .map(|action| {
+                                      match *action {
+                                          Action::SendRequest(ref put_request) => {
+                                              put_request.clone()
+                                          }
+                                          _ => panic!("Unexpected action {:?}", action),
+                                      }
+                                  })
+                                  .collect::<Vec<_>>();
+
+        // A holder confirming it has stored the copy does.  Repeated confirmations are ignored.
+        let pmid_node = put_requests[0].dst.name().clone();
+        let stored = ResponseMessage {
+            src: Authority::ManagedNode(pmid_node.clone()),
+            dst: env.our_authority.clone(),
+            content: ResponseContent::PutSuccess(sha512::hash(&[]), message_id.clone()),
+        };
+        for _ in 0..2 {
+            let result = env.immutable_data_manager.handle_response(&env.routing, &stored);
+            assert!(unwrap_result!(unwrap_option!(result, "Success not handled")).is_empty());
+            assert_eq!(env.immutable_data_manager.farming_rate(), MIN_FARMING_RATE + 1);
+        }
+
+        // A holder which never stored it leaves the rate alone
+        let our_authority = env.our_authority.clone();
+        let sacrifice = |put_request: &RequestMessage, error: ClientError| {
+            ResponseMessage {
+                src: put_request.dst.clone(),
+                dst: our_authority.clone(),
+                content: ResponseContent::PutFailure {
+                    id: message_id.clone(),
+                    request: put_request.clone(),
+                    external_error_indicator: unwrap_result!(serialisation::serialise(&error)),
+                },
             }
+        };
+        let refusal = sacrifice(&put_requests[1], ClientError::NotEnoughSpace);
+        let result = env.immutable_data_manager.handle_response(&env.routing, &refusal);
+        assert!(unwrap_result!(unwrap_option!(result, "Refusal not handled")).is_empty());
+        assert_eq!(env.immutable_data_manager.farming_rate(), MIN_FARMING_RATE + 1);
+
+        // Nor does one which didn't confirm it in time
+        env.clock.advance(Duration::seconds(DEFAULT_GET_TIMEOUT_SECS as i64 + 1));
+        env.immutable_data_manager.expire_pending_sacrificial_puts();
+        let late_loss = sacrifice(&put_requests[2], ClientError::HadToClearSacrificial);
+        let result = env.immutable_data_manager.handle_response(&env.routing, &late_loss);
+        assert!(unwrap_result!(unwrap_option!(result, "Sacrifice not handled")).is_empty());
+        assert_eq!(env.immutable_data_manager.farming_rate(), MIN_FARMING_RATE + 1);
+
+        // Losing a stored copy drops the rate again.  Repeated notices are ignored.
+        let loss = sacrifice(&put_requests[0], ClientError::HadToClearSacrificial);
+        for _ in 0..2 {
+            let result = env.immutable_data_manager.handle_response(&env.routing, &loss);
+            assert!(unwrap_result!(unwrap_option!(result, "Sacrifice not handled")).is_empty());
+            assert_eq!(env.immutable_data_manager.farming_rate(), MIN_FARMING_RATE);
         }
+        let account = &env.immutable_data_manager.accounts[&env.data.name()];
+        assert_eq!(account.len(), REPLICANTS - 3);
+        assert!(!account.contains(&DataHolder::Good(pmid_node)));
+    }
+
+    #[test]
+    fn farming_rate_refreshes_are_keyed_by_sender() {
+        let mut env = environment_setup();
+        let our_name = unwrap_result!(env.routing.name());
+        let rate_refresh = |env: &mut Environment, sender: &XorName, farming_rate: u64| {
+            // The name the refresh claims to be for is ignored
+            let refresh = Refresh::new(&random(),
+                                       RefreshValue::ImmutableDataManagerFarmingRate(farming_rate));
+            let result = env.immutable_data_manager
+                            .handle_refresh_request(&env.routing,
+                                                    &env.our_authority,
+                                                    &env.our_authority,
+                                                    sender,
+                                                    &refresh);
+            assert!(unwrap_result!(unwrap_option!(result, "Refresh not handled")).is_empty());
+        };
+
+        // The group's rate is the median of all the members' rates, one per member
+        let member = random::<XorName>();
+        rate_refresh(&mut env, &member, 5);
+        rate_refresh(&mut env, &random(), 7);
+        rate_refresh(&mut env, &member, 9);
+        assert_eq!(env.immutable_data_manager.group_farming_rates.len(), 2);
+        assert_eq!(env.immutable_data_manager.group_farming_rates[&member], 9);
+        assert_eq!(env.immutable_data_manager.farming_rate(), 7);
+
+        // Our own rate is already counted, so our own refresh is ignored
+        rate_refresh(&mut env, &our_name, 1000);
+        assert!(!env.immutable_data_manager.group_farming_rates.contains_key(&our_name));
+        assert_eq!(env.immutable_data_manager.farming_rate(), 7);
+    }
+
+    #[test]
+    fn churn_refreshes_affected_accounts() {
+        let mut env = environment_setup();
+        let churn = utils::churn_environment();
+        for data_name in churn.near_names.iter().chain(Some(&churn.far_name)) {
+            let _ = env.immutable_data_manager.accounts.insert(*data_name, Account::new());
+        }
+
+        // Each churn event also sends the farming rate on to the group
+        send_actions(&churn.routing,
+                     env.immutable_data_manager.handle_node_added(&churn.routing, churn.member));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 4);
+
+        send_actions(&churn.routing,
+                     env.immutable_data_manager.handle_node_added(&churn.routing, churn.far_name));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 5);
     }
 
     #[test]
@@ -15,20 +15,30 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use clock::Clock;
+use config_handler::Config;
 use error::{ClientError, InternalError};
-use lru_time_cache::LruCache;
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, MessageId, RequestContent, RequestMessage};
+use personas::Persona;
+use routing::{Authority, Data, MessageId, RequestContent, RequestMessage, ResponseContent,
+              ResponseMessage};
+use refresh_accumulator::{self, Accumulated, RefreshAccumulator};
+use routing_api::RoutingApi;
 use sodiumoxide::crypto::hash::sha512;
+use state_store::StateStore;
+use status::Status;
 use std::collections::HashMap;
-use time::Duration;
-use types::{Refresh, RefreshValue};
+use std::sync::Arc;
+use time::{Duration, SteadyTime};
+use types::{Action, Refresh, RefreshValue};
 use utils;
-use vault::RoutingNode;
 use xor_name::XorName;
 
 const DEFAULT_ACCOUNT_SIZE: u64 = 1_073_741_824;  // 1 GB
 const DEFAULT_PAYMENT: u64 = 1_048_576;  // 1 MB
+const STATE_NAME: &'static str = "maid_manager_accounts";
+// How long a client's Put waits for a response from the NaeManagers before it's failed.
+const REQUEST_TIMEOUT_SECS: i64 = 300;
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
 pub struct Account {
@@ -68,98 +78,118 @@ impl Account {
 
 
 
+struct CachedRequest {
+    request: RequestMessage,
+    expiry: SteadyTime,
+}
+
 pub struct MaidManager {
     accounts: HashMap<XorName, Account>,
-    request_cache: LruCache<MessageId, RequestMessage>,
+    request_cache: HashMap<MessageId, CachedRequest>,
+    refresh_accumulator: RefreshAccumulator<Account>,
+    clock: Arc<Clock>,
 }
 
 impl MaidManager {
-    pub fn new() -> MaidManager {
+    pub fn new(config: &Config, clock: Arc<Clock>) -> MaidManager {
         MaidManager {
             accounts: HashMap::new(),
-            request_cache: LruCache::with_expiry_duration_and_capacity(Duration::minutes(5), 1000),
+            request_cache: HashMap::new(),
+            refresh_accumulator: RefreshAccumulator::new(config.refresh_quorum(), clock.clone()),
+            clock: clock,
         }
     }
 
-    pub fn handle_put(&mut self,
-                      routing_node: &RoutingNode,
-                      request: &RequestMessage)
-                      -> Result<(), InternalError> {
+    pub fn handle_put(&mut self, request: &RequestMessage) -> Result<Vec<Action>, InternalError> {
         match request.content {
-            RequestContent::Put(Data::Immutable(_), _) => {
-                self.handle_put_immutable_data(routing_node, request)
-            }
-            RequestContent::Put(Data::Structured(_), _) => {
-                self.handle_put_structured_data(routing_node, request)
-            }
+            RequestContent::Put(Data::Immutable(_), _) => self.handle_put_immutable_data(request),
+            RequestContent::Put(Data::Structured(_), _) => self.handle_put_structured_data(request),
             _ => unreachable!("Error in vault demuxing"),
         }
     }
 
     pub fn handle_put_success(&mut self,
-                              routing_node: &RoutingNode,
                               message_id: &MessageId)
-                              -> Result<(), InternalError> {
+                              -> Result<Vec<Action>, InternalError> {
         match self.request_cache.remove(message_id) {
-            Some(client_request) => {
+            Some(CachedRequest { request: client_request, .. }) => {
                 // Send success response back to client
                 let message_hash =
                     sha512::hash(&try!(serialisation::serialise(&client_request))[..]);
                 let src = client_request.dst;
                 let dst = client_request.src;
-                let _ = routing_node.send_put_success(src, dst, message_hash, message_id.clone());
-                Ok(())
+                let content = ResponseContent::PutSuccess(message_hash, message_id.clone());
+                Ok(vec![Action::response(src, dst, content)])
             }
             None => Err(InternalError::FailedToFindCachedRequest(message_id.clone())),
         }
     }
 
     pub fn handle_put_failure(&mut self,
-                              routing_node: &RoutingNode,
                               message_id: &MessageId,
                               external_error_indicator: &Vec<u8>)
-                              -> Result<(), InternalError> {
+                              -> Result<Vec<Action>, InternalError> {
         match self.request_cache.remove(message_id) {
-            Some(client_request) => {
-                // Refund account
-                match self.accounts.get_mut(client_request.dst.name()) {
-                    Some(account) => {
-                        account.delete_data(DEFAULT_PAYMENT /* data.payload_size() as u64 */)
-                    }
-                    None => return Ok(()),
-                }
-
-                // Send failure response back to client
+            Some(CachedRequest { request: client_request, .. }) => {
                 let error =
                     try!(serialisation::deserialise::<ClientError>(external_error_indicator));
-                self.reply_with_put_failure(routing_node,
-                                            client_request,
-                                            message_id.clone(),
-                                            &error)
+                self.refund(client_request, message_id.clone(), &error)
             }
             None => Err(InternalError::FailedToFindCachedRequest(message_id.clone())),
         }
     }
 
-    pub fn handle_refresh(&mut self, name: XorName, account: Account) {
-        let _ = self.accounts.insert(name, account);
+    /// Fails any Puts which have been waiting on the NaeManagers for too long.
+    pub fn fail_expired_requests(&mut self) -> Result<Vec<Action>, InternalError> {
+        let now = self.clock.now();
+        let expired_ids = self.request_cache
+                              .iter()
+                              .filter(|&(_, cached_request)| cached_request.expiry <= now)
+                              .map(|(message_id, _)| message_id.clone())
+                              .collect::<Vec<_>>();
+        let mut actions = vec![];
+        for message_id in expired_ids {
+            if let Some(cached_request) = self.request_cache.remove(&message_id) {
+                debug!("MaidManager timed out waiting on Put {:?}", message_id);
+                actions.extend(try!(self.refund(cached_request.request,
+                                                message_id,
+                                                &ClientError::Timeout)));
+            }
+        }
+        Ok(actions)
     }
 
-    pub fn handle_churn(&mut self, routing_node: &RoutingNode) {
-        for (maid_name, account) in self.accounts.iter() {
-            let src = Authority::ClientManager(maid_name.clone());
-            let refresh = Refresh::new(maid_name, RefreshValue::MaidManagerAccount(account.clone()));
-            if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
-                debug!("MaidManager sending refresh for account {:?}", src.name());
-                let _ = routing_node.send_refresh_request(src, serialised_refresh);
+    /// Takes over the account once a quorum of the group has sent matching refreshes for it.
+    pub fn handle_refresh(&mut self,
+                          name: XorName,
+                          sender: XorName,
+                          account: Account)
+                          -> Vec<Action> {
+        match self.refresh_accumulator.add(name, sender, account) {
+            Accumulated::Agreed(account) => {
+                let _ = self.accounts.insert(name, account);
+                vec![]
+            }
+            Accumulated::Conflicted => {
+                vec![refresh_accumulator::sync_request(Authority::ClientManager(name), self.name())]
             }
+            Accumulated::Pending => vec![],
         }
     }
 
+    fn account_refresh(&self, maid_name: &XorName) -> Option<Action> {
+        self.accounts.get(maid_name).map(|account| {
+            let src = Authority::ClientManager(maid_name.clone());
+            let refresh = Refresh::new(maid_name,
+                                       RefreshValue::MaidManagerAccount(account.clone()));
+            debug!("MaidManager sending refresh for account {:?}", src.name());
+            Action::SendRefresh(src, refresh)
+        })
+    }
+
     fn handle_put_immutable_data(&mut self,
-                                 routing_node: &RoutingNode,
                                  request: &RequestMessage)
-                                 -> Result<(), InternalError> {
+                                 -> Result<Vec<Action>, InternalError> {
         // Take a hash of the message anticipating sending this as a success response to the client.
         let message_hash = sha512::hash(&try!(serialisation::serialise(request))[..]);
 
@@ -180,28 +210,26 @@ impl MaidManager {
                              account.put_data(DEFAULT_PAYMENT /* data.payload_size() as u64 */)
                          });
         if let Err(error) = result {
-            try!(self.reply_with_put_failure(routing_node, request.clone(), message_id, &error));
-            return Err(InternalError::Client(error));
+            return Ok(vec![try!(Self::put_failure(request.clone(), message_id, &error))]);
         }
 
-        {
-            // Send data on to NAE Manager
+        // Send data on to NAE Manager
+        let forward_action = {
             let src = request.dst.clone();
             let dst = Authority::NaeManager(data.name());
-            let _ = routing_node.send_put_request(src, dst, data.clone(), message_id.clone());
-        }
+            Action::request(src, dst, RequestContent::Put(data, message_id.clone()))
+        };
 
         // Send success response back to client but log client request in case SD Put fails at SDMs
         let src = request.dst.clone();
         let dst = request.src.clone();
-        let _ = routing_node.send_put_success(src, dst, message_hash, message_id);
-        Ok(())
+        let content = ResponseContent::PutSuccess(message_hash, message_id);
+        Ok(vec![forward_action, Action::response(src, dst, content)])
     }
 
     fn handle_put_structured_data(&mut self,
-                                  routing_node: &RoutingNode,
                                   request: &RequestMessage)
-                                  -> Result<(), InternalError> {
+                                  -> Result<Vec<Action>, InternalError> {
         let (data, type_tag, message_id) = match request.content {
             RequestContent::Put(Data::Structured(ref data), ref message_id) => {
                 (Data::Structured(data.clone()),
@@ -216,11 +244,7 @@ impl MaidManager {
         if type_tag == 0 {
             if self.accounts.contains_key(&client_name) {
                 let error = ClientError::AccountExists;
-                try!(self.reply_with_put_failure(routing_node,
-                                                 request.clone(),
-                                                 message_id,
-                                                 &error));
-                return Err(InternalError::Client(error));
+                return Ok(vec![try!(Self::put_failure(request.clone(), message_id, &error))]);
             }
 
             // Create the account
@@ -234,82 +258,225 @@ impl MaidManager {
                                  account.put_data(DEFAULT_PAYMENT /* data.payload_size() as u64 */)
                              });
             if let Err(error) = result {
-                try!(self.reply_with_put_failure(routing_node,
-                                                 request.clone(),
-                                                 message_id,
-                                                 &error));
-                return Err(InternalError::Client(error));
+                return Ok(vec![try!(Self::put_failure(request.clone(), message_id, &error))]);
             }
         };
 
-        {
-            // Send data on to NAE Manager
-            let src = request.dst.clone();
-            let dst = Authority::NaeManager(data.name());
-            let _ = routing_node.send_put_request(src, dst, data.clone(), message_id.clone());
+        // Send data on to NAE Manager
+        let src = request.dst.clone();
+        let dst = Authority::NaeManager(data.name());
+        let forward_action = Action::request(src,
+                                             dst,
+                                             RequestContent::Put(data, message_id.clone()));
+
+        let cached_request = CachedRequest {
+            request: request.clone(),
+            expiry: self.clock.now() + Duration::seconds(REQUEST_TIMEOUT_SECS),
+        };
+        if let Some(prior_request) = self.request_cache
+                                         .insert(message_id.clone(), cached_request) {
+            error!("Overwrote existing cached request: {:?}", prior_request.request);
         }
+        Ok(vec![forward_action])
+    }
 
-        if let Some(prior_request) = self.request_cache
-                                         .insert(message_id.clone(), request.clone()) {
-            error!("Overwrote existing cached request: {:?}", prior_request);
+    // Refunds the client's account for a Put which failed, and passes the failure on to the client.
+    fn refund(&mut self,
+              client_request: RequestMessage,
+              message_id: MessageId,
+              error: &ClientError)
+              -> Result<Vec<Action>, InternalError> {
+        match self.accounts.get_mut(client_request.dst.name()) {
+            Some(account) => account.delete_data(DEFAULT_PAYMENT /* data.payload_size() as u64 */),
+            None => return Ok(vec![]),
         }
-        Ok(())
+        Ok(vec![try!(Self::put_failure(client_request, message_id, error))])
     }
 
-    fn reply_with_put_failure(&self,
-                              routing_node: &RoutingNode,
-                              request: RequestMessage,
-                              message_id: MessageId,
-                              error: &ClientError)
-                              -> Result<(), InternalError> {
+    fn put_failure(request: RequestMessage,
+                   message_id: MessageId,
+                   error: &ClientError)
+                   -> Result<Action, InternalError> {
+        debug!("MaidManager rejecting Put: {:?}", error);
         let src = request.dst.clone();
         let dst = request.src.clone();
         let external_error_indicator = try!(serialisation::serialise(error));
-        let _ = routing_node.send_put_failure(src,
-                                              dst,
-                                              request,
-                                              external_error_indicator,
-                                              message_id);
+        let content = ResponseContent::PutFailure {
+            id: message_id,
+            request: request,
+            external_error_indicator: external_error_indicator,
+        };
+        Ok(Action::response(src, dst, content))
+    }
+}
+
+impl Persona for MaidManager {
+    fn name(&self) -> &'static str {
+        "maid_manager"
+    }
+
+    fn handle_request(&mut self,
+                      _routing_node: &RoutingApi,
+                      request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        match (&request.src, &request.dst, &request.content) {
+            (&Authority::Client{ .. },
+             &Authority::ClientManager(_),
+             &RequestContent::Put(Data::Immutable(_), _)) |
+            (&Authority::Client{ .. },
+             &Authority::ClientManager(_),
+             &RequestContent::Put(Data::Structured(_), _)) => {
+                Some(self.handle_put(request))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_response(&mut self,
+                       _routing_node: &RoutingApi,
+                       response: &ResponseMessage)
+                       -> Option<Result<Vec<Action>, InternalError>> {
+        match (&response.src, &response.dst, &response.content) {
+            (&Authority::NaeManager(_),
+             &Authority::ClientManager(_),
+             &ResponseContent::PutSuccess(_, ref message_id)) => {
+                Some(self.handle_put_success(message_id))
+            }
+            (&Authority::NaeManager(_),
+             &Authority::ClientManager(_),
+             &ResponseContent::PutFailure{ ref id, ref external_error_indicator, .. }) => {
+                Some(self.handle_put_failure(id, external_error_indicator))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_refresh_request(&mut self,
+                              _routing_node: &RoutingApi,
+                              src: &Authority,
+                              dst: &Authority,
+                              sender: &XorName,
+                              refresh: &Refresh)
+                              -> Option<Result<Vec<Action>, InternalError>> {
+        match (src, dst, &refresh.value) {
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &RefreshValue::MaidManagerAccount(ref account)) => {
+                Some(Ok(self.handle_refresh(refresh.name, sender.clone(), account.clone())))
+            }
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &RefreshValue::SyncRequest(ref persona)) if persona == self.name() => {
+                Some(Ok(self.account_refresh(&refresh.name).into_iter().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_churn(&mut self, _routing_node: &RoutingApi) -> Vec<Action> {
+        self.accounts.keys().filter_map(|maid_name| self.account_refresh(maid_name)).collect()
+    }
+
+    fn handle_churn_of(&mut self,
+                       routing_node: &RoutingApi,
+                       churned_node: &XorName)
+                       -> Vec<Action> {
+        self.accounts
+            .keys()
+            .filter(|maid_name| utils::is_affected_by_churn(routing_node, maid_name, churned_node))
+            .filter_map(|maid_name| self.account_refresh(maid_name))
+            .collect()
+    }
+
+    fn handle_tick(&mut self, _routing_node: &RoutingApi) -> Result<Vec<Action>, InternalError> {
+        self.refresh_accumulator.expire();
+        self.fail_expired_requests()
+    }
+
+    fn load_state(&mut self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        if let Some(accounts) = try!(state_store.load(STATE_NAME)) {
+            self.accounts = accounts;
+        }
         Ok(())
     }
+
+    fn save_state(&self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        state_store.save(STATE_NAME, &self.accounts)
+    }
+
+    fn update_status(&self, status: &mut Status) {
+        status.maid_manager_account_count = self.accounts.len();
+        status.maid_manager_cached_request_count = self.request_cache.len();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.request_cache.is_empty()
+    }
 }
 
 
-#[cfg(all(test, feature = "use-mock-routing"))]
+#[cfg(test)]
 mod test {
     use super::*;
-    use error::{ClientError, InternalError};
+    use clock::MockClock;
+    use config_handler::Config;
+    use error::ClientError;
     use maidsafe_utilities::serialisation;
+    use mock_routing::MockRoutingNode;
+    use personas::Persona;
     use rand::random;
+    use refresh_accumulator;
     use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, RequestContent,
-                  RequestMessage, ResponseContent};
+                  RequestMessage, ResponseContent, ResponseMessage, StructuredData};
+    use sodiumoxide::crypto::hash::sha512;
     use sodiumoxide::crypto::sign;
-    use std::sync::mpsc;
-    use utils::generate_random_vec_u8;
-    use vault::RoutingNode;
+    use std::sync::{Arc, mpsc};
+    use time::Duration;
+    use types::{Action, Refresh, RefreshValue};
+    use utils::{self, generate_random_vec_u8};
+    use worker::send_actions;
     use xor_name::XorName;
 
     struct Environment {
         our_authority: Authority,
         client: Authority,
-        routing: RoutingNode,
+        clock: Arc<MockClock>,
         maid_manager: MaidManager,
     }
 
     fn environment_setup() -> Environment {
         let from = random::<XorName>();
         let keys = sign::gen_keypair();
+        let clock = Arc::new(MockClock::new());
         Environment {
             our_authority: Authority::ClientManager(from.clone()),
             client: Authority::Client {
                 client_key: keys.0,
                 proxy_node_name: from.clone(),
             },
-            routing: unwrap_result!(RoutingNode::new(mpsc::channel().0)),
-            maid_manager: MaidManager::new(),
+            clock: clock.clone(),
+            maid_manager: MaidManager::new(&Config::default(), clock),
         }
     }
 
+    fn put_structured_data(env: &mut Environment, type_tag: u64) -> RequestMessage {
+        let data = unwrap_result!(StructuredData::new(type_tag,
+                                                      random::<XorName>(),
+                                                      0,
+                                                      vec![],
+                                                      vec![],
+                                                      vec![],
+                                                      None));
+        let request = RequestMessage {
+            src: env.client.clone(),
+            dst: Authority::ClientManager(utils::client_name(&env.client)),
+            content: RequestContent::Put(Data::Structured(data), MessageId::new()),
+        };
+        let actions = unwrap_result!(env.maid_manager.handle_put(&request));
+        assert_eq!(actions.len(), 1);
+        request
+    }
+
     #[test]
     fn handle_put_without_account() {
         let mut env = environment_setup();
@@ -324,17 +491,15 @@ mod test {
             content: RequestContent::Put(Data::Immutable(immutable_data), message_id.clone()),
         };
 
-        match env.maid_manager.handle_put(&env.routing, &valid_request) {
-            Err(InternalError::Client(ClientError::NoSuchAccount)) => (),
+        let actions = unwrap_result!(env.maid_manager.handle_put(&valid_request));
+        assert_eq!(actions.len(), 1);
+        let put_failure = match actions[0] {
+            Action::SendResponse(ref response) => response.clone(),
             _ => unreachable!(),
-        }
-        let put_requests = env.routing.put_requests_given();
-        assert!(put_requests.is_empty());
-        let put_failures = env.routing.put_failures_given();
-        assert_eq!(put_failures.len(), 1);
-        assert_eq!(put_failures[0].src, env.our_authority);
-        assert_eq!(put_failures[0].dst, env.client);
-        match &put_failures[0].content {
+        };
+        assert_eq!(put_failure.src, env.our_authority);
+        assert_eq!(put_failure.dst, env.client);
+        match &put_failure.content {
             &ResponseContent::PutFailure{ ref id, ref request, ref external_error_indicator } => {
                 assert_eq!(*id, message_id);
                 assert_eq!(*request, valid_request);
@@ -342,46 +507,177 @@ mod test {
                     ClientError::NoSuchAccount => (),
                     _ => unreachable!(),
                 }
-
             }
             _ => unreachable!(),
         }
+    }
+
+    #[test]
+    fn cached_put_times_out() {
+        let mut env = environment_setup();
+        let client_name = utils::client_name(&env.client);
+
+        // Create the account, then store some data against it.
+        let create_request = put_structured_data(&mut env, 0);
+        let put_request = put_structured_data(&mut env, 1);
+        assert_eq!(env.maid_manager.accounts[&client_name].data_stored, DEFAULT_PAYMENT);
+        assert!(!env.maid_manager.is_idle());
+
+        // Nothing happens until the NaeManagers have had their chance to respond.
+        env.clock.advance(Duration::seconds(REQUEST_TIMEOUT_SECS - 1));
+        assert!(unwrap_result!(env.maid_manager.fail_expired_requests()).is_empty());
+
+        // Then both Puts fail, and the account is refunded.
+        env.clock.advance(Duration::seconds(2));
+        let actions = unwrap_result!(env.maid_manager.fail_expired_requests());
+        assert_eq!(actions.len(), 2);
+        for action in &actions {
+            let response = match *action {
+                Action::SendResponse(ref response) => response,
+                _ => panic!("Unexpected action {:?}", action),
+            };
+            match response.content {
+                ResponseContent::PutFailure{ ref request, ref external_error_indicator, .. } => {
+                    assert!(*request == create_request || *request == put_request);
+                    match unwrap_result!(serialisation::deserialise::<ClientError>(
+                            external_error_indicator)) {
+                        ClientError::Timeout => (),
+                        error => panic!("Unexpected error {:?}", error),
+                    }
+                }
+                _ => panic!("Unexpected response {:?}", response),
+            }
+        }
+        assert_eq!(env.maid_manager.accounts[&client_name], Account::default());
+        assert!(env.maid_manager.is_idle());
+    }
+
+    #[test]
+    fn refresh_needs_quorum() {
+        let mut env = environment_setup();
+        let maid_name = random::<XorName>();
+        let account = Account {
+            data_stored: DEFAULT_PAYMENT,
+            ..Account::default()
+        };
+
+        // A differing refresh from one member makes us ask the group to send theirs again
+        let actions = env.maid_manager.handle_refresh(maid_name, random(), account.clone());
+        assert!(actions.is_empty());
+        let actions = env.maid_manager.handle_refresh(maid_name, random(), Account::default());
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Action::SendRefresh(Authority::ClientManager(ref name),
+                                Refresh { value: RefreshValue::SyncRequest(ref persona), .. }) => {
+                assert_eq!(*name, maid_name);
+                assert_eq!(persona, "maid_manager");
+            }
+            ref action => panic!("Unexpected action {:?}", action),
+        }
+
+        // The account is only taken over once a quorum agrees on it
+        for _ in 1..refresh_accumulator::DEFAULT_QUORUM {
+            assert!(!env.maid_manager.accounts.contains_key(&maid_name));
+            let actions = env.maid_manager.handle_refresh(maid_name, random(), account.clone());
+            assert!(actions.is_empty());
+        }
+        assert_eq!(env.maid_manager.accounts.get(&maid_name), Some(&account));
+    }
 
-        // assert_eq!(::utils::HANDLED,
-        //            maid_manager.handle_put(&our_authority,
-        //                                    &client,
-        //                                    &::routing::data::Data::Immutable(data.clone()),
-        //                                    &None));
-        // let put_requests = routing.put_requests_given();
-        // assert_eq!(put_requests.len(), 1);
-        // assert_eq!(put_requests[0].our_authority, our_authority);
-        // assert_eq!(put_requests[0].location, Authority::NaeManager(data.name()));
-        // assert_eq!(put_requests[0].data, Data::Immutable(data));
-    }
-
-    // #[test]
-    // fn handle_churn_and_account_transfer() {
-    //     let churn_node = random();
-    //     let (our_authority, routing, mut maid_manager, client, data) = env_setup();
-    //     assert_eq!(::utils::HANDLED,
-    //                maid_manager.handle_put(&our_authority,
-    //                                        &client,
-    //                                        &::routing::data::Data::Immutable(data.clone()),
-    //                                        &None));
-    //     maid_manager.handle_churn(&churn_node);
-    //     let refresh_requests = routing.refresh_requests_given();
-    //     assert_eq!(refresh_requests.len(), 1);
-    //     assert_eq!(refresh_requests[0].type_tag, ACCOUNT_TAG);
-    //     assert_eq!(refresh_requests[0].our_authority.name(),
-    //                client.name());
-
-    //     let mut d = ::cbor::Decoder::from_bytes(&refresh_requests[0].content[..]);
-    //     if let Some(mm_account) = d.decode().next().and_then(|result| result.ok()) {
-    //         maid_manager.database.handle_account_transfer(mm_account);
-    //     }
-    //     maid_manager.handle_churn(&churn_node);
-    //     let refresh_requests = routing.refresh_requests_given();
-    //     assert_eq!(refresh_requests.len(), 2);
-    //     assert_eq!(refresh_requests[0], refresh_requests[1]);
-    // }
+    #[test]
+    fn only_handle_messages_for_client_managers() {
+        let mut env = environment_setup();
+        let routing = unwrap_result!(MockRoutingNode::new(mpsc::channel().0));
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(1024));
+        let content = RequestContent::Put(Data::Immutable(data.clone()), MessageId::new());
+
+        // A client's Put is ours, but the same Put sent on to the NaeManagers isn't.
+        let request = RequestMessage {
+            src: env.client.clone(),
+            dst: env.our_authority.clone(),
+            content: content.clone(),
+        };
+        assert!(env.maid_manager.handle_request(&routing, &request).is_some());
+        let request = RequestMessage {
+            src: env.our_authority.clone(),
+            dst: Authority::NaeManager(data.name()),
+            content: content,
+        };
+        assert!(env.maid_manager.handle_request(&routing, &request).is_none());
+
+        // Neither are responses which aren't on their way back to a client's account.
+        let response = ResponseMessage {
+            src: Authority::NaeManager(data.name()),
+            dst: Authority::ManagedNode(random()),
+            content: ResponseContent::PutSuccess(sha512::hash(&[]), MessageId::new()),
+        };
+        assert!(env.maid_manager.handle_response(&routing, &response).is_none());
+
+        // Or other personas' sync requests.
+        let refresh = Refresh::new(&random(), RefreshValue::SyncRequest("pmid_manager".to_owned()));
+        assert!(env.maid_manager
+                   .handle_refresh_request(&routing,
+                                           &env.our_authority,
+                                           &env.our_authority,
+                                           &random(),
+                                           &refresh)
+                   .is_none());
+    }
+
+    #[test]
+    fn handlers_return_actions_without_sending_them() {
+        let mut env = environment_setup();
+        let routing = unwrap_result!(MockRoutingNode::new(mpsc::channel().0));
+        let data = unwrap_result!(StructuredData::new(0,
+                                                      random::<XorName>(),
+                                                      0,
+                                                      vec![],
+                                                      vec![],
+                                                      vec![],
+                                                      None));
+        let request = RequestMessage {
+            src: env.client.clone(),
+            dst: Authority::ClientManager(utils::client_name(&env.client)),
+            content: RequestContent::Put(Data::Structured(data.clone()), MessageId::new()),
+        };
+
+        let actions = unwrap_result!(unwrap_option!(env.maid_manager
+                                                       .handle_request(&routing, &request),
+                                                    "Put should be handled"));
+        assert!(routing.put_requests_given().is_empty());
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Action::SendRequest(ref forwarded) => {
+                assert_eq!(forwarded.dst, Authority::NaeManager(data.name()));
+            }
+            ref action => panic!("Unexpected action {:?}", action),
+        }
+
+        // It's up to the caller to carry them out.
+        send_actions(&routing, actions);
+        assert_eq!(routing.put_requests_given().len(), 1);
+    }
+
+    #[test]
+    fn churn_refreshes_affected_accounts() {
+        let mut env = environment_setup();
+        let churn = utils::churn_environment();
+        for maid_name in churn.near_names.iter().chain(Some(&churn.far_name)) {
+            let _ = env.maid_manager.accounts.insert(*maid_name, Account::default());
+        }
+
+        // Churn within our close group refreshes the accounts we share with it
+        send_actions(&churn.routing,
+                     env.maid_manager.handle_node_added(&churn.routing, churn.member));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 3);
+
+        // Churn elsewhere refreshes nothing
+        send_actions(&churn.routing,
+                     env.maid_manager.handle_node_lost(&churn.routing, churn.far_name));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 3);
+
+        // Handing off every account still refreshes them all
+        send_actions(&churn.routing, env.maid_manager.handle_churn(&churn.routing));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 7);
+    }
 }
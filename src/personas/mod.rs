@@ -21,3 +21,120 @@ pub mod mpid_manager;
 pub mod pmid_manager;
 pub mod pmid_node;
 pub mod structured_data_manager;
+
+use config_handler::Config;
+use error::InternalError;
+use routing::{Authority, RequestMessage, ResponseMessage};
+use routing_api::RoutingApi;
+use state_store::StateStore;
+use status::Status;
+use types::{Action, Refresh};
+use xor_name::XorName;
+
+/// Common interface through which the vault drives each of its personas.
+///
+/// The message hooks return `None` if the message isn't addressed to this persona, in which case
+/// it is left to the other personas.  Personas don't send anything to the network themselves;
+/// instead their hooks return the `Action`s for the vault to carry out.
+///
+/// Each persona runs on its own worker thread, hence the `Send` bound.
+pub trait Persona: Send {
+    /// Name used to identify this persona in logs and metrics.
+    fn name(&self) -> &'static str;
+
+    /// Handles a request if it is addressed to this persona.
+    fn handle_request(&mut self,
+                      _routing_node: &RoutingApi,
+                      _request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        None
+    }
+
+    /// Handles a response if it is addressed to this persona.
+    fn handle_response(&mut self,
+                       _routing_node: &RoutingApi,
+                       _response: &ResponseMessage)
+                       -> Option<Result<Vec<Action>, InternalError>> {
+        None
+    }
+
+    /// Handles a refresh received from `sender`, another member of the group, if it is addressed
+    /// to this persona.
+    fn handle_refresh_request(&mut self,
+                              _routing_node: &RoutingApi,
+                              _src: &Authority,
+                              _dst: &Authority,
+                              _sender: &XorName,
+                              _refresh: &Refresh)
+                              -> Option<Result<Vec<Action>, InternalError>> {
+        None
+    }
+
+    /// Returns a refresh for every account held, so the group can take them over.
+    fn handle_churn(&mut self, _routing_node: &RoutingApi) -> Vec<Action> {
+        vec![]
+    }
+
+    /// Returns a refresh for each account whose close group `churned_node` has just joined or
+    /// left.  Defaults to refreshing every account.
+    fn handle_churn_of(&mut self,
+                       routing_node: &RoutingApi,
+                       _churned_node: &XorName)
+                       -> Vec<Action> {
+        self.handle_churn(routing_node)
+    }
+
+    /// Called once the routing node has joined the network.
+    fn handle_connected(&mut self,
+                        _routing_node: &RoutingApi)
+                        -> Result<Vec<Action>, InternalError> {
+        Ok(vec![])
+    }
+
+    /// Called when the running vault is given a new config, e.g. via its `ConfigHandle`.
+    fn handle_config_changed(&mut self,
+                             _routing_node: &RoutingApi,
+                             _config: &Config)
+                             -> Result<Vec<Action>, InternalError> {
+        Ok(vec![])
+    }
+
+    /// Called periodically by the vault, so the persona can act on timeouts and run maintenance.
+    fn handle_tick(&mut self, _routing_node: &RoutingApi) -> Result<Vec<Action>, InternalError> {
+        Ok(vec![])
+    }
+
+    /// Called when a node joins our routing table.
+    fn handle_node_added(&mut self,
+                         routing_node: &RoutingApi,
+                         node_added: XorName)
+                         -> Vec<Action> {
+        self.handle_churn_of(routing_node, &node_added)
+    }
+
+    /// Called when a node leaves our routing table.
+    fn handle_node_lost(&mut self,
+                        routing_node: &RoutingApi,
+                        node_lost: XorName)
+                        -> Vec<Action> {
+        self.handle_churn_of(routing_node, &node_lost)
+    }
+
+    /// Restores any state previously saved to `state_store`.
+    fn load_state(&mut self, _state_store: &mut StateStore) -> Result<(), InternalError> {
+        Ok(())
+    }
+
+    /// Saves any state which should survive a restart to `state_store`.
+    fn save_state(&self, _state_store: &mut StateStore) -> Result<(), InternalError> {
+        Ok(())
+    }
+
+    /// Fills in this persona's fields of the status snapshot.
+    fn update_status(&self, _status: &mut Status) {}
+
+    /// Returns false while this persona is waiting on responses from the network.
+    fn is_idle(&self) -> bool {
+        true
+    }
+}
@@ -16,20 +16,30 @@
 // relating to use of the SAFE Network Software.
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use chunk_store::ChunkStore;
+use clock::Clock;
+use config_handler::Config;
 use default_chunk_store;
 use error::{ClientError, InternalError};
 use maidsafe_utilities::serialisation::{deserialise, serialise};
 use mpid_messaging::{MAX_INBOX_SIZE, MAX_OUTBOX_SIZE, MpidHeader, MpidMessage, MpidMessageWrapper};
-use routing::{Authority, Data, PlainData, RequestContent, RequestMessage};
+use personas::Persona;
+use routing::{Authority, Data, PlainData, RequestContent, RequestMessage, ResponseContent,
+              ResponseMessage};
+use refresh_accumulator::{self, Accumulated, RefreshAccumulator};
+use routing_api::RoutingApi;
 use sodiumoxide::crypto::sign::PublicKey;
 use sodiumoxide::crypto::hash::sha512;
-use types::{Refresh, RefreshValue};
+use state_store::StateStore;
+use status::{ChunkStoreStatus, Status};
+use types::{Action, Refresh, RefreshValue};
 use utils;
-use vault::RoutingNode;
 use xor_name::XorName;
 
+const STATE_NAME: &'static str = "mpid_manager_accounts";
+
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
 struct MailBox {
     allowance: u64,
@@ -76,6 +86,10 @@ impl MailBox {
         }
     }
 
+    fn len(&self) -> usize {
+        self.mail_box.len()
+    }
+
     fn contains_key(&self, entry: &XorName) -> bool {
         self.mail_box.contains_key(entry)
     }
@@ -160,28 +174,39 @@ impl Account {
     }
 }
 
+// An account along with the stored messages and received headers it refers to
+type AccountRefresh = (Account, Vec<PlainData>, Vec<PlainData>);
+
 pub struct MpidManager {
     accounts: HashMap<XorName, Account>,
     chunk_store_inbox: ChunkStore,
     chunk_store_outbox: ChunkStore,
+    refresh_accumulator: RefreshAccumulator<AccountRefresh>,
 }
 
 impl MpidManager {
-    pub fn new() -> MpidManager {
-        MpidManager {
+    pub fn new(config: &Config, clock: Arc<Clock>) -> Result<MpidManager, InternalError> {
+        let data_dir = config.data_dir();
+        let root = data_dir.as_ref().map(|dir| dir.as_path());
+        let max_space = config.mpid_manager_max_space();
+        Ok(MpidManager {
             accounts: HashMap::new(),
-            chunk_store_inbox: default_chunk_store::new().unwrap(),
-            chunk_store_outbox: default_chunk_store::new().unwrap(),
-        }
+            chunk_store_inbox: try!(default_chunk_store::new(root,
+                                                             "mpid_manager_inbox",
+                                                             max_space)),
+            chunk_store_outbox: try!(default_chunk_store::new(root,
+                                                              "mpid_manager_outbox",
+                                                              max_space)),
+            refresh_accumulator: RefreshAccumulator::new(config.refresh_quorum(), clock),
+        })
     }
 
     // The name of the PlainData is expected to be the mpidheader or mpidmessage name
     // The content of the PlainData is execpted to be the serialised MpidMessageWrapper
     // holding mpidheader or mpidmessage
     pub fn handle_put(&mut self,
-                      routing_node: &RoutingNode,
                       request: &RequestMessage)
-                      -> Result<(), InternalError> {
+                      -> Result<Vec<Action>, InternalError> {
         let (data, message_id) = match request.content {
             RequestContent::Put(Data::Plain(ref data), ref message_id) => {
                 (data.clone(), message_id.clone())
@@ -189,6 +214,7 @@ impl MpidManager {
             _ => unreachable!("Error in vault demuxing"),
         };
         let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
+        let mut actions = vec![];
         match mpid_message_wrapper {
             MpidMessageWrapper::PutHeader(mpid_header) => {
                 if self.chunk_store_inbox.has_chunk(&data.name()) {
@@ -206,18 +232,19 @@ impl MpidManager {
                         let value = try!(serialise(&wrapper));
                         let name = try!(mpid_header.name());
                         let data = Data::Plain(PlainData::new(name, value));
-                        try!(routing_node.send_post_request(request.dst.clone(),
-                                                            dst,
-                                                            data,
-                                                            message_id.clone()));
-                        return Ok(());
+                        let content = RequestContent::Post(data, message_id.clone());
+                        actions.push(Action::request(request.dst.clone(), dst, content));
+                        return Ok(actions);
                     } else {
-                        try!(routing_node.send_put_failure(request.dst.clone(),
-                                                           request.src.clone(),
-                                                           request.clone(),
-                                                           Vec::new(),
-                                                           message_id));
-                        return Ok(());
+                        let content = ResponseContent::PutFailure {
+                            id: message_id,
+                            request: request.clone(),
+                            external_error_indicator: Vec::new(),
+                        };
+                        actions.push(Action::response(request.dst.clone(),
+                                                      request.src.clone(),
+                                                      content));
+                        return Ok(actions);
                     }
                 }
 
@@ -227,11 +254,14 @@ impl MpidManager {
                        .put_into_inbox(serialised_header.len() as u64, &data.name(), &None) {
                     try!(self.chunk_store_inbox.put(&data.name(), &serialised_header[..]));
                 } else {
-                    try!(routing_node.send_put_failure(request.dst.clone(),
-                                                       request.src.clone(),
-                                                       request.clone(),
-                                                       Vec::new(),
-                                                       message_id));
+                    let content = ResponseContent::PutFailure {
+                        id: message_id,
+                        request: request.clone(),
+                        external_error_indicator: Vec::new(),
+                    };
+                    actions.push(Action::response(request.dst.clone(),
+                                                  request.src.clone(),
+                                                  content));
                 }
             }
             MpidMessageWrapper::PutMessage(mpid_message) => {
@@ -244,12 +274,15 @@ impl MpidManager {
                         if !account.put_into_outbox(serialised_message.len() as u64,
                                                     &data.name(),
                                                     &Some(client_key)) {
-                            try!(routing_node.send_put_failure(request.dst.clone(),
-                                                               request.src.clone(),
-                                                               request.clone(),
-                                                               Vec::new(),
-                                                               message_id));
-                            return Ok(());
+                            let content = ResponseContent::PutFailure {
+                                id: message_id,
+                                request: request.clone(),
+                                external_error_indicator: Vec::new(),
+                            };
+                            actions.push(Action::response(request.dst.clone(),
+                                                          request.src.clone(),
+                                                          content));
+                            return Ok(actions);
                         }
                     };
                     try!(self.chunk_store_outbox.put(&data.name(), &serialised_message[..]));
@@ -260,25 +293,30 @@ impl MpidManager {
                     let serialised_wrapper = try!(serialise(&wrapper));
                     let name = try!(mpid_message.header().name());
                     let notification = Data::Plain(PlainData::new(name, serialised_wrapper));
-                    try!(routing_node.send_put_request(src, dst, notification, message_id.clone()));
+                    let content = RequestContent::Put(notification, message_id.clone());
+                    actions.push(Action::request(src, dst, content));
                     // Send put success to Client.
                     let src = request.dst.clone();
                     let dst = request.src.clone();
                     let digest = sha512::hash(&try!(serialise(request))[..]);
-                    let _ = routing_node.send_put_success(src, dst, digest, message_id);
+                    let content = ResponseContent::PutSuccess(digest, message_id);
+                    actions.push(Action::response(src, dst, content));
                 } else {
                     // Client not registered online.
-                    try!(routing_node.send_put_failure(request.dst.clone(),
-                                                       request.src.clone(),
-                                                       request.clone(),
-                                                       Vec::new(),
-                                                       message_id));
+                    let content = ResponseContent::PutFailure {
+                        id: message_id,
+                        request: request.clone(),
+                        external_error_indicator: Vec::new(),
+                    };
+                    actions.push(Action::response(request.dst.clone(),
+                                                  request.src.clone(),
+                                                  content));
                 }
             }
             _ => unreachable!("Error in vault demuxing"),
         }
 
-        Ok(())
+        Ok(actions)
     }
 
     // PutFailure only happens from receiver's MpidManager to sender's MpidManager to
@@ -286,9 +324,8 @@ impl MpidManager {
     // The request in the put_failure response is the original request from sender's MpidManager
     // to receiver's MpidManager, i.e. MpidMessageWrapper::PutHeader(mpid_header)
     pub fn handle_put_failure(&mut self,
-                              routing_node: &RoutingNode,
                               request: &RequestMessage)
-                              -> Result<(), InternalError> {
+                              -> Result<Vec<Action>, InternalError> {
         let (data, message_id) = match request.content {
             RequestContent::Put(Data::Plain(ref data), ref message_id) => {
                 (data.clone(), message_id.clone())
@@ -296,6 +333,7 @@ impl MpidManager {
             _ => unreachable!("Error in vault demuxing"),
         };
         let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
+        let mut actions = vec![];
         match mpid_message_wrapper {
             MpidMessageWrapper::PutHeader(mpid_header) => {
                 if mpid_header.sender() == request.src.name() {
@@ -304,11 +342,14 @@ impl MpidManager {
                         if account.has_in_outbox(&ori_msg_name) {
                             let clients = account.registered_clients();
                             for client in clients.iter() {
-                                let _ = routing_node.send_put_failure(request.src.clone(),
-                                                                      client.clone(),
-                                                                      request.clone(),
-                                                                      Vec::new(),
-                                                                      message_id.clone());
+                                let content = ResponseContent::PutFailure {
+                                    id: message_id.clone(),
+                                    request: request.clone(),
+                                    external_error_indicator: Vec::new(),
+                                };
+                                actions.push(Action::response(request.src.clone(),
+                                                              client.clone(),
+                                                              content));
                             }
                         }
                     }
@@ -316,13 +357,12 @@ impl MpidManager {
             }
             _ => unreachable!("Error in vault demuxing"),
         }
-        Ok(())
+        Ok(actions)
     }
 
     pub fn handle_post(&mut self,
-                       routing_node: &RoutingNode,
                        request: &RequestMessage)
-                       -> Result<(), InternalError> {
+                       -> Result<Vec<Action>, InternalError> {
         let (data, message_id) = match request.content {
             RequestContent::Post(Data::Plain(ref data), ref message_id) => {
                 (data.clone(), message_id.clone())
@@ -330,6 +370,7 @@ impl MpidManager {
             _ => unreachable!("Error in vault demuxing"),
         };
         let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
+        let mut actions = vec![];
         match mpid_message_wrapper {
             MpidMessageWrapper::Online => {
                 let account = self.accounts
@@ -340,7 +381,8 @@ impl MpidManager {
                 let src = request.dst.clone();
                 let dst = request.src.clone();
                 let digest = sha512::hash(&try!(serialise(request))[..]);
-                let _ = routing_node.send_post_success(src, dst, digest, message_id.clone());
+                let content = ResponseContent::PostSuccess(digest, message_id.clone());
+                actions.push(Action::response(src, dst, content));
                 // For each received header in the inbox, fetch the full message from the sender
                 let received_headers = account.received_headers();
                 for header in received_headers.iter() {
@@ -360,10 +402,8 @@ impl MpidManager {
                             };
                             let name = try!(mpid_header.name());
                             let data = Data::Plain(PlainData::new(name, serialised_request));
-                            let _ = routing_node.send_post_request(request.dst.clone(),
-                                                                   target,
-                                                                   data,
-                                                                   message_id.clone());
+                            let content = RequestContent::Post(data, message_id.clone());
+                            actions.push(Action::request(request.dst.clone(), target, content));
                         }
                         Err(_) => {}
                     }
@@ -381,18 +421,21 @@ impl MpidManager {
                             let serialised_wrapper = try!(serialise(&wrapper));
                             let data = Data::Plain(PlainData::new(message_name,
                                                                       serialised_wrapper));
-                            try!(routing_node.send_post_request(request.dst.clone(),
-                                                                request.src.clone(),
-                                                                data,
-                                                                message_id.clone()));
+                            let content = RequestContent::Post(data, message_id.clone());
+                            actions.push(Action::request(request.dst.clone(),
+                                                         request.src.clone(),
+                                                         content));
                         }
                     }
                     _ => {
-                        try!(routing_node.send_post_failure(request.dst.clone(),
-                                                            request.src.clone(),
-                                                            request.clone(),
-                                                            Vec::new(),
-                                                            message_id))
+                        let content = ResponseContent::PostFailure {
+                            id: message_id,
+                            request: request.clone(),
+                            external_error_indicator: Vec::new(),
+                        };
+                        actions.push(Action::response(request.dst.clone(),
+                                                      request.src.clone(),
+                                                      content));
                     }
                 }
             }
@@ -402,11 +445,11 @@ impl MpidManager {
                         if mpid_message.recipient() == request.dst.name() {
                             let clients = receiver.registered_clients();
                             for client in clients.iter() {
-                                let _ =
-                                    routing_node.send_post_request(request.dst.clone(),
-                                                                   client.clone(),
-                                                                   Data::Plain(data.clone()),
+                                let content = RequestContent::Post(Data::Plain(data.clone()),
                                                                    message_id.clone());
+                                actions.push(Action::request(request.dst.clone(),
+                                                             client.clone(),
+                                                             content));
                             }
                         }
                     }
@@ -439,7 +482,8 @@ impl MpidManager {
                         let serialised_wrapper = try!(serialise(&wrapper));
                         let data = Data::Plain(PlainData::new(request.dst.name().clone(),
                                                               serialised_wrapper));
-                        try!(routing_node.send_post_request(src, dst, data, message_id.clone()));
+                        let content = RequestContent::Post(data, message_id.clone());
+                        actions.push(Action::request(src, dst, content));
                     }
                 }
             }
@@ -462,20 +506,20 @@ impl MpidManager {
                         let wrapper = MpidMessageWrapper::GetOutboxHeadersResponse(mpid_headers);
                         let serialised_wrapper = try!(serialise(&wrapper));
                         let data = Data::Plain(PlainData::new(request.dst.name().clone(), serialised_wrapper));
-                        try!(routing_node.send_post_request(src, dst, data, message_id.clone()));
+                        let content = RequestContent::Post(data, message_id.clone());
+                        actions.push(Action::request(src, dst, content));
                     }
                 }
             }
             _ => unreachable!("Error in vault demuxing"),
         }
 
-        Ok(())
+        Ok(actions)
     }
 
     pub fn handle_delete(&mut self,
-                         routing_node: &RoutingNode,
                          request: &RequestMessage)
-                         -> Result<(), InternalError> {
+                         -> Result<Vec<Action>, InternalError> {
         let (data, message_id) = match request.content {
             RequestContent::Delete(Data::Plain(ref data), ref message_id) => {
                 (data.clone(), message_id.clone())
@@ -483,6 +527,7 @@ impl MpidManager {
             _ => unreachable!("Error in vault demuxing"),
         };
         let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
+        let mut actions = vec![];
         match mpid_message_wrapper {
             MpidMessageWrapper::DeleteMessage(message_name) => {
                 if let Some(ref mut account) = self.accounts.get_mut(&request.dst.name().clone()) {
@@ -498,7 +543,7 @@ impl MpidManager {
                         if !registered {
                             let mpid_message: MpidMessage = try!(deserialise(&data));
                             if *mpid_message.recipient() != utils::client_name(&request.src) {
-                                return Ok(()); // !
+                                return Ok(actions); // !
                             }
                         }
 
@@ -509,11 +554,14 @@ impl MpidManager {
                         }
                     } else {
                         error!("Failed to get from chunk store.");
-                        try!(routing_node.send_delete_failure(request.dst.clone(),
-                                                              request.src.clone(),
-                                                              request.clone(),
-                                                              Vec::new(),
-                                                              message_id))
+                        let content = ResponseContent::DeleteFailure {
+                            id: message_id,
+                            request: request.clone(),
+                            external_error_indicator: Vec::new(),
+                        };
+                        actions.push(Action::response(request.dst.clone(),
+                                                      request.src.clone(),
+                                                      content));
                     }
                 }
             }
@@ -530,11 +578,14 @@ impl MpidManager {
                             }
                         } else {
                             error!("Failed to get from chunk store.");
-                            try!(routing_node.send_delete_failure(request.dst.clone(),
-                                                                  request.src.clone(),
-                                                                  request.clone(),
-                                                                  Vec::new(),
-                                                                  message_id))
+                            let content = ResponseContent::DeleteFailure {
+                                id: message_id,
+                                request: request.clone(),
+                                external_error_indicator: Vec::new(),
+                            };
+                            actions.push(Action::response(request.dst.clone(),
+                                                          request.src.clone(),
+                                                          content));
                         }
                     }
                 }
@@ -542,21 +593,35 @@ impl MpidManager {
             _ => unreachable!("Error in vault demuxing"),
         }
 
-        Ok(())
+        Ok(actions)
     }
 
+    /// Takes over the account and its messages once a quorum of the group has sent matching
+    /// refreshes for them.
     pub fn handle_refresh(&mut self,
                           name: XorName,
+                          sender: XorName,
                           account: &Account,
                           stored_messages: &Vec<PlainData>,
-                          received_headers: &Vec<PlainData>) {
-        let _ = self.accounts.insert(name.clone(), account.clone());
-        Self::insert_chunks(&mut self.chunk_store_outbox, stored_messages);
-        Self::insert_chunks(&mut self.chunk_store_inbox, received_headers);
+                          received_headers: &Vec<PlainData>)
+                          -> Vec<Action> {
+        let value = (account.clone(), stored_messages.clone(), received_headers.clone());
+        match self.refresh_accumulator.add(name, sender, value) {
+            Accumulated::Agreed((account, stored_messages, received_headers)) => {
+                let _ = self.accounts.insert(name, account);
+                Self::insert_chunks(&mut self.chunk_store_outbox, &stored_messages);
+                Self::insert_chunks(&mut self.chunk_store_inbox, &received_headers);
+                vec![]
+            }
+            Accumulated::Conflicted => {
+                vec![refresh_accumulator::sync_request(Authority::ClientManager(name), self.name())]
+            }
+            Accumulated::Pending => vec![],
+        }
     }
 
-    pub fn handle_churn(&mut self, routing_node: &RoutingNode) {
-        for (mpid_name, account) in self.accounts.iter() {
+    fn account_refresh(&self, mpid_name: &XorName) -> Option<Action> {
+        self.accounts.get(mpid_name).map(|account| {
             let received_headers = Self::fetch_chunks(&self.chunk_store_inbox,
                                                       &account.received_headers());
             let stored_messages = Self::fetch_chunks(&self.chunk_store_outbox,
@@ -567,11 +632,9 @@ impl MpidManager {
                                        RefreshValue::MpidManagerAccount(account.clone(),
                                                                         stored_messages,
                                                                         received_headers));
-            if let Ok(serialised_refresh) = serialise(&refresh) {
-                debug!("MpidManager sending refresh for account {:?}", src.name());
-                let _ = routing_node.send_refresh_request(src, serialised_refresh);
-            }
-        }
+            debug!("MpidManager sending refresh for account {:?}", src.name());
+            Action::SendRefresh(src, refresh)
+        })
     }
 
     fn fetch_chunks(storage: &ChunkStore, names: &Vec<XorName>) -> Vec<PlainData> {
@@ -593,28 +656,162 @@ impl MpidManager {
     }
 }
 
+impl Persona for MpidManager {
+    fn name(&self) -> &'static str {
+        "mpid_manager"
+    }
 
+    fn handle_request(&mut self,
+                      _routing_node: &RoutingApi,
+                      request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        match (&request.src, &request.dst, &request.content) {
+            (&Authority::Client{ .. },
+             &Authority::ClientManager(_),
+             &RequestContent::Put(Data::Plain(_), _)) |
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &RequestContent::Put(Data::Plain(_), _)) => {
+                Some(self.handle_put(request))
+            }
+            (&Authority::Client{ .. },
+             &Authority::ClientManager(_),
+             &RequestContent::Post(Data::Plain(_), _)) |
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &RequestContent::Post(Data::Plain(_), _)) => {
+                Some(self.handle_post(request))
+            }
+            (&Authority::Client{ .. },
+             &Authority::ClientManager(_),
+             &RequestContent::Delete(Data::Plain(_), _)) => {
+                Some(self.handle_delete(request))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_response(&mut self,
+                       _routing_node: &RoutingApi,
+                       response: &ResponseMessage)
+                       -> Option<Result<Vec<Action>, InternalError>> {
+        match (&response.src, &response.dst, &response.content) {
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &ResponseContent::PutFailure{ ref request, .. }) => {
+                Some(self.handle_put_failure(request))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_refresh_request(&mut self,
+                              _routing_node: &RoutingApi,
+                              src: &Authority,
+                              dst: &Authority,
+                              sender: &XorName,
+                              refresh: &Refresh)
+                              -> Option<Result<Vec<Action>, InternalError>> {
+        match (src, dst, &refresh.value) {
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &RefreshValue::MpidManagerAccount(ref account, ref stored_messages, ref received_headers)) => {
+                Some(Ok(self.handle_refresh(refresh.name,
+                                            sender.clone(),
+                                            account,
+                                            stored_messages,
+                                            received_headers)))
+            }
+            (&Authority::ClientManager(_),
+             &Authority::ClientManager(_),
+             &RefreshValue::SyncRequest(ref persona)) if persona == self.name() => {
+                Some(Ok(self.account_refresh(&refresh.name).into_iter().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_churn(&mut self, _routing_node: &RoutingApi) -> Vec<Action> {
+        self.accounts.keys().filter_map(|mpid_name| self.account_refresh(mpid_name)).collect()
+    }
+
+    fn handle_churn_of(&mut self,
+                       routing_node: &RoutingApi,
+                       churned_node: &XorName)
+                       -> Vec<Action> {
+        self.accounts
+            .keys()
+            .filter(|mpid_name| utils::is_affected_by_churn(routing_node, mpid_name, churned_node))
+            .filter_map(|mpid_name| self.account_refresh(mpid_name))
+            .collect()
+    }
+
+    fn handle_tick(&mut self, _routing_node: &RoutingApi) -> Result<Vec<Action>, InternalError> {
+        self.refresh_accumulator.expire();
+        Ok(vec![])
+    }
+
+    fn load_state(&mut self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        if let Some(accounts) = try!(state_store.load(STATE_NAME)) {
+            self.accounts = accounts;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        state_store.save(STATE_NAME, &self.accounts)
+    }
 
-#[cfg(all(test, feature = "use-mock-routing"))]
+    fn update_status(&self, status: &mut Status) {
+        status.mpid_manager_account_count = self.accounts.len();
+        status.mpid_manager_inbox_header_count = 0;
+        status.mpid_manager_inbox_used_space = 0;
+        status.mpid_manager_outbox_message_count = 0;
+        status.mpid_manager_outbox_used_space = 0;
+        for account in self.accounts.values() {
+            status.mpid_manager_inbox_header_count += account.inbox.len();
+            status.mpid_manager_inbox_used_space += account.inbox.used_space;
+            status.mpid_manager_outbox_message_count += account.outbox.len();
+            status.mpid_manager_outbox_used_space += account.outbox.used_space;
+        }
+        status.chunk_stores.push(ChunkStoreStatus::new("mpid_manager_inbox",
+                                                       &self.chunk_store_inbox));
+        status.chunk_stores.push(ChunkStoreStatus::new("mpid_manager_outbox",
+                                                       &self.chunk_store_outbox));
+    }
+}
+
+
+
+#[cfg(test)]
 mod test {
     use super::*;
+    use clock::MockClock;
+    use config_handler::Config;
     use error::{ClientError, InternalError};
     use maidsafe_utilities::serialisation;
     use rand;
     use routing::{Authority, Data, MessageId, PlainData, RequestContent, RequestMessage,
-                  ResponseContent};
+                  ResponseContent, ResponseMessage};
+    use personas::Persona;
+    use rustc_serialize::hex::ToHex;
     use sodiumoxide::crypto::sign;
-    use std::sync::mpsc;
-    use utils::generate_random_vec_u8;
-    use vault::RoutingNode;
+    use sodiumoxide::randombytes;
+    use state_store::StateStore;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use types::Action;
+    use utils::{self, generate_random_vec_u8};
+    use worker::send_actions;
     use xor_name::XorName;
     use mpid_messaging::{MpidHeader, MpidMessage, MpidMessageWrapper};
 
     struct Environment {
         our_authority: Authority,
         client: Authority,
-        routing: RoutingNode,
         mpid_manager: MpidManager,
+        actions: Vec<Action>,
     }
 
     fn environment_setup() -> Environment {
@@ -626,11 +823,98 @@ mod test {
                 client_key: keys.0,
                 proxy_node_name: from.clone(),
             },
-            routing: unwrap_result!(RoutingNode::new(mpsc::channel().0)),
-            mpid_manager: MpidManager::new(),
+            mpid_manager: unwrap_result!(MpidManager::new(&Config::default(),
+                                                          Arc::new(MockClock::new()))),
+            actions: vec![],
         }
     }
 
+    fn requests_given<F>(env: &Environment, filter: F) -> Vec<RequestMessage>
+        where F: Fn(&RequestContent) -> bool
+    {
+        env.actions
+           .iter()
+           .filter_map(|action| {
+               match *action {
+                   Action::SendRequest(ref request) if filter(&request.content) => {
+                       Some(request.clone())
+                   }
+                   _ => None,
+               }
+           })
+           .collect()
+    }
+
+    fn responses_given<F>(env: &Environment, filter: F) -> Vec<ResponseMessage>
+        where F: Fn(&ResponseContent) -> bool
+    {
+        env.actions
+           .iter()
+           .filter_map(|action| {
+               match *action {
+                   Action::SendResponse(ref response) if filter(&response.content) => {
+                       Some(response.clone())
+                   }
+                   _ => None,
+               }
+           })
+           .collect()
+    }
+
+    fn put_requests_given(env: &Environment) -> Vec<RequestMessage> {
+        requests_given(env, |content| {
+            match *content {
+                RequestContent::Put(..) => true,
+                _ => false,
+            }
+        })
+    }
+
+    fn post_requests_given(env: &Environment) -> Vec<RequestMessage> {
+        requests_given(env, |content| {
+            match *content {
+                RequestContent::Post(..) => true,
+                _ => false,
+            }
+        })
+    }
+
+    fn delete_requests_given(env: &Environment) -> Vec<RequestMessage> {
+        requests_given(env, |content| {
+            match *content {
+                RequestContent::Delete(..) => true,
+                _ => false,
+            }
+        })
+    }
+
+    fn put_failures_given(env: &Environment) -> Vec<ResponseMessage> {
+        responses_given(env, |content| {
+            match *content {
+                ResponseContent::PutFailure { .. } => true,
+                _ => false,
+            }
+        })
+    }
+
+    fn post_failures_given(env: &Environment) -> Vec<ResponseMessage> {
+        responses_given(env, |content| {
+            match *content {
+                ResponseContent::PostFailure { .. } => true,
+                _ => false,
+            }
+        })
+    }
+
+    fn delete_failures_given(env: &Environment) -> Vec<ResponseMessage> {
+        responses_given(env, |content| {
+            match *content {
+                ResponseContent::DeleteFailure { .. } => true,
+                _ => false,
+            }
+        })
+    }
+
     fn register_online(env: &mut Environment, src: &Authority, dst: &Authority) {
         let wrapper = MpidMessageWrapper::Online;
         let name = src.name().clone();
@@ -643,8 +927,8 @@ mod test {
             content: RequestContent::Post(Data::Plain(plain_data.clone()), message_id.clone()),
         };
 
-        match env.mpid_manager.handle_post(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_post(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
     }
@@ -667,8 +951,8 @@ mod test {
             content: RequestContent::Put(Data::Plain(plain_data.clone()), id.clone()),
         };
 
-        match env.mpid_manager.handle_put(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_put(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
     }
@@ -688,8 +972,8 @@ mod test {
             content: RequestContent::Put(Data::Plain(plain_data.clone()), id.clone()),
         };
 
-        match env.mpid_manager.handle_put(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_put(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
     }
@@ -709,8 +993,8 @@ mod test {
             content: RequestContent::Post(Data::Plain(plain_data.clone()), id.clone()),
         };
 
-        match env.mpid_manager.handle_post(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_post(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
     }
@@ -729,8 +1013,8 @@ mod test {
             content: RequestContent::Delete(Data::Plain(plain_data.clone()), id.clone()),
         };
 
-        match env.mpid_manager.handle_delete(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_delete(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
     }
@@ -749,8 +1033,8 @@ mod test {
             content: RequestContent::Delete(Data::Plain(plain_data.clone()), id.clone()),
         };
 
-        match env.mpid_manager.handle_delete(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_delete(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
     }
@@ -779,9 +1063,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst, Authority::ClientManager(receiver_name));
@@ -799,6 +1083,48 @@ mod test {
         }
     }
 
+    #[test]
+    fn accounts_and_messages_survive_restart() {
+        let data_dir = env::temp_dir().join(format!("safe_vault_mpid_manager_{}",
+                                                    randombytes::randombytes(8).to_hex()));
+        let config = Config {
+            data_dir: Some(data_dir.to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        let mut env = environment_setup();
+        env.mpid_manager = unwrap_result!(MpidManager::new(&config, Arc::new(MockClock::new())));
+        let src = env.client.clone();
+        let dst = env.our_authority.clone();
+        register_online(&mut env, &src, &dst);
+        let (_public_key, secret_key) = sign::gen_keypair();
+        let mpid_message = unwrap_result!(MpidMessage::new(rand::random::<XorName>(),
+                                                           generate_random_vec_u8(128),
+                                                           rand::random::<XorName>(),
+                                                           generate_random_vec_u8(128),
+                                                           &secret_key));
+        put_mpid_message(&mut env, &mpid_message, &MessageId::new());
+        let message_name = unwrap_result!(mpid_message.header().name());
+        let message = unwrap_result!(env.mpid_manager.chunk_store_outbox.get(&message_name));
+        let accounts = env.mpid_manager.accounts.clone();
+        assert!(!accounts.is_empty());
+
+        // Saved as the vault does before it exits, then reloaded as it does on starting again
+        {
+            let mut state_store = unwrap_result!(StateStore::new(data_dir.join("state")));
+            unwrap_result!(env.mpid_manager.save_state(&mut state_store));
+        }
+        drop(env);
+
+        let mut mpid_manager = unwrap_result!(MpidManager::new(&config,
+                                                               Arc::new(MockClock::new())));
+        let mut state_store = unwrap_result!(StateStore::new(data_dir.join("state")));
+        unwrap_result!(mpid_manager.load_state(&mut state_store));
+        assert_eq!(mpid_manager.accounts, accounts);
+        assert_eq!(unwrap_result!(mpid_manager.chunk_store_outbox.get(&message_name)),
+                   message);
+        let _ = fs::remove_dir_all(data_dir);
+    }
+
     #[test]
     fn put_header() {
         let mut env = environment_setup();
@@ -820,9 +1146,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_header(&mut env, &mpid_header, &src, &dst, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert!(put_requests.is_empty());
     }
 
@@ -857,14 +1183,14 @@ mod test {
             content: RequestContent::Put(Data::Plain(plain_data.clone()), message_id.clone()),
         };
 
-        match env.mpid_manager.handle_put(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_put(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst, Authority::ClientManager(receiver));
@@ -880,7 +1206,7 @@ mod test {
         }
 
         // put message again...
-        match env.mpid_manager.handle_put(&env.routing, &request) {
+        match env.mpid_manager.handle_put(&request) {
             Ok(_) => panic!("Expected an error."),
             Err(InternalError::Client(ClientError::DataExists)) => (),
             Err(_) => panic!("Unexpected error."),
@@ -898,14 +1224,14 @@ mod test {
                                          MessageId::new().clone()),
         };
 
-        match env.mpid_manager.handle_put(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_put(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst, Authority::ClientManager(receiver));
@@ -921,7 +1247,7 @@ mod test {
         }
 
         // put header again...
-        match env.mpid_manager.handle_put(&env.routing, &request) {
+        match env.mpid_manager.handle_put(&request) {
             Ok(_) => panic!("Expected an error."),
             Err(InternalError::Client(ClientError::DataExists)) => (),
             Err(_) => panic!("Unexpected error."),
@@ -951,9 +1277,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst,
@@ -978,7 +1304,7 @@ mod test {
         let message_id = MessageId::new();
         get_mpid_message(&mut env, &mpid_header, &src, &dst, &message_id);
 
-        let post_requests = env.routing.post_requests_given();
+        let post_requests = post_requests_given(&env);
         assert_eq!(post_requests.len(), 1);
         assert_eq!(post_requests[0].src, env.our_authority);
         assert_eq!(post_requests[0].dst,
@@ -1018,9 +1344,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst,
@@ -1052,12 +1378,12 @@ mod test {
             content: RequestContent::Post(Data::Plain(plain_data.clone()), message_id.clone()),
         };
 
-        match env.mpid_manager.handle_post(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_post(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
 
-        let post_requests = env.routing.post_requests_given();
+        let post_requests = post_requests_given(&env);
         assert_eq!(post_requests.len(), 1);
         assert_eq!(post_requests[0].src, env.our_authority);
         assert_eq!(post_requests[0].dst, env.client);
@@ -1095,9 +1421,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst,
@@ -1127,12 +1453,12 @@ mod test {
             content: RequestContent::Post(Data::Plain(plain_data.clone()), message_id.clone()),
         };
 
-        match env.mpid_manager.handle_post(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_post(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
 
-        let post_requests = env.routing.post_requests_given();
+        let post_requests = post_requests_given(&env);
         assert_eq!(post_requests.len(), 1);
         assert_eq!(post_requests[0].src, env.our_authority);
         assert_eq!(post_requests[0].dst, env.client);
@@ -1172,9 +1498,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, env.our_authority);
         assert_eq!(put_requests[0].dst,
@@ -1204,7 +1530,7 @@ mod test {
         let message_id = MessageId::new();
         get_mpid_message(&mut env, &mpid_header, &src, &dst, &message_id);
 
-        let post_failures = env.routing.post_failures_given();
+        let post_failures = post_failures_given(&env);
         assert_eq!(post_failures.len(), 1);
         assert_eq!(post_failures[0].src, env.our_authority);
         assert_eq!(post_failures[0].dst,
@@ -1251,9 +1577,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, dst.clone());
         assert_eq!(put_requests[0].dst,
@@ -1287,18 +1613,18 @@ mod test {
         let message_id = MessageId::new();
         delete_mpid_header(&mut env, &mpid_header_name, &receiver, &dst, &message_id);
 
-        let delete_requests = env.routing.delete_requests_given();
+        let delete_requests = delete_requests_given(&env);
         assert!(delete_requests.is_empty());
-        let delete_failures = env.routing.delete_failures_given();
+        let delete_failures = delete_failures_given(&env);
         assert!(delete_failures.is_empty());
 
         // delete header again...
         let message_id = MessageId::new();
         delete_mpid_header(&mut env, &mpid_header_name, &receiver, &dst, &message_id);
 
-        let delete_requests = env.routing.delete_requests_given();
+        let delete_requests = delete_requests_given(&env);
         assert!(delete_requests.is_empty());
-        let delete_failures = env.routing.delete_failures_given();
+        let delete_failures = delete_failures_given(&env);
         assert_eq!(delete_failures.len(), 1);
         assert_eq!(delete_failures[0].src,
                    Authority::ClientManager(receiver_name.clone()));
@@ -1344,9 +1670,9 @@ mod test {
         let message_id = MessageId::new();
         put_mpid_message(&mut env, &mpid_message, &message_id);
 
-        let put_failures = env.routing.put_failures_given();
+        let put_failures = put_failures_given(&env);
         assert!(put_failures.is_empty());
-        let put_requests = env.routing.put_requests_given();
+        let put_requests = put_requests_given(&env);
         assert_eq!(put_requests.len(), 1);
         assert_eq!(put_requests[0].src, dst.clone());
         assert_eq!(put_requests[0].dst,
@@ -1375,7 +1701,7 @@ mod test {
         let message_id = MessageId::new();
         get_mpid_message(&mut env, &mpid_header, &src, &dst, &message_id);
 
-        let post_requests = env.routing.post_requests_given();
+        let post_requests = post_requests_given(&env);
         assert_eq!(post_requests.len(), 1);
         assert_eq!(post_requests[0].src, env.our_authority);
         assert_eq!(post_requests[0].dst,
@@ -1404,12 +1730,12 @@ mod test {
             content: RequestContent::Post(Data::Plain(plain_data.clone()), message_id.clone()),
         };
 
-        match env.mpid_manager.handle_post(&env.routing, &request) {
-            Ok(()) => (),
+        match env.mpid_manager.handle_post(&request) {
+            Ok(actions) => env.actions.extend(actions),
             Err(error) => panic!("Error: {:?}", error),
         }
 
-        let post_requests = env.routing.post_requests_given();
+        let post_requests = post_requests_given(&env);
         assert_eq!(post_requests.len(), 2);
         assert_eq!(post_requests[1].src, receiver.clone());
         assert_eq!(post_requests[1].dst, receiver.clone());
@@ -1424,4 +1750,23 @@ mod test {
             _ => unreachable!(),
         }
     }
+
+    #[test]
+    fn churn_refreshes_affected_accounts() {
+        let mut env = environment_setup();
+        let churn = utils::churn_environment();
+        for mpid_name in churn.near_names.iter().chain(Some(&churn.far_name)) {
+            let _ = env.mpid_manager.accounts.insert(*mpid_name, Account::default());
+        }
+
+        // Churn within our close group refreshes the accounts we share with it
+        send_actions(&churn.routing,
+                     env.mpid_manager.handle_churn_of(&churn.routing, &churn.member));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 3);
+
+        // Churn elsewhere refreshes nothing
+        send_actions(&churn.routing,
+                     env.mpid_manager.handle_churn_of(&churn.routing, &churn.far_name));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 3);
+    }
 }
@@ -15,69 +15,119 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use error::InternalError;
+use clock::Clock;
+use config_handler::Config;
+use error::{ClientError, InternalError};
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, ImmutableData, MessageId, ResponseMessage};
+use personas::Persona;
+use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, PlainData,
+              RequestContent, RequestMessage, ResponseContent, ResponseMessage};
+use refresh_accumulator::{self, Accumulated, RefreshAccumulator};
+use routing_api::RoutingApi;
+use state_store::StateStore;
+use status::Status;
 use std::collections::HashMap;
-use types::{Refresh, RefreshValue};
-use vault::RoutingNode;
+use std::sync::Arc;
+use std::u64;
+use types::{Action, Refresh, RefreshValue};
+use utils;
 use xor_name::XorName;
 
+const STATE_NAME: &'static str = "pmid_manager_accounts";
+
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
 pub struct Account {
     stored_total_size: u64,
     lost_total_size: u64,
+    // The part of `stored_total_size` taken up by Sacrificial copies
+    sacrificial_total_size: u64,
+    offered_space: u64,
+}
+
+// An account as saved in state format 1, before Sacrificial usage and offered space were recorded.
+#[derive(RustcEncodable, RustcDecodable)]
+struct AccountV1 {
+    stored_total_size: u64,
+    lost_total_size: u64,
+}
+
+impl From<AccountV1> for Account {
+    // The PmidNode's Sacrificial usage is unknown, and it registers its offered space again when
+    // it next connects.
+    fn from(account: AccountV1) -> Account {
+        Account {
+            stored_total_size: account.stored_total_size,
+            lost_total_size: account.lost_total_size,
+            ..Account::default()
+        }
+    }
+}
+
+fn migrate_accounts(version: u32,
+                    payload: &[u8])
+                    -> Result<HashMap<XorName, Account>, InternalError> {
+    match version {
+        1 => {
+            let accounts: HashMap<XorName, AccountV1> = try!(serialisation::deserialise(payload));
+            Ok(accounts.into_iter()
+                       .map(|(pmid_node, account)| (pmid_node, Account::from(account)))
+                       .collect())
+        }
+        _ => Err(InternalError::UnsupportedStateVersion(version)),
+    }
 }
 
 impl Default for Account {
     // FIXME: Account Creation process required https://maidsafe.atlassian.net/browse/MAID-1191
-    //   To bypass the the process for a simple network, allowance is granted by default
+    //   To bypass the the process for a simple network, allowance is granted by default, and the
+    //   PmidNode isn't limited until it registers the space it offers
     fn default() -> Account {
         Account {
             stored_total_size: 0,
             lost_total_size: 0,
+            sacrificial_total_size: 0,
+            offered_space: u64::MAX,
         }
     }
 }
 
 impl Account {
-    // Always return true to allow pmid_node carry out removal of Sacrificial copies
-    // Otherwise Account need to remember storage info of Primary, Backup and Sacrificial
-    // copies separately to trigger an early alert
-    fn put_data(&mut self, size: u64) {
-        // if (self.stored_total_size + size) > self.offered_space {
-        //   return false;
-        // }
+    // The PmidNode clears out Sacrificial copies to make room for Normal ones, so Sacrificial
+    // copies only count against the offered space for other Backup and Sacrificial copies.
+    fn put_data(&mut self, data: &ImmutableData) -> Result<(), ClientError> {
+        let size = data.payload_size() as u64;
+        let used_space = match *data.get_type_tag() {
+            ImmutableDataType::Normal => {
+                self.stored_total_size.saturating_sub(self.sacrificial_total_size)
+            }
+            _ => self.stored_total_size,
+        };
+        if used_space.saturating_add(size) > self.offered_space {
+            return Err(ClientError::NotEnoughSpace);
+        }
+
         self.stored_total_size += size;
+        if *data.get_type_tag() == ImmutableDataType::Sacrificial {
+            self.sacrificial_total_size += size;
+        }
+        Ok(())
     }
 
-    fn delete_data(&mut self, size: u64) {
+    fn delete_data(&mut self, data: &ImmutableData) {
+        let size = data.payload_size() as u64;
         if self.stored_total_size < size {
             self.stored_total_size = 0;
         } else {
             self.stored_total_size -= size;
         }
+        if *data.get_type_tag() == ImmutableDataType::Sacrificial {
+            self.sacrificial_total_size = self.sacrificial_total_size.saturating_sub(size);
+        }
     }
 
-    #[allow(dead_code)]
-    fn handle_lost_data(&mut self, size: u64) {
-        self.delete_data(size);
-        self.lost_total_size += size;
-    }
-
-    #[allow(dead_code)]
-    fn handle_falure(&mut self, size: u64) {
-        self.handle_lost_data(size);
-    }
-
-    #[allow(dead_code)]
-    fn update_account(&mut self, diff_size: u64) {
-        if self.stored_total_size < diff_size {
-            self.stored_total_size = 0;
-        } else {
-            self.stored_total_size -= diff_size;
-        }
-        self.lost_total_size += diff_size;
+    fn handle_lost_data(&mut self, data: &ImmutableData) {
+        self.delete_data(data);
+        self.lost_total_size += data.payload_size() as u64;
     }
 }
 
@@ -85,200 +135,487 @@ impl Account {
 
 pub struct PmidManager {
     accounts: HashMap<XorName, Account>,
+    refresh_accumulator: RefreshAccumulator<Account>,
 }
 
 impl PmidManager {
-    pub fn new() -> PmidManager {
-        PmidManager { accounts: HashMap::new() }
+    pub fn new(config: &Config, clock: Arc<Clock>) -> PmidManager {
+        PmidManager {
+            accounts: HashMap::new(),
+            refresh_accumulator: RefreshAccumulator::new(config.refresh_quorum(), clock),
+        }
     }
 
-    pub fn handle_put(&mut self,
-                      routing_node: &RoutingNode,
-                      data: &ImmutableData,
-                      message_id: &MessageId,
-                      pmid_node: XorName)
-                      -> Result<(), InternalError> {
-        // Put data always being allowed, i.e. no early alert
+    /// Passes the data on to our PmidNode, unless it would take the PmidNode beyond the space it
+    /// offers, in which case the data's managers are told so they can put it elsewhere.
+    pub fn handle_put(&mut self, request: &RequestMessage) -> Result<Vec<Action>, InternalError> {
+        let (data, message_id) = match request.content {
+            RequestContent::Put(Data::Immutable(ref data), ref message_id) => (data, message_id),
+            _ => unreachable!("Error in vault demuxing"),
+        };
+        let pmid_node = request.dst.name().clone();
+
+        if let Err(error) = self.accounts
+                                .entry(pmid_node.clone())
+                                .or_insert(Account::default())
+                                .put_data(data) {
+            debug!("PmidManager refusing {:?} for {:?}: {:?}",
+                   data.name(),
+                   pmid_node,
+                   error);
+            let external_error_indicator = try!(serialisation::serialise(&error));
+            let content = ResponseContent::PutFailure {
+                id: message_id.clone(),
+                request: request.clone(),
+                external_error_indicator: external_error_indicator,
+            };
+            return Ok(vec![Action::response(request.dst.clone(), request.src.clone(), content)]);
+        }
+
+        let src = Authority::NodeManager(pmid_node.clone());
+        let dst = Authority::ManagedNode(pmid_node);
+        let content = RequestContent::Put(Data::Immutable(data.clone()), message_id.clone());
+        Ok(vec![Action::request(src, dst, content)])
+    }
+
+    /// Handles the data's managers dropping our PmidNode as a holder.  The account is corrected,
+    /// and the PmidNode is told to delete its copy.
+    pub fn handle_delete(&mut self,
+                         request: &RequestMessage)
+                         -> Result<Vec<Action>, InternalError> {
+        let (data, message_id) = match request.content {
+            RequestContent::Delete(Data::Immutable(ref data), ref message_id) => (data, message_id),
+            _ => unreachable!("Error in vault demuxing"),
+        };
+        let pmid_node = request.dst.name().clone();
+        if let Some(account) = self.accounts.get_mut(&pmid_node) {
+            account.delete_data(data);
+        }
+
+        let src = Authority::NodeManager(pmid_node.clone());
+        let dst = Authority::ManagedNode(pmid_node);
+        let content = RequestContent::Delete(Data::Immutable(data.clone()), message_id.clone());
+        Ok(vec![Action::request(src, dst, content)])
+    }
+
+    /// Records the space our PmidNode offers.  It's sent when the PmidNode joins the network.
+    pub fn handle_offered_space(&mut self,
+                                pmid_node: &XorName,
+                                data: &PlainData)
+                                -> Result<Vec<Action>, InternalError> {
+        if data.name() != *pmid_node {
+            warn!("{:?} tried to register offered space for {:?}",
+                  pmid_node,
+                  data.name());
+            return Ok(vec![]);
+        }
+
+        let offered_space = try!(serialisation::deserialise::<u64>(data.value()));
+        debug!("PmidManager recording {:?} as offering {} bytes",
+               pmid_node,
+               offered_space);
         self.accounts
             .entry(pmid_node.clone())
             .or_insert(Account::default())
-            .put_data(data.payload_size() as u64);
+            .offered_space = offered_space;
+        Ok(vec![])
+    }
+
+    /// Handles our PmidNode reporting that it refused to store some data, or has deleted a
+    /// Sacrificial copy to make room.  The account is corrected, and the failure is passed on to
+    /// the data's managers.  Only data the PmidNode should still have been holding counts as lost.
+    pub fn handle_put_failure(&mut self,
+                              pmid_node: &XorName,
+                              message_id: &MessageId,
+                              request: &RequestMessage,
+                              external_error_indicator: &Vec<u8>)
+                              -> Result<Vec<Action>, InternalError> {
+        let data = match request.content {
+            RequestContent::Put(Data::Immutable(ref data), _) => data,
+            _ => {
+                warn!("Request type doesn't correspond to response type: {:?}",
+                      request);
+                return Err(InternalError::InvalidResponse);
+            }
+        };
+
+        let error = try!(serialisation::deserialise::<ClientError>(external_error_indicator));
+        if let Some(account) = self.accounts.get_mut(pmid_node) {
+            match error {
+                // The data was stored, but has since been deleted on purpose
+                ClientError::HadToClearSacrificial => account.delete_data(data),
+                // The PmidNode had no room for the data, so it was never stored
+                ClientError::NotEnoughSpace => account.delete_data(data),
+                // The data was accounted for when it was put, but the PmidNode doesn't have it
+                _ => account.handle_lost_data(data),
+            }
+        }
 
         let src = Authority::NodeManager(pmid_node.clone());
-        let dst = Authority::ManagedNode(pmid_node);
-        let _ = routing_node.send_put_request(src,
-                                              dst,
-                                              Data::Immutable(data.clone()),
-                                              message_id.clone());
-        Ok(())
+        let dst = Authority::NaeManager(data.name());
+        debug!("PmidManager forwarding Put failure ({:?}) for {:?} to {:?}",
+               error,
+               data.name(),
+               dst);
+        let content = ResponseContent::PutFailure {
+            id: message_id.clone(),
+            request: request.clone(),
+            external_error_indicator: external_error_indicator.clone(),
+        };
+        Ok(vec![Action::response(src, dst, content)])
     }
 
-    #[allow(dead_code)]
-    pub fn handle_put_failure(&mut self, _response: ResponseMessage) {
-        //        match from_authority {
-        // &Authority::ManagedNode(from_address) => {
-        // self.handle_put_response_from_pmid_node(our_authority.clone(),
-        //                                         from_address,
-        //                                         response.clone(),
-        //                                         response_token.clone());
-        // match response {
-        // ::routing::error::ResponseError::FailedRequestForData(data) => {
-        // let payload_size = data.payload_size() as u64;
-        // match data {
-        // ::routing::data::Data::Immutable(immutable_data) => {
-        // self.database.delete_data(&from_address, payload_size);
-        // let location = ::immutable_data_manager::Authority(immutable_data.name());
-        // let response = ::routing::error::ResponseError::FailedRequestForData(
-        // ::routing::data::Data::Immutable(immutable_data));
-        // self.routing
-        // .put_response(our_authority, location, response, response_token);
-        // }
-        // _ => warn!("Invalid data type for PUT RESPONSE at PmidManager: {:?}", data),
-        // }
-        // }
-        // ::routing::error::ResponseError::HadToClearSacrificial(data_name, data_size) => {
-        // self.database.delete_data(&from_address, data_size as u64);
-        // let location = ::immutable_data_manager::Authority(data_name.clone());
-        // let response = ::routing::error::ResponseError::HadToClearSacrificial(data_name,
-        // data_size);
-        // self.routing.put_response(our_authority, location, response, response_token);
-        // }
-        // _ => warn!("Invalid response type from PmidNode for PUT RESPONSE at PmidManager"),
-        // }
-        // }
-        // &::immutable_data_manager::Authority(_) => {
-        //     self.handle_put_response_from_data_manager(pmid_node, response.clone());
-        // }
-        // _ => warn!("Invalid authority for PUT RESPONSE at PmidManager: {:?}", from_authority),
-        // }
-        // ::utils::HANDLED
-        //
+    /// Takes over the account once a quorum of the group has sent matching refreshes for it.
+    pub fn handle_refresh(&mut self,
+                          name: XorName,
+                          sender: XorName,
+                          account: Account)
+                          -> Vec<Action> {
+        match self.refresh_accumulator.add(name, sender, account) {
+            Accumulated::Agreed(account) => {
+                let _ = self.accounts.insert(name, account);
+                vec![]
+            }
+            Accumulated::Conflicted => {
+                vec![refresh_accumulator::sync_request(Authority::NodeManager(name), self.name())]
+            }
+            Accumulated::Pending => vec![],
+        }
     }
 
-    pub fn handle_refresh(&mut self, name: XorName, account: Account) {
-        let _ = self.accounts.insert(name, account);
+    fn account_refresh(&self, pmid_node: &XorName) -> Option<Action> {
+        self.accounts.get(pmid_node).map(|account| {
+            let src = Authority::NodeManager(pmid_node.clone());
+            let refresh = Refresh::new(pmid_node,
+                                       RefreshValue::PmidManagerAccount(account.clone()));
+            debug!("PmidManager sending refresh for account {:?}", src.name());
+            Action::SendRefresh(src, refresh)
+        })
+    }
+}
+
+impl Persona for PmidManager {
+    fn name(&self) -> &'static str {
+        "pmid_manager"
     }
 
-    pub fn handle_churn(&mut self, routing_node: &RoutingNode) {
-        for (pmid_node, account) in self.accounts.iter() {
+    fn handle_request(&mut self,
+                      _routing_node: &RoutingApi,
+                      request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        match (&request.src, &request.dst, &request.content) {
+            (&Authority::NaeManager(_),
+             &Authority::NodeManager(_),
+             &RequestContent::Put(Data::Immutable(_), _)) => Some(self.handle_put(request)),
+            (&Authority::NaeManager(_),
+             &Authority::NodeManager(_),
+             &RequestContent::Delete(Data::Immutable(_), _)) => Some(self.handle_delete(request)),
+            (&Authority::ManagedNode(ref pmid_node),
+             &Authority::NodeManager(_),
+             &RequestContent::Post(Data::Plain(ref data), _)) => {
+                Some(self.handle_offered_space(pmid_node, data))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_response(&mut self,
+                       _routing_node: &RoutingApi,
+                       response: &ResponseMessage)
+                       -> Option<Result<Vec<Action>, InternalError>> {
+        match (&response.src, &response.dst, &response.content) {
+            (&Authority::ManagedNode(ref pmid_node),
+             &Authority::NodeManager(_),
+             &ResponseContent::PutFailure{ ref id, ref request, ref external_error_indicator }) => {
+                Some(self.handle_put_failure(pmid_node, id, request, external_error_indicator))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_refresh_request(&mut self,
+                              _routing_node: &RoutingApi,
+                              src: &Authority,
+                              dst: &Authority,
+                              sender: &XorName,
+                              refresh: &Refresh)
+                              -> Option<Result<Vec<Action>, InternalError>> {
+        match (src, dst, &refresh.value) {
+            (&Authority::NodeManager(_),
+             &Authority::NodeManager(_),
+             &RefreshValue::PmidManagerAccount(ref account)) => {
+                Some(Ok(self.handle_refresh(refresh.name, sender.clone(), account.clone())))
+            }
+            (&Authority::NodeManager(_),
+             &Authority::NodeManager(_),
+             &RefreshValue::SyncRequest(ref persona)) if persona == self.name() => {
+                Some(Ok(self.account_refresh(&refresh.name).into_iter().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_churn(&mut self, routing_node: &RoutingApi) -> Vec<Action> {
+        let mut actions = vec![];
+        for pmid_node in self.accounts.keys() {
             // Only refresh accounts for PmidNodes to which we are still close
             if routing_node.close_group(pmid_node.clone()).ok().is_none() {
                 continue;
             }
+            actions.extend(self.account_refresh(pmid_node));
+        }
+        actions
+    }
 
-            let src = Authority::NodeManager(pmid_node.clone());
-            let refresh = Refresh::new(pmid_node, RefreshValue::PmidManagerAccount(account.clone()));
-            if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
-                debug!("PmidManager sending refresh for account {:?}", src.name());
-                let _ = routing_node.send_refresh_request(src, serialised_refresh);
-            }
+    fn handle_churn_of(&mut self,
+                       routing_node: &RoutingApi,
+                       churned_node: &XorName)
+                       -> Vec<Action> {
+        self.accounts
+            .keys()
+            .filter(|pmid_node| utils::is_affected_by_churn(routing_node, pmid_node, churned_node))
+            .filter_map(|pmid_node| self.account_refresh(pmid_node))
+            .collect()
+    }
+
+    fn handle_tick(&mut self, _routing_node: &RoutingApi) -> Result<Vec<Action>, InternalError> {
+        self.refresh_accumulator.expire();
+        Ok(vec![])
+    }
+
+    fn load_state(&mut self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        if let Some(accounts) = try!(state_store.load_migrating(STATE_NAME, migrate_accounts)) {
+            self.accounts = accounts;
         }
+        Ok(())
+    }
+
+    fn save_state(&self, state_store: &mut StateStore) -> Result<(), InternalError> {
+        state_store.save(STATE_NAME, &self.accounts)
     }
 
-    // fn handle_put_response_from_data_manager(&mut self,
-    //                                          pmid_node: XorName,
-    //                                          response: ::routing::error::ResponseError) {
-    //     match response {
-    //         ::routing::error::ResponseError::FailedRequestForData(data) => {
-    //             self.database.delete_data(&pmid_node, data.payload_size() as u64);
-    //         }
-    //         _ => warn!("Invalid response type from ImmutableDataManager for PUT RESPONSE at PmidManager"),
-    //     }
-    // }
+    fn update_status(&self, status: &mut Status) {
+        status.pmid_manager_account_count = self.accounts.len();
+    }
 }
 
 
-// #[cfg(all(test, feature = "use-mock-routing"))]
-// mod test {
-// use super::*;
-// use lru_time_cache::LruCache;
-// use maidsafe_utilities::serialisation::serialise;
-// use rand::random;
-// use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, RequestContent, RequestMessage,
-// ResponseContent, ResponseMessage};
-// use std::cmp::{max, min, Ordering};
-// use std::collections::BTreeSet;
-// use time::{Duration, SteadyTime};
-// use types::Refreshable;
-// use utils::{median, merge, HANDLED, NOT_HANDLED};
-// use vault::Routing;
-// use xor_name::{XorName, closer_to_target};
-//
-// fn env_setup()
-// -> (::routing::Authority,
-// ::vault::Routing,
-// PmidManager,
-// ::routing::Authority,
-// ImmutableData)
-// {
-// let routing = ::vault::Routing::new(::std::sync::mpsc::channel().0);
-// let pmid_manager = PmidManager::new(routing.clone());
-// let value = generate_random_vec_u8(1024);
-// let data =
-// ImmutableData::new(ImmutableDataType::Normal, value);
-// (Authority::NodeManager(random()),
-// routing,
-// pmid_manager,
-// Authority::NaeManager(random()),
-// data)
-// }
-//
-// #[test]
-// fn handle_put() {
-// let (our_authority,
-// routing,
-// mut pmid_manager,
-// from_authority,
-// data) = env_setup();
-// assert_eq!(::utils::HANDLED,
-// pmid_manager.handle_put(&our_authority,
-// &from_authority,
-// &::routing::data::Data::Immutable(data.clone())));
-// let put_requests = routing.put_requests_given();
-// assert_eq!(put_requests.len(), 1);
-// assert_eq!(put_requests[0].our_authority, our_authority);
-// assert_eq!(put_requests[0].location,
-// Authority::ManagedNode(our_authority.name().clone()));
-// assert_eq!(put_requests[0].data,
-// ::routing::data::Data::Immutable(data));
-// }
-//
-// #[test]
-// fn handle_churn_and_account_transfer() {
-// let (our_authority,
-// routing,
-// mut pmid_manager,
-// from_authority,
-// data) = env_setup();
-// assert_eq!(::utils::HANDLED,
-// pmid_manager.handle_put(&our_authority,
-// &from_authority,
-// &::routing::data::Data::Immutable(data.clone())));
-// let close_group = vec![XorName::new([1u8; 64]),
-// XorName::new([2u8; 64]),
-// XorName::new([3u8; 64]),
-// XorName::new([4u8; 64]),
-// our_authority.name().clone(),
-// XorName::new([5u8; 64]),
-// XorName::new([6u8; 64]),
-// XorName::new([7u8; 64]),
-// XorName::new([8u8; 64])];
-// let churn_node = random();
-// pmid_manager.handle_churn(&close_group, &churn_node);
-// let refresh_requests = routing.refresh_requests_given();
-// assert_eq!(refresh_requests.len(), 1);
-// assert_eq!(refresh_requests[0].type_tag, ACCOUNT_TAG);
-// assert_eq!(refresh_requests[0].our_authority.name(),
-// our_authority.name());
-//
-// let mut d = ::cbor::Decoder::from_bytes(&refresh_requests[0].content[..]);
-// if let Some(pm_account) = d.decode().next().and_then(|result| result.ok()) {
-// pmid_manager.database.handle_account_transfer(pm_account);
-// }
-// pmid_manager.handle_churn(&close_group, &churn_node);
-// let refresh_requests = routing.refresh_requests_given();
-// assert_eq!(refresh_requests.len(), 2);
-// assert_eq!(refresh_requests[0], refresh_requests[1]);
-// }
-// }
-//
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+    use config_handler::Config;
+    use error::ClientError;
+    use maidsafe_utilities::serialisation;
+    use personas::Persona;
+    use rand::random;
+    use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, PlainData,
+                  RequestContent, RequestMessage, ResponseContent};
+    use rustc_serialize::hex::ToHex;
+    use sodiumoxide::randombytes;
+    use state_store::StateStore;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use types::Action;
+    use utils::generate_random_vec_u8;
+    use xor_name::XorName;
+
+    fn put(pmid_manager: &mut PmidManager,
+           pmid_node: &XorName,
+           data: &ImmutableData,
+           message_id: &MessageId)
+           -> Vec<Action> {
+        let request = RequestMessage {
+            src: Authority::NaeManager(data.name()),
+            dst: Authority::NodeManager(pmid_node.clone()),
+            content: RequestContent::Put(Data::Immutable(data.clone()), message_id.clone()),
+        };
+        unwrap_result!(pmid_manager.handle_put(&request))
+    }
+
+    // Puts the data on the PmidNode, then has the PmidNode fail it with `error`.
+    fn put_then_fail(pmid_manager: &mut PmidManager,
+                     pmid_node: &XorName,
+                     data: &ImmutableData,
+                     error: ClientError)
+                     -> Vec<Action> {
+        let message_id = MessageId::new();
+        let actions = put(pmid_manager, pmid_node, data, &message_id);
+        let put_request = match actions[0] {
+            Action::SendRequest(ref put_request) => put_request.clone(),
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        };
+        let external_error_indicator = unwrap_result!(serialisation::serialise(&error));
+        unwrap_result!(pmid_manager.handle_put_failure(pmid_node,
+                                                       &message_id,
+                                                       &put_request,
+                                                       &external_error_indicator))
+    }
+
+    #[test]
+    fn handle_put_failure() {
+        let mut pmid_manager = PmidManager::new(&Config::default(), Arc::new(MockClock::new()));
+        let pmid_node = random::<XorName>();
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(1024));
+        let size = data.payload_size() as u64;
+
+        // The failure is passed on to the data's managers
+        let actions = put_then_fail(&mut pmid_manager,
+                                    &pmid_node,
+                                    &data,
+                                    ClientError::NotEnoughSpace);
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Action::SendResponse(ref response) => {
+                assert_eq!(response.src, Authority::NodeManager(pmid_node.clone()));
+                assert_eq!(response.dst, Authority::NaeManager(data.name()));
+                match response.content {
+                    ResponseContent::PutFailure{ ref request, .. } => {
+                        match request.content {
+                            RequestContent::Put(Data::Immutable(ref failed_data), _) => {
+                                assert_eq!(*failed_data, data)
+                            }
+                            _ => panic!("Unexpected request {:?}", request),
+                        }
+                    }
+                    _ => panic!("Unexpected response {:?}", response),
+                }
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        }
+
+        // Data the PmidNode had no room for was never stored, so isn't lost either
+        assert_eq!(pmid_manager.accounts[&pmid_node], Account::default());
+
+        // Sacrificed data is just no longer stored
+        let sacrificial_data = ImmutableData::new(ImmutableDataType::Sacrificial,
+                                                  generate_random_vec_u8(1024));
+        let _ = put_then_fail(&mut pmid_manager,
+                              &pmid_node,
+                              &sacrificial_data,
+                              ClientError::HadToClearSacrificial);
+        assert_eq!(pmid_manager.accounts[&pmid_node], Account::default());
+
+        // Data the PmidNode should have been holding counts as lost
+        let _ = put_then_fail(&mut pmid_manager, &pmid_node, &data, ClientError::NoSuchData);
+        assert_eq!(pmid_manager.accounts[&pmid_node],
+                   Account { lost_total_size: size, ..Account::default() });
+    }
+
+    #[test]
+    fn enforce_offered_space() {
+        let mut pmid_manager = PmidManager::new(&Config::default(), Arc::new(MockClock::new()));
+        let pmid_node = random::<XorName>();
+
+        // The PmidNode registers the space it offers
+        let offered_space = 3000u64;
+        let value = unwrap_result!(serialisation::serialise(&offered_space));
+        let registration = PlainData::new(pmid_node.clone(), value);
+        assert!(unwrap_result!(pmid_manager.handle_offered_space(&pmid_node, &registration))
+                    .is_empty());
+        assert_eq!(pmid_manager.accounts[&pmid_node].offered_space, offered_space);
+
+        // Another node can't register space on its behalf
+        let value = unwrap_result!(serialisation::serialise(&0u64));
+        let registration = PlainData::new(random::<XorName>(), value);
+        let _ = unwrap_result!(pmid_manager.handle_offered_space(&pmid_node, &registration));
+        assert_eq!(pmid_manager.accounts[&pmid_node].offered_space, offered_space);
+
+        // Fill most of the offered space with a Sacrificial copy
+        let sacrificial_data = ImmutableData::new(ImmutableDataType::Sacrificial,
+                                                  generate_random_vec_u8(2000));
+        let actions = put(&mut pmid_manager, &pmid_node, &sacrificial_data, &MessageId::new());
+        match actions[0] {
+            Action::SendRequest(ref request) => {
+                assert_eq!(request.dst, Authority::ManagedNode(pmid_node.clone()))
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        }
+
+        // A Backup copy beyond the offered space is refused, and its managers are told
+        let backup_data = ImmutableData::new(ImmutableDataType::Backup,
+                                             generate_random_vec_u8(1500));
+        let actions = put(&mut pmid_manager, &pmid_node, &backup_data, &MessageId::new());
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Action::SendResponse(ref response) => {
+                assert_eq!(response.src, Authority::NodeManager(pmid_node.clone()));
+                assert_eq!(response.dst, Authority::NaeManager(backup_data.name()));
+                match response.content {
+                    ResponseContent::PutFailure{ ref external_error_indicator, .. } => {
+                        assert_eq!(unwrap_result!(serialisation::deserialise::<ClientError>(
+                                       external_error_indicator)),
+                                   ClientError::NotEnoughSpace)
+                    }
+                    _ => panic!("Unexpected response {:?}", response),
+                }
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        }
+
+        // A Normal copy is let through, since the PmidNode can clear the Sacrificial one for it
+        let normal_data = ImmutableData::new(ImmutableDataType::Normal,
+                                             generate_random_vec_u8(1500));
+        let actions = put(&mut pmid_manager, &pmid_node, &normal_data, &MessageId::new());
+        match actions[0] {
+            Action::SendRequest(ref request) => {
+                assert_eq!(request.dst, Authority::ManagedNode(pmid_node.clone()))
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        }
+        assert_eq!(pmid_manager.accounts[&pmid_node].stored_total_size,
+                   (sacrificial_data.payload_size() + normal_data.payload_size()) as u64);
+    }
+
+    #[test]
+    fn handle_delete() {
+        let mut pmid_manager = PmidManager::new(&Config::default(), Arc::new(MockClock::new()));
+        let pmid_node = random::<XorName>();
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(1024));
+        let _ = put(&mut pmid_manager, &pmid_node, &data, &MessageId::new());
+        assert_eq!(pmid_manager.accounts[&pmid_node].stored_total_size,
+                   data.payload_size() as u64);
+
+        // The account no longer counts the data, and the PmidNode is told to delete it
+        let message_id = MessageId::new();
+        let request = RequestMessage {
+            src: Authority::NaeManager(data.name()),
+            dst: Authority::NodeManager(pmid_node.clone()),
+            content: RequestContent::Delete(Data::Immutable(data.clone()), message_id.clone()),
+        };
+        let actions = unwrap_result!(pmid_manager.handle_delete(&request));
+        assert_eq!(actions,
+                   vec![Action::request(Authority::NodeManager(pmid_node.clone()),
+                                        Authority::ManagedNode(pmid_node.clone()),
+                                        RequestContent::Delete(Data::Immutable(data),
+                                                               message_id))]);
+        assert_eq!(pmid_manager.accounts[&pmid_node], Account::default());
+    }
+
+    #[test]
+    fn load_accounts_saved_by_older_vault() {
+        let dir = env::temp_dir().join(format!("safe_vault_pmid_manager_{}",
+                                               randombytes::randombytes(8).to_hex()));
+        let mut state_store = unwrap_result!(StateStore::new(dir.clone()));
+        let pmid_node = random::<XorName>();
+        let mut accounts = HashMap::new();
+        let _ = accounts.insert(pmid_node,
+                                AccountV1 {
+                                    stored_total_size: 3,
+                                    lost_total_size: 2,
+                                });
+        unwrap_result!(state_store.save_as_version(STATE_NAME, 1, &accounts));
+
+        let mut pmid_manager = PmidManager::new(&Config::default(), Arc::new(MockClock::new()));
+        unwrap_result!(pmid_manager.load_state(&mut state_store));
+        assert_eq!(pmid_manager.accounts[&pmid_node],
+                   Account {
+                       stored_total_size: 3,
+                       lost_total_size: 2,
+                       ..Account::default()
+                   });
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -16,30 +16,69 @@
 // relating to use of the SAFE Network Software.
 
 use chunk_store::ChunkStore;
+use config_handler::Config;
 use default_chunk_store;
-use error::InternalError;
+use error::{ClientError, InternalError};
 use maidsafe_utilities::serialisation;
-use routing::{Data, DataRequest, ImmutableData, ImmutableDataType, RequestContent, RequestMessage};
-use vault::RoutingNode;
+use personas::Persona;
+use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
+              PlainData, RequestContent, RequestMessage, ResponseContent};
+use routing_api::RoutingApi;
+use sodiumoxide::crypto::hash::sha512;
+use status::{ChunkStoreStatus, Status};
+use std::u64;
+use types::Action;
+use xor_name::XorName;
 
 pub struct PmidNode {
+    // Not limited itself, as the space we offer can change while we're running.
     chunk_store: ChunkStore,
+    max_space: u64,
 }
 
 impl PmidNode {
-    pub fn new() -> Result<PmidNode, InternalError> {
+    pub fn new(config: &Config) -> Result<PmidNode, InternalError> {
+        let data_dir = config.data_dir();
         Ok(PmidNode {
-            // TODO allow adjustable max_disk_space and return meaningful error rather than panic
-            // if the ChunkStore creation fails.
-            // See https://maidsafe.atlassian.net/browse/MAID-1189
-            chunk_store: try!(default_chunk_store::new()),
+            chunk_store: try!(default_chunk_store::new(data_dir.as_ref().map(|dir| dir.as_path()),
+                                                       "pmid_node",
+                                                       u64::MAX)),
+            max_space: config.pmid_node_max_space(),
         })
     }
 
-    pub fn handle_get(&mut self,
-                      routing_node: &RoutingNode,
-                      request: &RequestMessage)
-                      -> Result<(), InternalError> {
+    /// Deletes our copy of data we're no longer one of the holders of.
+    pub fn handle_delete(&mut self,
+                         request: &RequestMessage)
+                         -> Result<Vec<Action>, InternalError> {
+        let data_name = match request.content {
+            RequestContent::Delete(Data::Immutable(ref data), _) => data.name(),
+            _ => unreachable!("Error in vault demuxing"),
+        };
+        info!("pmid_node {:?} deleting {:?}", request.dst.name(), data_name);
+        if self.chunk_store.has_chunk(&data_name) {
+            try!(self.chunk_store.delete(&data_name));
+        }
+        Ok(vec![])
+    }
+
+    /// Tells our PmidManagers how much space we offer, so they don't send us more than we can hold.
+    /// It's sent when we join the network, and again whenever the limit in our config changes.
+    pub fn register_offered_space(&self,
+                                  routing_node: &RoutingApi)
+                                  -> Result<Vec<Action>, InternalError> {
+        let our_name = try!(routing_node.name());
+        let offered_space = self.max_space;
+        debug!("pmid_node {:?} offering {} bytes", our_name, offered_space);
+        let value = try!(serialisation::serialise(&offered_space));
+        let src = Authority::ManagedNode(our_name.clone());
+        let dst = Authority::NodeManager(our_name.clone());
+        let content = RequestContent::Post(Data::Plain(PlainData::new(our_name, value)),
+                                           MessageId::new());
+        Ok(vec![Action::request(src, dst, content)])
+    }
+
+    pub fn handle_get(&mut self, request: &RequestMessage) -> Result<Vec<Action>, InternalError> {
         let (data_name, message_id) = match &request.content {
             &RequestContent::Get(DataRequest::Immutable(ref name, _), ref message_id) => {
                 (name, message_id)
@@ -47,48 +86,58 @@ impl PmidNode {
             _ => unreachable!("Error in vault demuxing"),
         };
 
-        let data = try!(self.chunk_store.get(data_name));
-        let decoded = try!(serialisation::deserialise::<ImmutableData>(&data));
+        // If we can't return the data, say so straight away so the managers can mark us as failed
+        let decoded = match self.fetch(data_name) {
+            Some(decoded) => decoded,
+            None => {
+                let error = ClientError::NoSuchData;
+                let failure = try!(Self::get_failure(request.clone(), message_id.clone(), &error));
+                return Ok(vec![failure]);
+            }
+        };
         debug!("As {:?} sending data {:?} to {:?}",
                request.dst,
                Data::Immutable(decoded.clone()),
                request.src);
-        let _ = routing_node.send_get_success(request.dst.clone(),
-                                              request.src.clone(),
-                                              Data::Immutable(decoded),
-                                              message_id.clone());
-        Ok(())
+        let content = ResponseContent::GetSuccess(Data::Immutable(decoded), message_id.clone());
+        Ok(vec![Action::response(request.dst.clone(), request.src.clone(), content)])
     }
 
-    pub fn handle_put(&mut self, request: &RequestMessage) -> Result<(), InternalError> {
-        let data = match request.content {
-            RequestContent::Put(Data::Immutable(ref data), _) => data.clone(),
+    pub fn handle_put(&mut self, request: &RequestMessage) -> Result<Vec<Action>, InternalError> {
+        let (data, message_id) = match request.content {
+            RequestContent::Put(Data::Immutable(ref data), ref message_id) => {
+                (data.clone(), message_id.clone())
+            }
             _ => unreachable!("Error in vault demuxing"),
         };
         let data_name = data.name();
         info!("pmid_node {:?} storing {:?}", request.dst.name(), data_name);
         let serialised_data = try!(serialisation::serialise(&data));
-        if self.chunk_store.has_space(serialised_data.len() as u64) {
+        if self.has_space(serialised_data.len() as u64) {
             // the type_tag needs to be stored as well
             // TODO: error handling
             try!(self.chunk_store.put(&data_name, &serialised_data));
-            return Ok(());
+            // Sacrificial copies count towards the farming rate, so their managers are told
+            if *data.get_type_tag() == ImmutableDataType::Sacrificial {
+                return Ok(vec![try!(Self::put_success(request, data_name, message_id))]);
+            }
+            return Ok(vec![]);
         }
 
         // If we can't store the data and it's a Backup or Sacrificial copy, just notify PmidManager
         // to update the account - replication shall not be carried out for it.
         if *data.get_type_tag() != ImmutableDataType::Normal {
-            // self.notify_managers_of_sacrifice(our_authority, data, response_token);
-            return Ok(());
+            let error = ClientError::NotEnoughSpace;
+            return Ok(vec![try!(Self::put_failure(request.clone(), message_id, &error))]);
         }
 
         // If we can't store the data and it's a Normal copy, try to make room for it by clearing
         // out Sacrificial chunks.
         let required_space = serialised_data.len() -
-                             (self.chunk_store.max_space() -
-                              self.chunk_store.used_space()) as usize;
+                             self.max_space.saturating_sub(self.chunk_store.used_space()) as usize;
         let names = self.chunk_store.names();
         let mut emptied_space = 0;
+        let mut actions = vec![];
         for name in names.iter() {
             let fetched_data = match self.chunk_store.get(name) {
                 Ok(data) => data,
@@ -111,10 +160,11 @@ impl PmidNode {
                     // For sacrificed data, just notify PmidManager to update the account and
                     // ImmutableDataManager need to adjust its farming rate, replication shall not be carried
                     // out for it.
-                    // self.notify_managers_of_sacrifice(&our_authority, parsed_data, &response_token);
+                    actions.push(try!(Self::notify_managers_of_sacrifice(&request.dst,
+                                                                         parsed_data)));
                     if emptied_space > required_space {
                         try!(self.chunk_store.put(&data_name, &serialised_data));
-                        return Ok(());
+                        return Ok(actions);
                     }
                 }
                 _ => {}
@@ -122,85 +172,427 @@ impl PmidNode {
         }
 
         // We failed to make room for it - replication needs to be carried out.
-        // let src = request.dst.clone();
-        // let dst = request.src.clone();
-        // debug!("As {:?} sending Put failure to {:?}", src, dst);
-        // let _ = routing_node.send_put_failure(src, dst, request.clone(), vec![], message_id);  // TODO - set proper error value
-        Ok(())
-    }
-
-    // fn notify_managers_of_sacrifice(&self,
-    //                                 our_authority: &::routing::Authority,
-    //                                 data: ImmutableData,
-    //                                 response_token: &Option<::routing::SignedToken>) {
-    //     let location = Authority::NodeManager(our_authority.name().clone());
-    //     let error =
-    //         ::routing::error::ResponseError::HadToClearSacrificial(data.name(),
-    //                                                                data.payload_size() as u32);
-    //     debug!("As {:?} sacrificing data {:?} freeing space {:?}, notifying {:?}", our_authority,
-    //            data.name(), data.payload_size(), location);
-    //     self.routing.put_response(our_authority.clone(), location, error, response_token.clone());
-    // }
+        let error = ClientError::NotEnoughSpace;
+        actions.push(try!(Self::put_failure(request.clone(), message_id, &error)));
+        Ok(actions)
+    }
+
+    fn has_space(&self, required_space: u64) -> bool {
+        self.chunk_store.used_space().saturating_add(required_space) <= self.max_space
+    }
+
+    // Returns the data if it's held and intact.  A corrupt chunk is removed.
+    fn fetch(&mut self, data_name: &XorName) -> Option<ImmutableData> {
+        let data = match self.chunk_store.get(data_name) {
+            Ok(data) => data,
+            Err(error) => {
+                debug!("Failed to get {:?}: {:?}", data_name, error);
+                return None;
+            }
+        };
+        match serialisation::deserialise::<ImmutableData>(&data) {
+            Ok(decoded) => {
+                if decoded.name() == *data_name {
+                    return Some(decoded);
+                }
+            }
+            Err(_) => (),
+        }
+        warn!("Removing corrupt chunk {:?}", data_name);
+        let _ = self.chunk_store.delete(data_name);
+        None
+    }
+
+    // The notice takes the form of a failure of a Put of the sacrificed data, so that our managers
+    // can handle it in the same way as data we failed to store in the first place.
+    fn notify_managers_of_sacrifice(our_authority: &Authority,
+                                    data: ImmutableData)
+                                    -> Result<Action, InternalError> {
+        let location = Authority::NodeManager(our_authority.name().clone());
+        debug!("As {:?} sacrificing data {:?} freeing space {:?}, notifying {:?}",
+               our_authority,
+               data.name(),
+               data.payload_size(),
+               location);
+        let message_id = MessageId::new();
+        let request = RequestMessage {
+            src: location,
+            dst: our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(data), message_id.clone()),
+        };
+        Self::put_failure(request, message_id, &ClientError::HadToClearSacrificial)
+    }
+
+    fn get_failure(request: RequestMessage,
+                   message_id: MessageId,
+                   error: &ClientError)
+                   -> Result<Action, InternalError> {
+        let src = request.dst.clone();
+        let dst = request.src.clone();
+        debug!("As {:?} sending Get failure to {:?}: {:?}", src, dst, error);
+        let external_error_indicator = try!(serialisation::serialise(error));
+        let content = ResponseContent::GetFailure {
+            id: message_id,
+            request: request,
+            external_error_indicator: external_error_indicator,
+        };
+        Ok(Action::response(src, dst, content))
+    }
+
+    // Goes straight to the data's managers, as our own managers have no use for it.
+    fn put_success(request: &RequestMessage,
+                   data_name: XorName,
+                   message_id: MessageId)
+                   -> Result<Action, InternalError> {
+        let digest = sha512::hash(&try!(serialisation::serialise(request))[..]);
+        let src = request.dst.clone();
+        let dst = Authority::NaeManager(data_name);
+        debug!("As {:?} sending Put success to {:?}", src, dst);
+        Ok(Action::response(src, dst, ResponseContent::PutSuccess(digest, message_id)))
+    }
+
+    fn put_failure(request: RequestMessage,
+                   message_id: MessageId,
+                   error: &ClientError)
+                   -> Result<Action, InternalError> {
+        let src = request.dst.clone();
+        let dst = request.src.clone();
+        debug!("As {:?} sending Put failure to {:?}: {:?}", src, dst, error);
+        let external_error_indicator = try!(serialisation::serialise(error));
+        let content = ResponseContent::PutFailure {
+            id: message_id,
+            request: request,
+            external_error_indicator: external_error_indicator,
+        };
+        Ok(Action::response(src, dst, content))
+    }
 }
 
+impl Persona for PmidNode {
+    fn name(&self) -> &'static str {
+        "pmid_node"
+    }
 
-// #[cfg(all(test, feature = "use-mock-routing"))]
-// mod test {
-// use super::*;
-// use lru_time_cache::LruCache;
-// use maidsafe_utilities::serialisation::serialise;
-// use rand::random;
-// use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, RequestContent, RequestMessage,
-// ResponseContent, ResponseMessage};
-// use std::cmp::{max, min, Ordering};
-// use std::collections::BTreeSet;
-// use time::{Duration, SteadyTime};
-// use types::Refreshable;
-// use utils::{median, merge, HANDLED, NOT_HANDLED};
-// use vault::Routing;
-// use xor_name::{XorName, closer_to_target};
-//
-// #[test]
-// fn handle_put_get() {
-// let routing = ::vault::Routing::new(::std::sync::mpsc::channel().0);
-// let mut pmid_node = PmidNode::new(routing.clone());
-//
-// let us = random();
-// let our_authority = Authority::ManagedNode(us.clone());
-//
-// let from_authority = Authority::NodeManager(us.clone());
-//
-// let value = generate_random_vec_u8(1024);
-// let data =
-// ImmutableData::new(ImmutableDataType::Normal, value);
-// {
-// assert_eq!(::utils::HANDLED,
-// pmid_node.handle_put(&our_authority,
-// &from_authority,
-// &::routing::data::Data::Immutable(data.clone()),
-// &None));
-// assert_eq!(0, routing.put_requests_given().len());
-// assert_eq!(0, routing.put_responses_given().len());
-// }
-// {
-// let from = random();
-// let from_authority = Authority::NaeManager(from.clone());
-//
-// let request =
-// ::routing::data::DataRequest::Immutable(data.name().clone(),
-// ImmutableDataType::Normal);
-//
-// assert_eq!(::utils::HANDLED,
-// pmid_node.handle_get(&our_authority, &from_authority, &request, &None));
-// let get_responses = routing.get_responses_given();
-// assert_eq!(get_responses.len(), 1);
-// assert_eq!(get_responses[0].our_authority, our_authority);
-// assert_eq!(get_responses[0].location, from_authority);
-// assert_eq!(get_responses[0].data,
-// ::routing::data::Data::Immutable(data.clone()));
-// assert_eq!(get_responses[0].data_request, request);
-// assert_eq!(get_responses[0].response_token, None);
-// }
-// }
-// }
-//
+    fn handle_request(&mut self,
+                      _routing_node: &RoutingApi,
+                      request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        match (&request.src, &request.dst, &request.content) {
+            (&Authority::NaeManager(_),
+             &Authority::ManagedNode(_),
+             &RequestContent::Get(DataRequest::Immutable(_, _), _)) => {
+                Some(self.handle_get(request))
+            }
+            (&Authority::NodeManager(_),
+             &Authority::ManagedNode(_),
+             &RequestContent::Put(Data::Immutable(_), _)) => Some(self.handle_put(request)),
+            (&Authority::NodeManager(_),
+             &Authority::ManagedNode(_),
+             &RequestContent::Delete(Data::Immutable(_), _)) => Some(self.handle_delete(request)),
+            _ => None,
+        }
+    }
+
+    fn handle_connected(&mut self,
+                        routing_node: &RoutingApi)
+                        -> Result<Vec<Action>, InternalError> {
+        self.register_offered_space(routing_node)
+    }
+
+    fn handle_config_changed(&mut self,
+                             routing_node: &RoutingApi,
+                             config: &Config)
+                             -> Result<Vec<Action>, InternalError> {
+        let max_space = config.pmid_node_max_space();
+        if max_space == self.max_space {
+            return Ok(vec![]);
+        }
+        info!("pmid_node offered space changed from {} to {} bytes",
+              self.max_space,
+              max_space);
+        self.max_space = max_space;
+        self.register_offered_space(routing_node)
+    }
+
+    fn update_status(&self, status: &mut Status) {
+        status.chunk_stores.push(ChunkStoreStatus {
+            name: "pmid_node".to_owned(),
+            used_space: self.chunk_store.used_space(),
+            max_space: self.max_space,
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config_handler::Config;
+    use error::ClientError;
+    use maidsafe_utilities::serialisation;
+    use mock_routing::MockRoutingNode;
+    use personas::Persona;
+    use rand::random;
+    use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
+                  RequestContent, RequestMessage, ResponseContent};
+    use routing_api::RoutingApi;
+    use std::sync::mpsc;
+    use types::Action;
+    use utils::generate_random_vec_u8;
+    use xor_name::XorName;
+
+    struct Environment {
+        our_authority: Authority,
+        manager_authority: Authority,
+        pmid_node: PmidNode,
+    }
+
+    fn environment_setup(max_space: u64) -> Environment {
+        let us = random::<XorName>();
+        let config = Config { pmid_node_max_space: Some(max_space), ..Config::default() };
+        Environment {
+            our_authority: Authority::ManagedNode(us.clone()),
+            manager_authority: Authority::NodeManager(us),
+            pmid_node: unwrap_result!(PmidNode::new(&config)),
+        }
+    }
+
+    fn put(env: &mut Environment, data: &ImmutableData) -> (RequestMessage, Vec<Action>) {
+        let request = RequestMessage {
+            src: env.manager_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(data.clone()), MessageId::new()),
+        };
+        let actions = unwrap_result!(env.pmid_node.handle_put(&request));
+        (request, actions)
+    }
+
+    // Checks the action is a Put failure for the expected data, sent to our managers.
+    fn check_put_failure(env: &Environment,
+                         action: &Action,
+                         data: &ImmutableData,
+                         expected_error: ClientError) {
+        let response = match *action {
+            Action::SendResponse(ref response) => response,
+            _ => panic!("Unexpected action {:?}", action),
+        };
+        assert_eq!(response.src, env.our_authority);
+        assert_eq!(response.dst, env.manager_authority);
+        match response.content {
+            ResponseContent::PutFailure{ ref request, ref external_error_indicator, .. } => {
+                match request.content {
+                    RequestContent::Put(Data::Immutable(ref failed_data), _) => {
+                        assert_eq!(*failed_data, *data)
+                    }
+                    _ => panic!("Unexpected request {:?}", request),
+                }
+                assert_eq!(unwrap_result!(serialisation::deserialise::<ClientError>(
+                               external_error_indicator)),
+                           expected_error);
+            }
+            _ => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    fn get_request(env: &Environment, data: &ImmutableData) -> RequestMessage {
+        RequestMessage {
+            src: Authority::NaeManager(data.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Get(DataRequest::Immutable(data.name(),
+                                                                data.get_type_tag().clone()),
+                                         MessageId::new()),
+        }
+    }
+
+    // Checks the action is a Get failure for the request, reporting that we don't have the data.
+    fn check_get_failure(env: &Environment, action: &Action, request: &RequestMessage) {
+        let response = match *action {
+            Action::SendResponse(ref response) => response,
+            _ => panic!("Unexpected action {:?}", action),
+        };
+        assert_eq!(response.src, env.our_authority);
+        assert_eq!(response.dst, request.src);
+        match response.content {
+            ResponseContent::GetFailure{ request: ref failed_request,
+                                         ref external_error_indicator,
+                                         .. } => {
+                assert_eq!(*failed_request, *request);
+                assert_eq!(unwrap_result!(serialisation::deserialise::<ClientError>(
+                               external_error_indicator)),
+                           ClientError::NoSuchData);
+            }
+            _ => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn handle_put_get() {
+        let mut env = environment_setup(1_048_576);
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(1024));
+        let (_, actions) = put(&mut env, &data);
+        assert!(actions.is_empty());
+
+        let request = get_request(&env, &data);
+        let from = request.src.clone();
+        let message_id = match request.content {
+            RequestContent::Get(_, ref message_id) => message_id.clone(),
+            _ => unreachable!(),
+        };
+        let actions = unwrap_result!(env.pmid_node.handle_get(&request));
+        assert_eq!(actions,
+                   vec![Action::response(env.our_authority.clone(),
+                                         from,
+                                         ResponseContent::GetSuccess(Data::Immutable(data),
+                                                                     message_id))]);
+    }
+
+    #[test]
+    fn sacrifice_to_store_normal_data() {
+        let mut env = environment_setup(3000);
+
+        // Fill most of the store with Sacrificial copies
+        let sacrificial_data = (0..2)
+                                   .map(|_| {
+                                       ImmutableData::new(ImmutableDataType::Sacrificial,
+                                                          generate_random_vec_u8(1024))
+                                   })
+                                   .collect::<Vec<_>>();
+        // Each is confirmed to its managers, as it counts towards the farming rate
+        for data in &sacrificial_data {
+            let actions = put(&mut env, data).1;
+            assert_eq!(actions.len(), 1);
+            let response = match actions[0] {
+                Action::SendResponse(ref response) => response,
+                ref action => panic!("Unexpected action {:?}", action),
+            };
+            assert_eq!(response.src, env.our_authority);
+            assert_eq!(response.dst, Authority::NaeManager(data.name()));
+            match response.content {
+                ResponseContent::PutSuccess(..) => (),
+                _ => panic!("Unexpected response {:?}", response),
+            }
+        }
+
+        // A Normal copy which doesn't fit displaces one of them, and our managers are told
+        let normal_data = ImmutableData::new(ImmutableDataType::Normal,
+                                             generate_random_vec_u8(1500));
+        let (_, actions) = put(&mut env, &normal_data);
+        assert_eq!(actions.len(), 1);
+        let sacrificed = match actions[0] {
+            Action::SendResponse(ref response) => {
+                match response.content {
+                    ResponseContent::PutFailure{ ref request, .. } => {
+                        match request.content {
+                            RequestContent::Put(Data::Immutable(ref data), _) => data.clone(),
+                            _ => panic!("Unexpected request {:?}", request),
+                        }
+                    }
+                    _ => panic!("Unexpected response {:?}", response),
+                }
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        };
+        assert!(sacrificial_data.contains(&sacrificed));
+        check_put_failure(&env, &actions[0], &sacrificed, ClientError::HadToClearSacrificial);
+        assert!(env.pmid_node.chunk_store.has_chunk(&normal_data.name()));
+        assert!(!env.pmid_node.chunk_store.has_chunk(&sacrificed.name()));
+
+        // A Backup copy which doesn't fit is refused outright
+        let backup_data = ImmutableData::new(ImmutableDataType::Backup,
+                                             generate_random_vec_u8(1500));
+        let (_, actions) = put(&mut env, &backup_data);
+        assert_eq!(actions.len(), 1);
+        check_put_failure(&env, &actions[0], &backup_data, ClientError::NotEnoughSpace);
+        assert!(!env.pmid_node.chunk_store.has_chunk(&backup_data.name()));
+    }
+
+    #[test]
+    fn get_missing_or_corrupt_chunk() {
+        let mut env = environment_setup(1_048_576);
+
+        // Data we never stored
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(1024));
+        let request = get_request(&env, &data);
+        let actions = unwrap_result!(env.pmid_node.handle_get(&request));
+        assert_eq!(actions.len(), 1);
+        check_get_failure(&env, &actions[0], &request);
+
+        // A chunk which can't be parsed is removed
+        unwrap_result!(env.pmid_node.chunk_store.put(&data.name(), &generate_random_vec_u8(100)));
+        let actions = unwrap_result!(env.pmid_node.handle_get(&request));
+        assert_eq!(actions.len(), 1);
+        check_get_failure(&env, &actions[0], &request);
+        assert!(!env.pmid_node.chunk_store.has_chunk(&data.name()));
+
+        // As is one holding the wrong data
+        let other_data = ImmutableData::new(ImmutableDataType::Normal,
+                                            generate_random_vec_u8(1024));
+        let serialised_other_data = unwrap_result!(serialisation::serialise(&other_data));
+        unwrap_result!(env.pmid_node.chunk_store.put(&data.name(), &serialised_other_data));
+        let actions = unwrap_result!(env.pmid_node.handle_get(&request));
+        assert_eq!(actions.len(), 1);
+        check_get_failure(&env, &actions[0], &request);
+        assert!(!env.pmid_node.chunk_store.has_chunk(&data.name()));
+    }
+
+    #[test]
+    fn handle_delete() {
+        let mut env = environment_setup(1_048_576);
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(1024));
+        let _ = put(&mut env, &data);
+        assert!(env.pmid_node.chunk_store.has_chunk(&data.name()));
+
+        let request = RequestMessage {
+            src: env.manager_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Delete(Data::Immutable(data.clone()), MessageId::new()),
+        };
+        assert!(unwrap_result!(env.pmid_node.handle_delete(&request)).is_empty());
+        assert!(!env.pmid_node.chunk_store.has_chunk(&data.name()));
+
+        // Deleting it again is harmless
+        assert!(unwrap_result!(env.pmid_node.handle_delete(&request)).is_empty());
+    }
+
+    #[test]
+    fn register_changed_offered_space() {
+        let routing = unwrap_result!(MockRoutingNode::new(mpsc::channel().0));
+        let our_name = unwrap_result!(routing.name());
+        let mut env = environment_setup(1024);
+
+        // Too big for the space we offer to begin with
+        let data = ImmutableData::new(ImmutableDataType::Backup, generate_random_vec_u8(1500));
+        let (_, actions) = put(&mut env, &data);
+        assert_eq!(actions.len(), 1);
+        check_put_failure(&env, &actions[0], &data, ClientError::NotEnoughSpace);
+
+        // An unchanged limit isn't registered again
+        let config = Config { pmid_node_max_space: Some(1024), ..Config::default() };
+        assert!(unwrap_result!(env.pmid_node.handle_config_changed(&routing, &config)).is_empty());
+
+        // A changed one is, and applies to the next Put
+        let config = Config { pmid_node_max_space: Some(4096), ..Config::default() };
+        let actions = unwrap_result!(env.pmid_node.handle_config_changed(&routing, &config));
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Action::SendRequest(ref request) => {
+                assert_eq!(request.src, Authority::ManagedNode(our_name.clone()));
+                assert_eq!(request.dst, Authority::NodeManager(our_name.clone()));
+                match request.content {
+                    RequestContent::Post(Data::Plain(ref data), _) => {
+                        assert_eq!(data.name(), our_name);
+                        let offered_space: u64 =
+                            unwrap_result!(serialisation::deserialise(data.value()));
+                        assert_eq!(offered_space, 4096);
+                    }
+                    _ => panic!("Unexpected request {:?}", request),
+                }
+            }
+            _ => panic!("Unexpected action {:?}", actions[0]),
+        }
+
+        let (_, actions) = put(&mut env, &data);
+        assert_eq!(actions.len(), 1);
+        assert!(env.pmid_node.chunk_store.has_chunk(&data.name()));
+    }
+}
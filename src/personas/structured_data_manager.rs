@@ -16,32 +16,38 @@
 // relating to use of the SAFE Network Software.
 
 use chunk_store::ChunkStore;
+use config_handler::Config;
 use default_chunk_store;
 use error::{ClientError, InternalError};
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, DataRequest, RequestContent, RequestMessage, StructuredData};
+use personas::Persona;
+use routing::{Authority, Data, DataRequest, RequestContent, RequestMessage, ResponseContent,
+              StructuredData};
+use routing_api::RoutingApi;
 use sodiumoxide::crypto::hash::sha512;
-use types::{Refresh, RefreshValue};
-use vault::RoutingNode;
+use status::{ChunkStoreStatus, Status};
+use types::{Action, Refresh, RefreshValue};
+use utils;
+use xor_name::XorName;
 
 pub struct StructuredDataManager {
     chunk_store: ChunkStore,
 }
 
 impl StructuredDataManager {
-    pub fn new() -> StructuredDataManager {
-        StructuredDataManager {
-            // TODO allow adjustable max_disk_space and return meaningful error rather than panic
-            // if the ChunkStore creation fails.
-            // See https://maidsafe.atlassian.net/browse/MAID-1370
-            chunk_store: default_chunk_store::new().unwrap(),
-        }
+    pub fn new(config: &Config) -> Result<StructuredDataManager, InternalError> {
+        let data_dir = config.data_dir();
+        let max_space = config.structured_data_manager_max_space();
+        Ok(StructuredDataManager {
+            chunk_store: try!(default_chunk_store::new(data_dir.as_ref().map(|dir| dir.as_path()),
+                                                       "structured_data_manager",
+                                                       max_space)),
+        })
     }
 
     pub fn handle_get(&mut self,
-                      routing_node: &RoutingNode,
                       request: &RequestMessage)
-                      -> Result<(), InternalError> {
+                      -> Result<Vec<Action>, InternalError> {
         // TODO - handle type_tag from name too
         let (data_name, message_id) = match request.content {
             RequestContent::Get(ref data_request @ DataRequest::Structured(_, _),
@@ -55,28 +61,27 @@ impl StructuredDataManager {
                        request.dst,
                        Data::Structured(decoded.clone()),
                        request.src);
-                let _ = routing_node.send_get_success(request.dst.clone(),
-                                                      request.src.clone(),
-                                                      Data::Structured(decoded),
-                                                      message_id.clone());
-                return Ok(());
+                let content = ResponseContent::GetSuccess(Data::Structured(decoded),
+                                                          message_id.clone());
+                return Ok(vec![Action::response(request.dst.clone(),
+                                                request.src.clone(),
+                                                content)]);
             }
         }
 
         let error = ClientError::NoSuchData;
         let external_error_indicator = try!(serialisation::serialise(&error));
-        try!(routing_node.send_get_failure(request.dst.clone(),
-                                           request.src.clone(),
-                                           request.clone(),
-                                           external_error_indicator,
-                                           message_id.clone()));
-        Ok(())
+        let content = ResponseContent::GetFailure {
+            id: message_id.clone(),
+            request: request.clone(),
+            external_error_indicator: external_error_indicator,
+        };
+        Ok(vec![Action::response(request.dst.clone(), request.src.clone(), content)])
     }
 
     pub fn handle_put(&mut self,
-                      routing_node: &RoutingNode,
                       request: &RequestMessage)
-                      -> Result<(), InternalError> {
+                      -> Result<Vec<Action>, InternalError> {
         // Take a hash of the message anticipating sending this as a success response to the MM.
         let message_hash = sha512::hash(&try!(serialisation::serialise(request))[..]);
 
@@ -95,23 +100,22 @@ impl StructuredDataManager {
             debug!("Already have SD {:?}", data_name);
             let error = ClientError::DataExists;
             let external_error_indicator = try!(serialisation::serialise(&error));
-            let _ = routing_node.send_put_failure(response_src,
-                                                  response_dst,
-                                                  request.clone(),
-                                                  external_error_indicator,
-                                                  message_id);
-            return Err(InternalError::Client(error));
+            let content = ResponseContent::PutFailure {
+                id: message_id,
+                request: request.clone(),
+                external_error_indicator: external_error_indicator,
+            };
+            return Ok(vec![Action::response(response_src, response_dst, content)]);
         }
 
         try!(self.chunk_store.put(&data_name, &try!(serialisation::serialise(data))));
-        let _ = routing_node.send_put_success(response_src, response_dst, message_hash, message_id);
-        Ok(())
+        let content = ResponseContent::PutSuccess(message_hash, message_id);
+        Ok(vec![Action::response(response_src, response_dst, content)])
     }
 
     pub fn handle_post(&mut self,
-                       routing_node: &RoutingNode,
                        request: &RequestMessage)
-                       -> Result<(), InternalError> {
+                       -> Result<Vec<Action>, InternalError> {
         let (new_data, message_id) = match &request.content {
             &RequestContent::Post(Data::Structured(ref structured_data), ref message_id) => {
                 (structured_data, message_id)
@@ -131,33 +135,32 @@ impl StructuredDataManager {
                                             .put(&existing_data.name(), &serialised_data) {
                             if let Ok(serialised_request) = serialisation::serialise(request) {
                                 let digest = sha512::hash(&serialised_request[..]);
-                                let _ = routing_node.send_post_success(request.dst.clone(),
-                                                                       request.src.clone(),
-                                                                       digest,
-                                                                       message_id.clone());
-                                return Ok(());
+                                let content = ResponseContent::PostSuccess(digest,
+                                                                           message_id.clone());
+                                return Ok(vec![Action::response(request.dst.clone(),
+                                                                request.src.clone(),
+                                                                content)]);
                             }
                         }
                     }
                 } else {
-                    return Ok(());
+                    return Ok(vec![]);
                 }
             }
         }
 
-        try!(routing_node.send_post_failure(request.dst.clone(),
-                                            request.src.clone(),
-                                            request.clone(),
-                                            Vec::new(),
-                                            message_id.clone()));
-        Ok(())
+        let content = ResponseContent::PostFailure {
+            id: message_id.clone(),
+            request: request.clone(),
+            external_error_indicator: Vec::new(),
+        };
+        Ok(vec![Action::response(request.dst.clone(), request.src.clone(), content)])
     }
 
     /// The structured_data in the delete request must be a valid updating version of the target
     pub fn handle_delete(&mut self,
-                         routing_node: &RoutingNode,
                          request: &RequestMessage)
-                         -> Result<(), InternalError> {
+                         -> Result<Vec<Action>, InternalError> {
         let (data, message_id) = match request.content {
             RequestContent::Delete(Data::Structured(ref data), ref message_id) => {
                 (data.clone(), message_id.clone())
@@ -174,23 +177,23 @@ impl StructuredDataManager {
                     if let Ok(()) = self.chunk_store.put(&data.name(), &[]) {
                         if let Ok(serialised_request) = serialisation::serialise(request) {
                             let digest = sha512::hash(&serialised_request[..]);
-                            let _ = routing_node.send_delete_success(request.dst.clone(),
-                                                                     request.src.clone(),
-                                                                     digest,
-                                                                     message_id.clone());
-                            return Ok(());
+                            let content = ResponseContent::DeleteSuccess(digest,
+                                                                         message_id.clone());
+                            return Ok(vec![Action::response(request.dst.clone(),
+                                                            request.src.clone(),
+                                                            content)]);
                         }
                     }
                 }
             }
         }
 
-        try!(routing_node.send_delete_failure(request.dst.clone(),
-                                              request.src.clone(),
-                                              request.clone(),
-                                              Vec::new(),
-                                              message_id.clone()));
-        Ok(())
+        let content = ResponseContent::DeleteFailure {
+            id: message_id.clone(),
+            request: request.clone(),
+            external_error_indicator: Vec::new(),
+        };
+        Ok(vec![Action::response(request.dst.clone(), request.src.clone(), content)])
     }
 
     pub fn handle_refresh(&mut self, structured_data: StructuredData) -> Result<(), InternalError> {
@@ -199,31 +202,143 @@ impl StructuredDataManager {
                                      &try!(serialisation::serialise(&structured_data)))))
     }
 
-    pub fn handle_churn(&mut self, routing_node: &RoutingNode) {
-        let data_names = self.chunk_store.names();
-        for data_name in data_names {
-            let serialised_data = match self.chunk_store.get(&data_name) {
-                Ok(data) => data,
-                _ => continue,
-            };
+    fn data_refresh(&self, data_name: &XorName) -> Option<Action> {
+        let serialised_data = match self.chunk_store.get(data_name) {
+            Ok(data) => data,
+            _ => return None,
+        };
+
+        let structured_data = match serialisation::deserialise::<StructuredData>(&serialised_data) {
+            Ok(parsed_data) => parsed_data,
+            Err(_) => return None,
+        };
+
+        let src = Authority::NaeManager(data_name.clone());
+        let refresh = Refresh::new(data_name, RefreshValue::StructuredDataManager(structured_data));
+        debug!("SD Manager sending refresh for account {:?}", src.name());
+        Some(Action::SendRefresh(src, refresh))
+    }
+}
+
+impl Persona for StructuredDataManager {
+    fn name(&self) -> &'static str {
+        "structured_data_manager"
+    }
+
+    fn handle_request(&mut self,
+                      _routing_node: &RoutingApi,
+                      request: &RequestMessage)
+                      -> Option<Result<Vec<Action>, InternalError>> {
+        match (&request.src, &request.dst, &request.content) {
+            (&Authority::Client{ .. },
+             &Authority::NaeManager(_),
+             &RequestContent::Get(DataRequest::Structured(_, _), _)) => {
+                Some(self.handle_get(request))
+            }
+            (&Authority::ClientManager(_),
+             &Authority::NaeManager(_),
+             &RequestContent::Put(Data::Structured(_), _)) => {
+                Some(self.handle_put(request))
+            }
+            (&Authority::Client{ .. },
+             &Authority::NaeManager(_),
+             &RequestContent::Post(Data::Structured(_), _)) => {
+                Some(self.handle_post(request))
+            }
+            (&Authority::Client{ .. },
+             &Authority::NaeManager(_),
+             &RequestContent::Delete(Data::Structured(_), _)) => {
+                Some(self.handle_delete(request))
+            }
+            _ => None,
+        }
+    }
 
-            let structured_data =
-                match serialisation::deserialise::<StructuredData>(&serialised_data) {
-                    Ok(parsed_data) => parsed_data,
-                    Err(_) => continue,
-                };
-
-            let src = Authority::NaeManager(data_name.clone());
-            let refresh = Refresh::new(&data_name,
-                                       RefreshValue::StructuredDataManager(structured_data));
-            if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
-                debug!("SD Manager sending refresh for account {:?}", src.name());
-                let _ = routing_node.send_refresh_request(src, serialised_refresh);
+    fn handle_refresh_request(&mut self,
+                              _routing_node: &RoutingApi,
+                              src: &Authority,
+                              dst: &Authority,
+                              _sender: &XorName,
+                              refresh: &Refresh)
+                              -> Option<Result<Vec<Action>, InternalError>> {
+        match (src, dst, &refresh.value) {
+            (&Authority::NaeManager(_),
+             &Authority::NaeManager(_),
+             &RefreshValue::StructuredDataManager(ref structured_data)) => {
+                Some(self.handle_refresh(structured_data.clone()).map(|()| vec![]))
             }
+            _ => None,
         }
     }
+
+    fn handle_churn(&mut self, _routing_node: &RoutingApi) -> Vec<Action> {
+        self.chunk_store
+            .names()
+            .iter()
+            .filter_map(|data_name| self.data_refresh(data_name))
+            .collect()
+    }
+
+    fn handle_churn_of(&mut self,
+                       routing_node: &RoutingApi,
+                       churned_node: &XorName)
+                       -> Vec<Action> {
+        self.chunk_store
+            .names()
+            .iter()
+            .filter(|data_name| utils::is_affected_by_churn(routing_node, data_name, churned_node))
+            .filter_map(|data_name| self.data_refresh(data_name))
+            .collect()
+    }
+
+    fn update_status(&self, status: &mut Status) {
+        status.structured_data_manager_chunk_count = self.chunk_store.names().len();
+        status.chunk_stores.push(ChunkStoreStatus::new("structured_data_manager",
+                                                       &self.chunk_store));
+    }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config_handler::Config;
+    use maidsafe_utilities::serialisation;
+    use personas::Persona;
+    use rand::random;
+    use routing::StructuredData;
+    use utils;
+    use worker::send_actions;
+    use xor_name::XorName;
+
+    #[test]
+    fn churn_refreshes_affected_data() {
+        let mut structured_data_manager =
+            unwrap_result!(StructuredDataManager::new(&Config::default()));
+        let churn = utils::churn_environment();
+        let data = unwrap_result!(StructuredData::new(0,
+                                                      random::<XorName>(),
+                                                      0,
+                                                      vec![],
+                                                      vec![],
+                                                      vec![],
+                                                      None));
+        let serialised_data = unwrap_result!(serialisation::serialise(&data));
+        // Stored under names chosen for their distance from ours, rather than the data's own
+        for data_name in churn.near_names.iter().chain(Some(&churn.far_name)) {
+            unwrap_result!(structured_data_manager.chunk_store.put(data_name, &serialised_data));
+        }
+
+        // Churn within our close group refreshes the data we share with it
+        send_actions(&churn.routing,
+                     structured_data_manager.handle_churn_of(&churn.routing, &churn.member));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 3);
+
+        // Churn elsewhere refreshes nothing
+        send_actions(&churn.routing,
+                     structured_data_manager.handle_churn_of(&churn.routing, &churn.far_name));
+        assert_eq!(churn.routing.refresh_requests_given().len(), 3);
+    }
+}
 
 // #[cfg(all(test, feature = "use-mock-routing"))]
 // mod test {
@@ -0,0 +1,172 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Collects the refreshes sent for each account by the members of its group, so that an account is
+//! only taken over once enough of them agree on it.  See `docs/account_transfer.md`.
+
+use clock::Clock;
+use routing::Authority;
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::{Duration, SteadyTime};
+use types::{Action, Refresh, RefreshValue};
+use xor_name::XorName;
+
+/// A majority of a close group of eight.
+pub const DEFAULT_QUORUM: usize = 5;
+// How long the refreshes for an account are collected for
+const TIMEOUT_SECS: i64 = 30;
+
+/// What to do after adding a refresh.
+#[derive(Debug, PartialEq)]
+pub enum Accumulated<T> {
+    /// Not enough members agree yet.
+    Pending,
+    /// Enough members agree on this value for it to be taken over.
+    Agreed(T),
+    /// Members disagree; the group should be asked to send their refreshes again.
+    Conflicted,
+}
+
+struct Entry<T> {
+    // <Group member, the value it sent>
+    values: HashMap<XorName, T>,
+    accepted: Option<T>,
+    conflicted: bool,
+    started: SteadyTime,
+}
+
+pub struct RefreshAccumulator<T> {
+    quorum: usize,
+    clock: Arc<Clock>,
+    // <Account name, the refreshes received for it>
+    entries: HashMap<XorName, Entry<T>>,
+}
+
+impl<T: Clone + Eq> RefreshAccumulator<T> {
+    pub fn new(quorum: usize, clock: Arc<Clock>) -> RefreshAccumulator<T> {
+        RefreshAccumulator {
+            quorum: quorum,
+            clock: clock,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Adds `sender`'s value for the account.  A value is agreed once `quorum` members have sent
+    /// it, and later refreshes carrying the same value are ignored.  A differing value is reported
+    /// as a conflict once per round; if the value had already been agreed, a new round is started.
+    pub fn add(&mut self, name: XorName, sender: XorName, value: T) -> Accumulated<T> {
+        let mut conflicted = false;
+        let superseded = match self.entries.get(&name) {
+            Some(&Entry { accepted: Some(ref accepted), .. }) => *accepted != value,
+            _ => false,
+        };
+        if superseded {
+            let _ = self.entries.remove(&name);
+            conflicted = true;
+        }
+
+        let now = self.clock.now();
+        let entry = self.entries.entry(name).or_insert_with(|| {
+            Entry {
+                values: HashMap::new(),
+                accepted: None,
+                conflicted: conflicted,
+                started: now,
+            }
+        });
+        if entry.accepted.is_some() {
+            return Accumulated::Pending;
+        }
+
+        let _ = entry.values.insert(sender, value.clone());
+        if entry.values.values().filter(|sent| **sent == value).count() >= self.quorum {
+            entry.accepted = Some(value.clone());
+            return Accumulated::Agreed(value);
+        }
+        if conflicted || (!entry.conflicted && entry.values.values().any(|sent| *sent != value)) {
+            entry.conflicted = true;
+            return Accumulated::Conflicted;
+        }
+        Accumulated::Pending
+    }
+
+    /// Drops the refreshes collected too long ago.
+    pub fn expire(&mut self) {
+        let deadline = self.clock.now() - Duration::seconds(TIMEOUT_SECS);
+        self.entries.retain(|_, entry| entry.started > deadline);
+    }
+}
+
+/// Asks the members of `authority`'s group to send `persona`'s refresh for the account again.
+pub fn sync_request(authority: Authority, persona: &str) -> Action {
+    let refresh = Refresh::new(authority.name(),
+                               RefreshValue::SyncRequest(persona.to_owned()));
+    Action::SendRefresh(authority, refresh)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+    use rand::random;
+    use std::sync::Arc;
+    use time::Duration;
+    use xor_name::XorName;
+
+    #[test]
+    fn agree_on_quorum() {
+        let mut accumulator = RefreshAccumulator::new(3, Arc::new(MockClock::new()));
+        let name = random::<XorName>();
+        let sender = random::<XorName>();
+        assert_eq!(accumulator.add(name, sender, 1), Accumulated::Pending);
+        // The same member sending again doesn't count twice
+        assert_eq!(accumulator.add(name, sender, 1), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Agreed(1));
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Pending);
+    }
+
+    #[test]
+    fn conflicting_values() {
+        let mut accumulator = RefreshAccumulator::new(3, Arc::new(MockClock::new()));
+        let name = random::<XorName>();
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 2), Accumulated::Conflicted);
+        // The conflict is only reported once, and the majority can still agree
+        assert_eq!(accumulator.add(name, random(), 2), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Agreed(1));
+
+        // A differing value after agreement starts a new round
+        assert_eq!(accumulator.add(name, random(), 3), Accumulated::Conflicted);
+        assert_eq!(accumulator.add(name, random(), 3), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 3), Accumulated::Agreed(3));
+    }
+
+    #[test]
+    fn expire() {
+        let clock = Arc::new(MockClock::new());
+        let mut accumulator = RefreshAccumulator::new(2, clock.clone());
+        let name = random::<XorName>();
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Pending);
+        clock.advance(Duration::seconds(TIMEOUT_SECS + 1));
+        accumulator.expire();
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Pending);
+        assert_eq!(accumulator.add(name, random(), 1), Accumulated::Agreed(1));
+    }
+}
@@ -0,0 +1,391 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use routing::{Authority, Data, DataRequest, InterfaceError, MessageId, Node, RequestMessage};
+use sodiumoxide::crypto::hash::sha512;
+use std::sync::Mutex;
+use xor_name::XorName;
+
+/// The parts of the routing API used by the vault.  Implemented by the real routing node and by
+/// the mock used in tests, so the vault and personas can be driven by either.
+pub trait RoutingApi {
+    /// Sends a Get request for the given data.
+    fn send_get_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data_request: DataRequest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError>;
+
+    /// Sends a Put request for the given data.
+    fn send_put_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError>;
+
+    /// Sends a Post request for the given data.
+    fn send_post_request(&self,
+                         src: Authority,
+                         dst: Authority,
+                         data: Data,
+                         id: MessageId)
+                         -> Result<(), InterfaceError>;
+
+    /// Sends a Delete request for the given data.
+    fn send_delete_request(&self,
+                           src: Authority,
+                           dst: Authority,
+                           data: Data,
+                           id: MessageId)
+                           -> Result<(), InterfaceError>;
+
+    /// Responds to a Get request with the data.
+    fn send_get_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError>;
+
+    /// Responds to a failed Get request.
+    fn send_get_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError>;
+
+    /// Responds to a successful Put request.
+    fn send_put_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request_hash: sha512::Digest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError>;
+
+    /// Responds to a failed Put request.
+    fn send_put_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError>;
+
+    /// Responds to a successful Post request.
+    fn send_post_success(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request_hash: sha512::Digest,
+                         id: MessageId)
+                         -> Result<(), InterfaceError>;
+
+    /// Responds to a failed Post request.
+    fn send_post_failure(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request: RequestMessage,
+                         external_error_indicator: Vec<u8>,
+                         id: MessageId)
+                         -> Result<(), InterfaceError>;
+
+    /// Responds to a successful Delete request.
+    fn send_delete_success(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request_hash: sha512::Digest,
+                           id: MessageId)
+                           -> Result<(), InterfaceError>;
+
+    /// Responds to a failed Delete request.
+    fn send_delete_failure(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request: RequestMessage,
+                           external_error_indicator: Vec<u8>,
+                           id: MessageId)
+                           -> Result<(), InterfaceError>;
+
+    /// Sends a serialised refresh to the rest of `src`'s group.
+    fn send_refresh_request(&self, src: Authority, content: Vec<u8>) -> Result<(), InterfaceError>;
+
+    /// Returns the close group of `name`, or `None` if we aren't in it.
+    fn close_group(&self, name: XorName) -> Result<Option<Vec<XorName>>, InterfaceError>;
+
+    /// Returns our own name.
+    fn name(&self) -> Result<XorName, InterfaceError>;
+}
+
+impl RoutingApi for Node {
+    fn send_get_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data_request: DataRequest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        Node::send_get_request(self, src, dst, data_request, id)
+    }
+
+    fn send_put_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        Node::send_put_request(self, src, dst, data, id)
+    }
+
+    fn send_post_request(&self,
+                         src: Authority,
+                         dst: Authority,
+                         data: Data,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
+        Node::send_post_request(self, src, dst, data, id)
+    }
+
+    fn send_delete_request(&self,
+                           src: Authority,
+                           dst: Authority,
+                           data: Data,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
+        Node::send_delete_request(self, src, dst, data, id)
+    }
+
+    fn send_get_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        Node::send_get_success(self, src, dst, data, id)
+    }
+
+    fn send_get_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        Node::send_get_failure(self, src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_put_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request_hash: sha512::Digest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        Node::send_put_success(self, src, dst, request_hash, id)
+    }
+
+    fn send_put_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        Node::send_put_failure(self, src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_post_success(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request_hash: sha512::Digest,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
+        Node::send_post_success(self, src, dst, request_hash, id)
+    }
+
+    fn send_post_failure(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request: RequestMessage,
+                         external_error_indicator: Vec<u8>,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
+        Node::send_post_failure(self, src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_delete_success(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request_hash: sha512::Digest,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
+        Node::send_delete_success(self, src, dst, request_hash, id)
+    }
+
+    fn send_delete_failure(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request: RequestMessage,
+                           external_error_indicator: Vec<u8>,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
+        Node::send_delete_failure(self, src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_refresh_request(&self, src: Authority, content: Vec<u8>) -> Result<(), InterfaceError> {
+        Node::send_refresh_request(self, src, content)
+    }
+
+    fn close_group(&self, name: XorName) -> Result<Option<Vec<XorName>>, InterfaceError> {
+        Node::close_group(self, name)
+    }
+
+    fn name(&self) -> Result<XorName, InterfaceError> {
+        Node::name(self)
+    }
+}
+
+// Locks the routing node for the duration of each call only, so it can be shared between threads.
+impl RoutingApi for Mutex<Box<RoutingApi + Send>> {
+    fn send_get_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data_request: DataRequest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_get_request(src, dst, data_request, id)
+    }
+
+    fn send_put_request(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_put_request(src, dst, data, id)
+    }
+
+    fn send_post_request(&self,
+                         src: Authority,
+                         dst: Authority,
+                         data: Data,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_post_request(src, dst, data, id)
+    }
+
+    fn send_delete_request(&self,
+                           src: Authority,
+                           dst: Authority,
+                           data: Data,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_delete_request(src, dst, data, id)
+    }
+
+    fn send_get_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        data: Data,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_get_success(src, dst, data, id)
+    }
+
+    fn send_get_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock())
+            .send_get_failure(src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_put_success(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request_hash: sha512::Digest,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_put_success(src, dst, request_hash, id)
+    }
+
+    fn send_put_failure(&self,
+                        src: Authority,
+                        dst: Authority,
+                        request: RequestMessage,
+                        external_error_indicator: Vec<u8>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock())
+            .send_put_failure(src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_post_success(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request_hash: sha512::Digest,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_post_success(src, dst, request_hash, id)
+    }
+
+    fn send_post_failure(&self,
+                         src: Authority,
+                         dst: Authority,
+                         request: RequestMessage,
+                         external_error_indicator: Vec<u8>,
+                         id: MessageId)
+                         -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock())
+            .send_post_failure(src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_delete_success(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request_hash: sha512::Digest,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_delete_success(src, dst, request_hash, id)
+    }
+
+    fn send_delete_failure(&self,
+                           src: Authority,
+                           dst: Authority,
+                           request: RequestMessage,
+                           external_error_indicator: Vec<u8>,
+                           id: MessageId)
+                           -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock())
+            .send_delete_failure(src, dst, request, external_error_indicator, id)
+    }
+
+    fn send_refresh_request(&self, src: Authority, content: Vec<u8>) -> Result<(), InterfaceError> {
+        unwrap_result!(self.lock()).send_refresh_request(src, content)
+    }
+
+    fn close_group(&self, name: XorName) -> Result<Option<Vec<XorName>>, InterfaceError> {
+        unwrap_result!(self.lock()).close_group(name)
+    }
+
+    fn name(&self) -> Result<XorName, InterfaceError> {
+        unwrap_result!(self.lock()).name()
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Durable on-disk storage for the personas' account tables.
+
+use error::InternalError;
+use maidsafe_utilities::serialisation;
+use rustc_serialize::{Decodable, Encodable};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+// Bump this whenever the serialised form of any persisted table changes, and have the persona
+// owning that table convert its older form via `load_migrating`.
+//   1: initial format
+//   2: PmidManager accounts record Sacrificial usage and offered space
+const FORMAT_VERSION: u32 = 2;
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct StateFile {
+    version: u32,
+    payload: Vec<u8>,
+}
+
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(dir: PathBuf) -> Result<StateStore, InternalError> {
+        try!(fs::create_dir_all(&dir));
+        Ok(StateStore { dir: dir })
+    }
+
+    /// Reads the table called `name`, returning `None` if it has never been saved.  Only for tables
+    /// whose serialised form has never changed, as one saved by an older vault is read as is.
+    pub fn load<T: Decodable>(&mut self, name: &str) -> Result<Option<T>, InternalError> {
+        self.load_migrating(name, |_, payload| Ok(try!(serialisation::deserialise::<T>(payload))))
+    }
+
+    /// Reads the table called `name`, returning `None` if it has never been saved.  A table saved
+    /// by an older vault is passed to `migrate` along with its format version, to be converted.
+    pub fn load_migrating<T, F>(&mut self,
+                                name: &str,
+                                migrate: F)
+                                -> Result<Option<T>, InternalError>
+        where T: Decodable,
+              F: FnOnce(u32, &[u8]) -> Result<T, InternalError>
+    {
+        let mut file = match File::open(self.path(name)) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(InternalError::Io(error)),
+        };
+        let mut contents = Vec::new();
+        let _ = try!(file.read_to_end(&mut contents));
+        let state_file = try!(serialisation::deserialise::<StateFile>(&contents));
+        if state_file.version > FORMAT_VERSION {
+            return Err(InternalError::UnsupportedStateVersion(state_file.version));
+        }
+        if state_file.version < FORMAT_VERSION {
+            info!("Migrating {} from state format {} to {}",
+                  name,
+                  state_file.version,
+                  FORMAT_VERSION);
+            return Ok(Some(try!(migrate(state_file.version, &state_file.payload))));
+        }
+        Ok(Some(try!(serialisation::deserialise::<T>(&state_file.payload))))
+    }
+
+    /// Writes the table called `name`.  Each worker only saves its persona's tables on a tick
+    /// after the persona has handled something, so this doesn't check for changes itself.
+    pub fn save<T: Encodable>(&mut self, name: &str, value: &T) -> Result<(), InternalError> {
+        self.save_version(name, FORMAT_VERSION, value)
+    }
+
+    /// Writes the table called `name` as if by a vault using format `version`.
+    #[cfg(test)]
+    pub fn save_as_version<T: Encodable>(&mut self,
+                                         name: &str,
+                                         version: u32,
+                                         value: &T)
+                                         -> Result<(), InternalError> {
+        self.save_version(name, version, value)
+    }
+
+    fn save_version<T: Encodable>(&mut self,
+                                  name: &str,
+                                  version: u32,
+                                  value: &T)
+                                  -> Result<(), InternalError> {
+        let state_file = StateFile {
+            version: version,
+            payload: try!(serialisation::serialise(value)),
+        };
+        let contents = try!(serialisation::serialise(&state_file));
+
+        // Write to a temporary file and rename it over the old one, so that a crash part way
+        // through never leaves a truncated table behind.
+        let temp_path = self.dir.join(format!("{}.tmp", name));
+        {
+            let mut file = try!(File::create(&temp_path));
+            try!(file.write_all(&contents));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&temp_path, self.path(name)));
+        self.sync_dir()
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    // Flushes the directory entry, so that the rename itself survives a crash.
+    #[cfg(unix)]
+    fn sync_dir(&self) -> Result<(), InternalError> {
+        Ok(try!(try!(File::open(&self.dir)).sync_all()))
+    }
+
+    // Directories can't be opened as files on other platforms.
+    #[cfg(not(unix))]
+    fn sync_dir(&self) -> Result<(), InternalError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use error::InternalError;
+    use maidsafe_utilities::serialisation;
+    use rustc_serialize::hex::ToHex;
+    use sodiumoxide::crypto::hash::sha512;
+    use sodiumoxide::randombytes;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn temp_dir() -> PathBuf {
+        env::temp_dir().join(format!("safe_vault_state_store_{}",
+                                     randombytes::randombytes(8).to_hex()))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = temp_dir();
+        let mut table = HashMap::new();
+        let _ = table.insert(sha512::hash(b"key").0.to_vec(), 42u64);
+        {
+            let mut state_store = unwrap_result!(StateStore::new(dir.clone()));
+            assert!(unwrap_result!(state_store.load::<HashMap<Vec<u8>, u64>>("table")).is_none());
+            unwrap_result!(state_store.save("table", &table));
+        }
+
+        // A new store over the same directory (i.e. after a restart) sees the saved table.
+        let mut state_store = unwrap_result!(StateStore::new(dir.clone()));
+        assert_eq!(unwrap_result!(state_store.load::<HashMap<Vec<u8>, u64>>("table")),
+                   Some(table));
+        assert!(!dir.join("table.tmp").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn save_replaces_previous_table() {
+        let dir = temp_dir();
+        let mut state_store = unwrap_result!(StateStore::new(dir.clone()));
+        unwrap_result!(state_store.save("table", &1u64));
+        unwrap_result!(state_store.save("table", &2u64));
+        assert_eq!(unwrap_result!(state_store.load::<u64>("table")), Some(2));
+        assert!(!dir.join("table.tmp").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn migrate_older_version() {
+        let dir = temp_dir();
+        let mut state_store = unwrap_result!(StateStore::new(dir.clone()));
+        unwrap_result!(state_store.save_as_version("table", 1, &7u32));
+
+        let loaded = state_store.load_migrating("table", |version, payload| {
+            assert_eq!(version, 1);
+            Ok(unwrap_result!(serialisation::deserialise::<u32>(payload)) as u64 * 2)
+        });
+        assert_eq!(unwrap_result!(loaded), Some(14u64));
+
+        // Tables which haven't changed form are read as is
+        assert_eq!(unwrap_result!(state_store.load::<u32>("table")), Some(7));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let dir = temp_dir();
+        let mut state_store = unwrap_result!(StateStore::new(dir.clone()));
+        {
+            let state_file = super::StateFile {
+                version: super::FORMAT_VERSION + 1,
+                payload: vec![],
+            };
+            let contents = unwrap_result!(serialisation::serialise(&state_file));
+            let mut file = unwrap_result!(File::create(dir.join("table")));
+            unwrap_result!(file.write_all(&contents));
+        }
+
+        match state_store.load::<u64>("table") {
+            Err(InternalError::UnsupportedStateVersion(version)) => {
+                assert_eq!(version, super::FORMAT_VERSION + 1)
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Optional local status server which serves a JSON snapshot of the vault's personas.
+
+use chunk_store::ChunkStore;
+use error::InternalError;
+use local_server::{self, LocalServer};
+use rustc_serialize::json;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, Default, RustcEncodable)]
+pub struct ChunkStoreStatus {
+    pub name: String,
+    pub used_space: u64,
+    pub max_space: u64,
+}
+
+impl ChunkStoreStatus {
+    pub fn new(name: &str, chunk_store: &ChunkStore) -> ChunkStoreStatus {
+        ChunkStoreStatus {
+            name: name.to_owned(),
+            used_space: chunk_store.used_space(),
+            max_space: chunk_store.max_space(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, RustcEncodable)]
+pub struct Status {
+    pub routing_name: Option<String>,
+    pub maid_manager_account_count: usize,
+    pub maid_manager_cached_request_count: usize,
+    pub immutable_data_manager_account_count: usize,
+    pub immutable_data_manager_ongoing_get_count: usize,
+    pub immutable_data_manager_unrecoverable_count: usize,
+    pub immutable_data_manager_farming_rate: u64,
+    pub pmid_manager_account_count: usize,
+    pub structured_data_manager_chunk_count: usize,
+    pub mpid_manager_account_count: usize,
+    pub mpid_manager_inbox_header_count: usize,
+    pub mpid_manager_inbox_used_space: u64,
+    pub mpid_manager_outbox_message_count: usize,
+    pub mpid_manager_outbox_used_space: u64,
+    pub chunk_stores: Vec<ChunkStoreStatus>,
+}
+
+impl Status {
+    /// Adds in the figures from `other`.  Each persona only fills in its own fields, so merging
+    /// the statuses reported by each persona gives the status of the whole vault.
+    pub fn merge(&mut self, other: &Status) {
+        if other.routing_name.is_some() {
+            self.routing_name = other.routing_name.clone();
+        }
+        self.maid_manager_account_count += other.maid_manager_account_count;
+        self.maid_manager_cached_request_count += other.maid_manager_cached_request_count;
+        self.immutable_data_manager_account_count += other.immutable_data_manager_account_count;
+        self.immutable_data_manager_ongoing_get_count +=
+            other.immutable_data_manager_ongoing_get_count;
+        self.immutable_data_manager_unrecoverable_count +=
+            other.immutable_data_manager_unrecoverable_count;
+        self.immutable_data_manager_farming_rate += other.immutable_data_manager_farming_rate;
+        self.pmid_manager_account_count += other.pmid_manager_account_count;
+        self.structured_data_manager_chunk_count += other.structured_data_manager_chunk_count;
+        self.mpid_manager_account_count += other.mpid_manager_account_count;
+        self.mpid_manager_inbox_header_count += other.mpid_manager_inbox_header_count;
+        self.mpid_manager_inbox_used_space += other.mpid_manager_inbox_used_space;
+        self.mpid_manager_outbox_message_count += other.mpid_manager_outbox_message_count;
+        self.mpid_manager_outbox_used_space += other.mpid_manager_outbox_used_space;
+        self.chunk_stores.extend(other.chunk_stores.iter().cloned());
+    }
+}
+
+/// Starts serving the latest `Status` as JSON on `127.0.0.1:<port>`, until the returned server is
+/// dropped.
+pub fn start_server(port: u16, status: Arc<Mutex<Status>>) -> Result<LocalServer, InternalError> {
+    local_server::start("Status", port, "application/json", move || {
+        match json::encode(&*unwrap_result!(status.lock())) {
+            Ok(body) => body,
+            Err(error) => {
+                error!("Failed to encode status: {:?}", error);
+                String::from("{}")
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use local_server;
+    use rustc_serialize::json::Json;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn serve_latest_status() {
+        let status = Arc::new(Mutex::new(Status::default()));
+        let server = unwrap_result!(start_server(0, status.clone()));
+        let address = server.address();
+        let json = unwrap_result!(Json::from_str(&local_server::get(&address)));
+        assert_eq!(json.find("maid_manager_account_count").and_then(Json::as_u64),
+                   Some(0));
+
+        unwrap_result!(status.lock()).maid_manager_account_count = 3;
+        let json = unwrap_result!(Json::from_str(&local_server::get(&address)));
+        assert_eq!(json.find("maid_manager_account_count").and_then(Json::as_u64),
+                   Some(3));
+    }
+}
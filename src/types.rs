@@ -16,7 +16,8 @@
 // relating to use of the SAFE Network Software.
 
 use personas::{immutable_data_manager, maid_manager, pmid_manager, mpid_manager};
-use routing::{PlainData, StructuredData};
+use routing::{Authority, PlainData, RequestContent, RequestMessage, ResponseContent,
+              ResponseMessage, StructuredData};
 use xor_name::XorName;
 
 #[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
@@ -38,8 +39,48 @@ impl Refresh {
 pub enum RefreshValue {
     MaidManagerAccount(maid_manager::Account),
     ImmutableDataManagerAccount(immutable_data_manager::Account),
+    // The farming rate as last resolved by the sending ImmutableDataManager
+    ImmutableDataManagerFarmingRate(u64),
     StructuredDataManager(StructuredData),
     PmidManagerAccount(pmid_manager::Account),
     // mpid_manager: account, outbox messages, inbox headers
     MpidManagerAccount(mpid_manager::Account, Vec<PlainData>, Vec<PlainData>),
+    // Asks the group to send the named persona's refresh for the account again, as the ones
+    // received disagreed
+    SyncRequest(String),
+}
+
+/// A refresh as sent over the network.  Routing only tells us which group a refresh came from, so
+/// the sending member adds its own name.
+#[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct RefreshEnvelope {
+    pub sender: XorName,
+    pub refresh: Refresh,
+}
+
+/// A message which a persona wants sent to the network.  Persona handlers return these rather than
+/// calling into routing themselves; the vault then sends them on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    SendRequest(RequestMessage),
+    SendResponse(ResponseMessage),
+    SendRefresh(Authority, Refresh),
+}
+
+impl Action {
+    pub fn request(src: Authority, dst: Authority, content: RequestContent) -> Action {
+        Action::SendRequest(RequestMessage {
+            src: src,
+            dst: dst,
+            content: content,
+        })
+    }
+
+    pub fn response(src: Authority, dst: Authority, content: ResponseContent) -> Action {
+        Action::SendResponse(ResponseMessage {
+            src: src,
+            dst: dst,
+            content: content,
+        })
+    }
 }
@@ -16,23 +16,33 @@
 // relating to use of the SAFE Network Software.
 
 use config_file_handler;
+#[cfg(test)]
+use mock_routing::MockRoutingNode;
 use routing::Authority;
+use routing_api::RoutingApi;
 use sodiumoxide::crypto::hash::sha512;
-use std::{env, process, sync};
+use std::sync;
 use std::ffi::OsString;
-use xor_name::XorName;
+#[cfg(test)]
+use std::sync::mpsc;
+use xor_name::{self, XorName};
 
-static HANDLE_VERSION: sync::Once = sync::ONCE_INIT;
+static LOG_VERSION: sync::Once = sync::ONCE_INIT;
 
-pub fn handle_version() {
-    HANDLE_VERSION.call_once(|| {
-        let name = config_file_handler::exe_file_stem().unwrap_or(OsString::new());
-        let name_and_version = format!("{} v{}", name.to_string_lossy(), env!("CARGO_PKG_VERSION"));
-        if env::args().any(|arg| arg == "--version") {
-            println!("{}", name_and_version);
-            process::exit(0);
-        }
-        let message = String::from("Running ") + &name_and_version;
+// The size of a close group, as used by routing.  The routing table crate which defines it is only
+// a dependency of mock routing, so it's repeated here and checked against it in the tests.
+const GROUP_SIZE: usize = 8;
+
+/// Returns the executable's name and the crate version.
+pub fn name_and_version() -> String {
+    let name = config_file_handler::exe_file_stem().unwrap_or(OsString::new());
+    format!("{} v{}", name.to_string_lossy(), env!("CARGO_PKG_VERSION"))
+}
+
+/// Logs the name and version, once only.
+pub fn log_version() {
+    LOG_VERSION.call_once(|| {
+        let message = String::from("Running ") + &name_and_version();
         let underline = String::from_utf8(vec!['=' as u8; message.len()]).unwrap();
         info!("\n\n{}\n{}", message, underline);
     });
@@ -45,7 +55,64 @@ pub fn client_name(authority: &Authority) -> XorName {
     }
 }
 
-#[cfg(all(test, feature = "use-mock-routing"))]
+/// Returns whether `churned_node` joining or leaving changes the close group of `name`, and we're
+/// still in that group to refresh it.  A node which has just left is no longer in the group, but
+/// it did belong if it's closer to `name` than one of the members now is.
+pub fn is_affected_by_churn(routing_node: &RoutingApi,
+                            name: &XorName,
+                            churned_node: &XorName)
+                            -> bool {
+    match routing_node.close_group(name.clone()) {
+        Ok(Some(close_group)) => {
+            close_group.len() < GROUP_SIZE || close_group.contains(churned_node) ||
+            close_group.iter()
+                       .any(|member| xor_name::closer_to_target(churned_node, member, name))
+        }
+        _ => false,
+    }
+}
+
+/// Names to churn the accounts of a persona under test with, all relative to a mock routing node.
+#[cfg(test)]
+pub struct ChurnEnvironment {
+    /// Records the refreshes sent once the persona's actions are carried out.
+    pub routing: MockRoutingNode,
+    pub our_name: XorName,
+    /// Another member of our close group.
+    pub member: XorName,
+    /// Three names, including our own, which share our close group.
+    pub near_names: Vec<XorName>,
+    /// A name half the network is closer to than us, so we aren't in its close group.
+    pub far_name: XorName,
+}
+
+#[cfg(test)]
+pub fn churn_environment() -> ChurnEnvironment {
+    let routing = unwrap_result!(MockRoutingNode::new(mpsc::channel().0));
+    let our_name = unwrap_result!(routing.name());
+    let close_group = unwrap_option!(unwrap_result!(routing.close_group(our_name)),
+                                     "We should be close to our own name");
+    let member = unwrap_option!(close_group.into_iter().find(|member| *member != our_name),
+                                "Close group has no other members");
+    let near_names = (0..3)
+                         .map(|i| {
+                             let mut near_name = our_name;
+                             near_name.0[63] ^= i;
+                             near_name
+                         })
+                         .collect();
+    let mut far_name = our_name;
+    far_name.0[0] ^= 0x80;
+    ChurnEnvironment {
+        routing: routing,
+        our_name: our_name,
+        member: member,
+        near_names: near_names,
+        far_name: far_name,
+    }
+}
+
+#[cfg(test)]
 pub fn generate_random_vec_u8(size: usize) -> Vec<u8> {
     use rand::random;
     let mut vec: Vec<u8> = Vec::with_capacity(size);
@@ -54,3 +121,13 @@ pub fn generate_random_vec_u8(size: usize) -> Vec<u8> {
     }
     vec
 }
+
+#[cfg(test)]
+mod test {
+    use kademlia_routing_table;
+
+    #[test]
+    fn group_size_matches_routing() {
+        assert_eq!(super::GROUP_SIZE, kademlia_routing_table::GROUP_SIZE);
+    }
+}
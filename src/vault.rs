@@ -15,332 +15,766 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use ctrlc::CtrlC;
+use clock::{Clock, SystemClock};
+use config_handler::Config;
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, DataRequest, Event, RequestContent, RequestMessage,
-              ResponseContent, ResponseMessage, RoutingMessage};
+use rustc_serialize::hex::ToHex;
+use routing::{Authority, Event, Node, RequestContent, RequestMessage, ResponseMessage};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::thread;
+use std::time::Duration;
 use xor_name::XorName;
 
 use error::InternalError;
+use metrics::Metrics;
+#[cfg(any(test, feature = "use-mock-routing"))]
+use mock_routing::MockRoutingNode;
+use personas::Persona;
 use personas::immutable_data_manager::ImmutableDataManager;
 use personas::maid_manager::MaidManager;
 use personas::mpid_manager::MpidManager;
 use personas::pmid_manager::PmidManager;
 use personas::pmid_node::PmidNode;
 use personas::structured_data_manager::StructuredDataManager;
-use types::{Refresh, RefreshValue};
+use routing_api::RoutingApi;
+use state_store::StateStore;
+use status::{self, Status};
+use types::RefreshEnvelope;
+use worker::{Dispatch, RefreshMessage, SharedRoutingNode, Worker, WorkerEvent, WorkerHandle,
+             WorkerReport};
+
+const STATE_DIR: &'static str = "state";
+// How long to wait for in-flight requests to complete once a stop has been requested.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+// Interval between the ticks delivered to the personas.
+const TICK_INTERVAL_SECS: u64 = 1;
+
+// Events handled by the vault's main loop.
+enum VaultEvent {
+    Routing(Event),
+    // The routing node has dropped its event sender, so no more events will arrive.
+    RoutingDisconnected,
+    Worker(WorkerReport),
+    Tick,
+    ShutdownTimeout,
+}
+
+/// Builds a `Vault`, so it can be run in-process rather than as the `safe_vault` executable.
+pub struct VaultBuilder {
+    config: Config,
+    event_observer: Option<Sender<Event>>,
+    stop_sender: Sender<()>,
+    stop_receiver: Receiver<()>,
+    config_sender: Sender<Config>,
+    config_receiver: Receiver<Config>,
+}
+
+impl VaultBuilder {
+    /// Creates a builder using the default config.
+    pub fn new() -> VaultBuilder {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let (config_sender, config_receiver) = mpsc::channel();
+        VaultBuilder {
+            config: Config::default(),
+            event_observer: None,
+            stop_sender: stop_sender,
+            stop_receiver: stop_receiver,
+            config_sender: config_sender,
+            config_receiver: config_receiver,
+        }
+    }
+
+    /// Sets the config, replacing any data dir set so far.
+    pub fn config(mut self, config: Config) -> VaultBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Sets the directory under which the chunk stores and persona state are kept.
+    pub fn data_dir<P: AsRef<Path>>(mut self, data_dir: P) -> VaultBuilder {
+        self.config.data_dir = Some(data_dir.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    /// Passes a copy of every routing event the vault receives on to `event_observer`.
+    pub fn event_observer(mut self, event_observer: Sender<Event>) -> VaultBuilder {
+        self.event_observer = Some(event_observer);
+        self
+    }
+
+    /// Returns a handle which can be used to stop the vault once it's running.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stop_sender.clone())
+    }
 
-#[cfg(not(all(test, feature = "use-mock-routing")))]
-pub type RoutingNode = ::routing::Node;
+    /// Returns a handle which can be used to give the vault a new config once it's running.
+    pub fn config_handle(&self) -> ConfigHandle {
+        ConfigHandle(self.config_sender.clone())
+    }
 
-#[cfg(all(test, feature = "use-mock-routing"))]
-pub type RoutingNode = ::mock_routing::MockRoutingNode;
+    /// Creates the vault, loading any persisted persona state from the data dir.
+    pub fn build(self) -> Result<Vault, InternalError> {
+        Vault::new(&self.config,
+                   self.event_observer,
+                   self.stop_receiver,
+                   self.config_receiver)
+    }
+}
+
+impl Default for VaultBuilder {
+    fn default() -> VaultBuilder {
+        VaultBuilder::new()
+    }
+}
+
+/// Stops a running vault.  The vault hands off its accounts and finishes in-flight requests
+/// before `Vault::run` returns.
+#[derive(Clone)]
+pub struct StopHandle(Sender<()>);
+
+impl StopHandle {
+    /// Asks the vault to stop, which it notices on its next tick.  Does nothing if the vault has
+    /// already gone.
+    pub fn stop(&self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// Updates the config of a running vault.  Only the settings the personas can apply while
+/// running take effect, currently the PmidNode's max space; the rest need a restart.
+#[derive(Clone)]
+pub struct ConfigHandle(Sender<Config>);
+
+impl ConfigHandle {
+    /// Hands the vault a new config, which it applies on its next tick.  Does nothing if the vault
+    /// has already gone.
+    pub fn update(&self, config: Config) {
+        let _ = self.0.send(config);
+    }
+}
+
+// Bookkeeping for a worker while the vault is running.
+struct RunningWorker {
+    handle: WorkerHandle,
+    // Events sent to the worker which it hasn't yet reported back on.
+    pending_events: usize,
+    idle: bool,
+    status: Status,
+}
 
-#[allow(unused)]
 /// Main struct to hold all personas and Routing instance
 pub struct Vault {
-    immutable_data_manager: ImmutableDataManager,
-    maid_manager: MaidManager,
-    mpid_manager: MpidManager,
-    pmid_manager: PmidManager,
-    pmid_node: PmidNode,
-    structured_data_manager: StructuredDataManager,
-    stop_receiver: Option<Receiver<()>>,
+    workers: Vec<Worker>,
+    running_workers: Vec<RunningWorker>,
+    status: Option<Arc<Mutex<Status>>>,
+    status_port: Option<u16>,
+    // Whether any worker has reported a new status since the snapshot was last rebuilt.
+    status_changed: bool,
+    metrics: Metrics,
+    // Number of refreshes rejected, for any reason, while claiming to be from each node.
+    refresh_rejections: HashMap<XorName, u64>,
+    stopping: bool,
+    stop_receiver: Receiver<()>,
+    config_receiver: Receiver<Config>,
+    use_mock_routing: bool,
     app_event_sender: Option<Sender<Event>>,
 }
 
 impl Vault {
-    pub fn run() {
-        let (stop_sender, stop_receiver) = mpsc::channel();
+    /// Connects to the network, or to mock routing if the config asks for it, and runs the vault
+    /// until it's stopped via its `StopHandle`.
+    pub fn run(&mut self) -> Result<(), InternalError> {
+        if self.use_mock_routing {
+            return self.run_mock();
+        }
+        self.do_run(|routing_sender| {
+            let routing_node: Box<RoutingApi + Send> = Box::new(try!(Node::new(routing_sender)));
+            Ok(routing_node)
+        })
+    }
 
-        // Handle Ctrl+C to properly stop the vault instance.
-        // TODO: this should probably be moved over to main.
-        CtrlC::set_handler(move || {
-            let _ = stop_sender.send(());
-        });
+    #[cfg(any(test, feature = "use-mock-routing"))]
+    fn run_mock(&mut self) -> Result<(), InternalError> {
+        info!("Running over mock routing.");
+        self.do_run(|routing_sender| {
+            let routing_node: Box<RoutingApi + Send> =
+                Box::new(try!(MockRoutingNode::new(routing_sender)));
+            Ok(routing_node)
+        })
+    }
 
-        // TODO - Keep retrying to construct new Vault until returns Ok() rather than using unwrap?
-        let _ = unwrap_result!(unwrap_result!(Vault::new(None, stop_receiver)).do_run());
+    #[cfg(not(any(test, feature = "use-mock-routing")))]
+    fn run_mock(&mut self) -> Result<(), InternalError> {
+        Err(InternalError::MockRoutingUnavailable)
     }
 
-    fn new(app_event_sender: Option<Sender<Event>>,
-           stop_receiver: Receiver<()>)
+    fn new(config: &Config,
+           app_event_sender: Option<Sender<Event>>,
+           stop_receiver: Receiver<()>,
+           config_receiver: Receiver<Config>)
            -> Result<Vault, InternalError> {
         ::sodiumoxide::init();
 
+        let clock: Arc<Clock> = Arc::new(SystemClock);
+        let personas: Vec<Box<Persona>> = vec![Box::new(ImmutableDataManager::new(config,
+                                                                                  clock.clone())),
+                                               Box::new(MaidManager::new(config, clock.clone())),
+                                               Box::new(try!(MpidManager::new(config,
+                                                                              clock.clone()))),
+                                               Box::new(PmidManager::new(config, clock.clone())),
+                                               Box::new(try!(PmidNode::new(config))),
+                                               Box::new(try!(StructuredDataManager::new(config)))];
+
+        // Account tables are only persisted if the vault has been given a data dir.
+        let state_dir = match config.data_dir() {
+            Some(data_dir) => {
+                try!(fs::create_dir_all(&data_dir));
+                Some(data_dir.join(STATE_DIR))
+            }
+            None => {
+                info!("No data dir configured - persona state will not be persisted.");
+                None
+            }
+        };
+
+        let mut workers = Vec::with_capacity(personas.len());
+        for mut persona in personas {
+            let state_store = match state_dir {
+                Some(ref state_dir) => {
+                    let mut state_store = try!(StateStore::new(state_dir.clone()));
+                    try!(persona.load_state(&mut state_store));
+                    Some(state_store)
+                }
+                None => None,
+            };
+            workers.push(Worker::new(persona, state_store));
+        }
+
+        let status = config.status_port.map(|_| Arc::new(Mutex::new(Status::default())));
+
         Ok(Vault {
-            immutable_data_manager: ImmutableDataManager::new(),
-            maid_manager: MaidManager::new(),
-            mpid_manager: MpidManager::new(),
-            pmid_manager: PmidManager::new(),
-            pmid_node: try!(PmidNode::new()),
-            structured_data_manager: StructuredDataManager::new(),
-            stop_receiver: Some(stop_receiver),
+            workers: workers,
+            running_workers: vec![],
+            status: status,
+            status_port: config.status_port,
+            status_changed: false,
+            metrics: try!(Metrics::new(config.metrics_port)),
+            refresh_rejections: HashMap::new(),
+            stopping: false,
+            stop_receiver: stop_receiver,
+            config_receiver: config_receiver,
+            use_mock_routing: config.use_mock_routing(),
             app_event_sender: app_event_sender,
         })
     }
 
-    fn do_run(&mut self) -> Result<(), InternalError> {
+    // Runs the vault against the routing node created by `new_routing_node`, which is given the
+    // sender for routing events.  This lets the vault be run over either real or mock routing.
+    fn do_run<F>(&mut self, new_routing_node: F) -> Result<(), InternalError>
+        where F: FnOnce(Sender<Event>) -> Result<Box<RoutingApi + Send>, InternalError>
+    {
+        // The local servers only run while the vault does, so that once it has stopped, an app
+        // embedding it can start another vault on the same ports.
+        let _status_server = match (self.status_port, self.status.clone()) {
+            (Some(port), Some(status)) => Some(try!(status::start_server(port, status))),
+            _ => None,
+        };
+        let _metrics_server = try!(self.metrics.serve());
+
+        let (vault_event_sender, vault_event_receiver) = mpsc::channel();
         let (routing_sender, routing_receiver) = mpsc::channel();
 
-        let routing_node = try!(RoutingNode::new(routing_sender));
-        let routing_node1 = Arc::new(Mutex::new(Some(routing_node)));
-        let routing_node2 = routing_node1.clone();
-
-        // Take the stop_receiver from self, so we can move it into the stop thread.
-        let stop_receiver = self.stop_receiver.take().unwrap();
+        let routing_node: SharedRoutingNode =
+            Arc::new(Mutex::new(try!(new_routing_node(routing_sender))));
+
+        // Forward routing events to the main event loop.  This thread exits once the routing node
+        // is dropped, as that closes the routing event channel.  If that happens while we're still
+        // running, routing has gone away underneath us, so the main event loop needs telling.
+        let routing_event_sender = vault_event_sender.clone();
+        let _ = thread!("Routing event forwarder", move || {
+            for event in routing_receiver.iter() {
+                if routing_event_sender.send(VaultEvent::Routing(event)).is_err() {
+                    return;
+                }
+            }
+            let _ = routing_event_sender.send(VaultEvent::RoutingDisconnected);
+        });
 
-        // Listen for stop event and destroy the routing node if one is received.  Destroying it
-        // will close the routing event channel, stopping the main event loop.
-        let stop_thread_handle = thread::spawn(move || {
-            let _ = stop_receiver.recv();
-            let _ = routing_node1.lock().unwrap().take();
+        // Start a thread per persona, and forward their reports to the main event loop.  The
+        // forwarding thread exits once all the workers have stopped.
+        let (report_sender, report_receiver) = mpsc::channel();
+        let metrics = self.metrics.clone();
+        for (index, worker) in self.workers.drain(..).enumerate() {
+            self.running_workers.push(RunningWorker {
+                handle: worker.start(index,
+                                     routing_node.clone(),
+                                     metrics.clone(),
+                                     report_sender.clone()),
+                pending_events: 0,
+                idle: true,
+                status: Status::default(),
+            });
+        }
+        drop(report_sender);
+        let report_event_sender = vault_event_sender.clone();
+        let _ = thread!("Worker report forwarder", move || {
+            for report in report_receiver.iter() {
+                if report_event_sender.send(VaultEvent::Worker(report)).is_err() {
+                    break;
+                }
+            }
+        });
 
-            stop_receiver
+        // Deliver a tick to the personas every `TICK_INTERVAL_SECS`.  This thread exits once the
+        // main event loop has finished.
+        let tick_sender = vault_event_sender.clone();
+        let _ = thread!("Vault ticker", move || {
+            loop {
+                thread::sleep(Duration::from_secs(TICK_INTERVAL_SECS));
+                if tick_sender.send(VaultEvent::Tick).is_err() {
+                    break;
+                }
+            }
         });
 
-        for event in routing_receiver.iter() {
-            let routing_node = routing_node2.lock().unwrap();
+        for vault_event in vault_event_receiver.iter() {
+            match vault_event {
+                VaultEvent::Routing(event) => self.on_event(&*routing_node, event),
+                VaultEvent::RoutingDisconnected => {
+                    error!("Lost the routing node - stopping the vault.");
+                    break;
+                }
+                VaultEvent::Worker(report) => self.on_worker_report(report),
+                VaultEvent::Tick => {
+                    // The stop channel is polled here rather than waited on by its own thread, so
+                    // that nothing is left blocked on it if we finish for some other reason.
+                    if !self.stopping && self.stop_requested() {
+                        info!("Vault stopping.  Handing off accounts before leaving.");
+                        self.stopping = true;
+                        self.dispatch(|| WorkerEvent::Churn);
+                        let timeout_sender = vault_event_sender.clone();
+                        let _ = thread!("Vault shutdown timer", move || {
+                            thread::sleep(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS));
+                            let _ = timeout_sender.send(VaultEvent::ShutdownTimeout);
+                        });
+                    }
+                    if let Some(config) = self.new_config() {
+                        let config = Arc::new(config);
+                        self.dispatch(|| WorkerEvent::ConfigChanged(config.clone()));
+                    }
+                    self.dispatch(|| WorkerEvent::Tick);
+                    // The snapshot is rebuilt at most once per tick, however many reports arrive.
+                    if self.status_changed {
+                        self.update_status(&*routing_node);
+                    }
+                }
+                VaultEvent::ShutdownTimeout => {
+                    warn!("Timed out waiting for in-flight requests to complete.");
+                    break;
+                }
+            }
 
-            if routing_node.is_none() {
+            if self.stopping && self.is_idle() {
                 break;
             }
+        }
 
-            let routing_node = routing_node.as_ref().unwrap();
+        // Each worker saves its persona's state once its events run out, so this just waits for
+        // them to finish whatever they're part way through.
+        for running_worker in self.running_workers.drain(..) {
+            if let Some(worker) = running_worker.handle.stop() {
+                self.workers.push(worker);
+            }
+        }
 
-            trace!("Vault {} received an event from routing: {:?}",
-                   unwrap_result!(routing_node.name()),
-                   event);
+        // Dropping the routing node disconnects us from the network.
+        drop(routing_node);
+        self.stopping = false;
 
-            let _ = self.app_event_sender
-                        .clone()
-                        .and_then(|sender| Some(sender.send(event.clone())));
+        Ok(())
+    }
 
-            if let Err(error) = match event {
-                Event::Request(request) => self.on_request(routing_node, request),
-                Event::Response(response) => self.on_response(routing_node, response),
-                Event::NodeAdded(node_added) => self.on_node_added(routing_node, node_added),
-                Event::NodeLost(node_lost) => self.on_node_lost(routing_node, node_lost),
-                Event::Connected => self.on_connected(),
-            } {
-                warn!("Failed to handle event: {:?}", error);
-            }
+    // Returns true if a `StopHandle` has asked us to stop.  If every `StopHandle` has been
+    // dropped, there's nothing to wait for.
+    fn stop_requested(&self) -> bool {
+        match self.stop_receiver.try_recv() {
+            Ok(()) => true,
+            Err(TryRecvError::Empty) |
+            Err(TryRecvError::Disconnected) => false,
         }
+    }
 
-        // Return the stop_receiver back to self, in case we want to call do_run again.
-        self.stop_receiver = Some(stop_thread_handle.join().unwrap());
+    // Returns the latest config given via a `ConfigHandle` since the last tick, if any.
+    fn new_config(&self) -> Option<Config> {
+        let mut config = None;
+        while let Ok(new_config) = self.config_receiver.try_recv() {
+            config = Some(new_config);
+        }
+        config
+    }
 
-        Ok(())
+    fn on_event(&mut self, routing_node: &RoutingApi, event: Event) {
+        trace!("Vault {} received an event from routing: {:?}",
+               unwrap_result!(routing_node.name()),
+               event);
+
+        let _ = self.app_event_sender
+                    .clone()
+                    .and_then(|sender| Some(sender.send(event.clone())));
+
+        if let Err(error) = match event {
+            Event::Request(request) => self.on_request(routing_node, request),
+            Event::Response(response) => self.on_response(response),
+            Event::NodeAdded(node_added) => self.on_node_added(node_added),
+            Event::NodeLost(node_lost) => self.on_node_lost(node_lost),
+            Event::Connected => self.on_connected(),
+        } {
+            warn!("Failed to handle event: {:?}", error);
+        }
     }
 
     fn on_request(&mut self,
-                  routing_node: &RoutingNode,
+                  routing_node: &RoutingApi,
                   request: RequestMessage)
                   -> Result<(), InternalError> {
-        match (&request.src, &request.dst, &request.content) {
-            // ================== Get ==================
-            (&Authority::Client{ .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Get(DataRequest::Immutable(_, _), _)) => {
-                self.immutable_data_manager.handle_get(routing_node, &request)
-            }
-            (&Authority::Client{ .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Get(DataRequest::Structured(_, _), _)) => {
-                self.structured_data_manager.handle_get(routing_node, &request)
-            }
-            (&Authority::NaeManager(_),
-             &Authority::ManagedNode(_),
-             &RequestContent::Get(DataRequest::Immutable(_, _), _)) => {
-                self.pmid_node.handle_get(routing_node, &request)
-            }
-            // ================== Put ==================
-            (&Authority::Client{ .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Immutable(_), _)) |
-            (&Authority::Client{ .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Structured(_), _)) => {
-                self.maid_manager.handle_put(routing_node, &request)
-            }
-            (&Authority::Client{ .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Plain(_), _)) |
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Plain(_), _)) => {
-                self.mpid_manager.handle_put(routing_node, &request)
-            }
-            (&Authority::ClientManager(_),
-             &Authority::NaeManager(_),
-             &RequestContent::Put(Data::Immutable(ref data), ref message_id)) => {
-                self.immutable_data_manager.handle_put(routing_node, data, message_id)
-            }
-            (&Authority::ClientManager(_),
-             &Authority::NaeManager(_),
-             &RequestContent::Put(Data::Structured(_), _)) => {
-                self.structured_data_manager.handle_put(routing_node, &request)
+        if let Authority::Client{ .. } = request.src {
+            if self.stopping {
+                debug!("Vault stopping - dropping client request {:?}", request);
+                return Ok(());
             }
-            (&Authority::NaeManager(_),
-             &Authority::NodeManager(pmid_node_name),
-             &RequestContent::Put(Data::Immutable(ref data), ref message_id)) => {
-                self.pmid_manager.handle_put(routing_node, data, message_id, pmid_node_name)
-            }
-            (&Authority::NodeManager(_),
-             &Authority::ManagedNode(_),
-             &RequestContent::Put(Data::Immutable(_), _)) => {
-                self.pmid_node.handle_put(&request)
-            }
-            // ================== Post ==================
-            (&Authority::Client{ .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Post(Data::Structured(_), _)) => {
-                self.structured_data_manager.handle_post(routing_node, &request)
-            }
-            (&Authority::Client{ .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Post(Data::Plain(_), _)) |
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RequestContent::Post(Data::Plain(_), _)) => {
-                self.mpid_manager.handle_post(routing_node, &request)
-            }
-            // ================== Delete ==================
-            (&Authority::Client{ .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Delete(Data::Plain(_), _)) => {
-                self.mpid_manager.handle_delete(routing_node, &request)
-            }
-            (&Authority::Client{ .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Delete(Data::Structured(_), _)) => {
-                self.structured_data_manager.handle_delete(routing_node, &request)
-            }
-            // ================== Refresh ==================
-            (src, dst, &RequestContent::Refresh(ref serialised_refresh)) => {
-                self.on_refresh(src, dst, serialised_refresh)
-            }
-            // ================== Invalid Request ==================
-            _ => Err(InternalError::UnknownMessageType(RoutingMessage::Request(request.clone()))),
         }
+
+        if let RequestContent::Refresh(ref serialised_refresh) = request.content {
+            // Refreshes are counted per persona by the workers.
+            return self.on_refresh(routing_node, &request.src, &request.dst, serialised_refresh);
+        }
+
+        let dispatch = Dispatch::new(request, self.running_workers.len());
+        self.dispatch(|| WorkerEvent::Request(dispatch.clone()));
+        Ok(())
     }
 
-    fn on_response(&mut self,
-                   routing_node: &RoutingNode,
-                   response: ResponseMessage)
-                   -> Result<(), InternalError> {
-        match (&response.src, &response.dst, &response.content) {
-            // ================== GetSuccess ==================
-            (&Authority::ManagedNode(_),
-             &Authority::NaeManager(_),
-             &ResponseContent::GetSuccess(Data::Immutable(_), _)) => {
-                self.immutable_data_manager.handle_get_success(routing_node, &response)
-            }
-            // ================== GetFailure ==================
-            (&Authority::ManagedNode(ref pmid_node),
-             &Authority::NaeManager(_),
-             &ResponseContent::GetFailure{ ref id, ref request, ref external_error_indicator }) => {
-                self.immutable_data_manager
-                    .handle_get_failure(routing_node,
-                                        pmid_node,
-                                        id,
-                                        request,
-                                        external_error_indicator)
-            }
-            // ================== PutSuccess ==================
-            (&Authority::NaeManager(_),
-             &Authority::ClientManager(_),
-             &ResponseContent::PutSuccess(_, ref message_id)) => {
-                self.maid_manager.handle_put_success(routing_node, message_id)
-            }
-            // ================== PutFailure ==================
-            (&Authority::NaeManager(_),
-             &Authority::ClientManager(_),
-             &ResponseContent::PutFailure{ ref id, ref external_error_indicator, .. }) => {
-                self.maid_manager.handle_put_failure(routing_node, id, external_error_indicator)
-            }
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &ResponseContent::PutFailure{ ref request, .. }) => {
-                self.mpid_manager.handle_put_failure(routing_node, request)
-            }
-            // ================== Invalid Response ==================
-            _ => Err(InternalError::UnknownMessageType(RoutingMessage::Response(response.clone()))),
+    fn on_response(&mut self, response: ResponseMessage) -> Result<(), InternalError> {
+        let dispatch = Dispatch::new(response, self.running_workers.len());
+        self.dispatch(|| WorkerEvent::Response(dispatch.clone()));
+        Ok(())
+    }
+
+    fn on_refresh(&mut self,
+                  routing_node: &RoutingApi,
+                  src: &Authority,
+                  dst: &Authority,
+                  serialised_refresh: &Vec<u8>)
+                  -> Result<(), InternalError> {
+        let envelope = try!(serialisation::deserialise::<RefreshEnvelope>(serialised_refresh));
+        if let Err(error) = validate_refresh(routing_node, src, &envelope) {
+            self.metrics.count_refresh_rejected(match error {
+                InternalError::RefreshSenderNotInCloseGroup(_) => "sender_not_in_close_group",
+                InternalError::NotInCloseGroup => "not_in_close_group",
+                InternalError::RefreshNameMismatch(_) => "name_mismatch",
+                _ => "routing_error",
+            });
+            let rejections = self.refresh_rejections.entry(envelope.sender).or_insert(0);
+            *rejections += 1;
+            warn!("Rejected {} refreshes claiming to be from {:?}",
+                  rejections,
+                  envelope.sender);
+            return Err(error);
         }
+        let message = RefreshMessage {
+            src: src.clone(),
+            dst: dst.clone(),
+            sender: envelope.sender,
+            refresh: envelope.refresh,
+        };
+        let dispatch = Dispatch::new(message, self.running_workers.len());
+        self.dispatch(|| WorkerEvent::Refresh(dispatch.clone()));
+        Ok(())
     }
 
-    fn on_node_added(&mut self,
-                     routing_node: &RoutingNode,
-                     node_added: XorName)
-                     -> Result<(), InternalError> {
-        self.maid_manager.handle_churn(routing_node);
-        self.immutable_data_manager.handle_node_added(routing_node, node_added);
-        self.structured_data_manager.handle_churn(routing_node);
-        self.pmid_manager.handle_churn(routing_node);
-        self.mpid_manager.handle_churn(routing_node);
+    fn on_node_added(&mut self, node_added: XorName) -> Result<(), InternalError> {
+        self.metrics.count_churn("node_added");
+        self.dispatch(|| WorkerEvent::NodeAdded(node_added));
         Ok(())
     }
 
-    fn on_node_lost(&mut self,
-                    routing_node: &RoutingNode,
-                    node_lost: XorName)
-                    -> Result<(), InternalError> {
-        self.maid_manager.handle_churn(routing_node);
-        self.immutable_data_manager.handle_node_lost(routing_node, node_lost);
-        self.structured_data_manager.handle_churn(routing_node);
-        self.pmid_manager.handle_churn(routing_node);
-        self.mpid_manager.handle_churn(routing_node);
+    fn on_node_lost(&mut self, node_lost: XorName) -> Result<(), InternalError> {
+        self.metrics.count_churn("node_lost");
+        let _ = self.refresh_rejections.remove(&node_lost);
+        self.dispatch(|| WorkerEvent::NodeLost(node_lost));
         Ok(())
     }
 
-    fn on_connected(&self) -> Result<(), InternalError> {
-        // TODO: what is expected to be done here?
+    fn on_connected(&mut self) -> Result<(), InternalError> {
         debug!("Vault connected");
-        // assert_eq!(kademlia_routing_table::GROUP_SIZE, self.immutable_data_manager.nodes_in_table_len());
+        self.dispatch(|| WorkerEvent::Connected);
         Ok(())
     }
 
-    fn on_refresh(&mut self,
-                  src: &Authority,
-                  dst: &Authority,
-                  serialised_refresh: &Vec<u8>)
-                  -> Result<(), InternalError> {
-        let refresh = try!(serialisation::deserialise::<Refresh>(serialised_refresh));
-        match (src, dst, &refresh.value) {
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RefreshValue::MaidManagerAccount(ref account)) => {
-                Ok(self.maid_manager.handle_refresh(refresh.name, account.clone()))
-            }
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RefreshValue::MpidManagerAccount(ref account, ref stored_messages, ref received_headers)) => {
-                Ok(self.mpid_manager
-                       .handle_refresh(refresh.name, account, stored_messages, received_headers))
-            }
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &RefreshValue::ImmutableDataManagerAccount(ref account)) => {
-                Ok(self.immutable_data_manager.handle_refresh(refresh.name, account.clone()))
-            }
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &RefreshValue::StructuredDataManager(ref structured_data)) => {
-                self.structured_data_manager.handle_refresh(structured_data.clone())
-            }
-            (&Authority::NodeManager(_),
-             &Authority::NodeManager(_),
-             &RefreshValue::PmidManagerAccount(ref account)) => {
-                Ok(self.pmid_manager.handle_refresh(refresh.name, account.clone()))
-            }
-            _ => Err(InternalError::UnknownRefreshType(src.clone(), dst.clone(), refresh.clone())),
+    fn on_worker_report(&mut self, report: WorkerReport) {
+        let running_worker = &mut self.running_workers[report.index];
+        running_worker.pending_events -= 1;
+        running_worker.idle = report.idle;
+        if let Some(status) = report.status {
+            running_worker.status = status;
+            self.status_changed = true;
+        }
+    }
+
+    // Sends an event created by `new_event` to every worker, each of which decides for itself
+    // whether its persona handles it.  See the `worker` module for what this costs.
+    fn dispatch<F: Fn() -> WorkerEvent>(&mut self, new_event: F) {
+        for running_worker in self.running_workers.iter_mut() {
+            running_worker.handle.send(new_event());
+            running_worker.pending_events += 1;
+        }
+    }
+
+    // Returns true if there are no in-flight requests awaiting responses from the network, and
+    // the workers have caught up with all the events sent to them.
+    fn is_idle(&self) -> bool {
+        self.running_workers
+            .iter()
+            .all(|running_worker| running_worker.pending_events == 0 && running_worker.idle)
+    }
+
+    fn update_status(&mut self, routing_node: &RoutingApi) {
+        self.status_changed = false;
+        // Nothing is watching, so there's no point building a snapshot.
+        if self.status.is_none() && !self.metrics.is_exported() {
+            return;
+        }
+
+        let mut snapshot = Status::default();
+        snapshot.routing_name = routing_node.name().ok().map(|name| name.0.to_hex());
+        for running_worker in self.running_workers.iter() {
+            snapshot.merge(&running_worker.status);
+        }
+
+        for chunk_store in snapshot.chunk_stores.iter() {
+            self.metrics.set_chunk_store_space(&chunk_store.name,
+                                               chunk_store.used_space,
+                                               chunk_store.max_space);
+        }
+
+        if let Some(ref status) = self.status {
+            *unwrap_result!(status.lock()) = snapshot;
         }
     }
 }
 
+// Checks that both we and the sender are in the close group of the refreshed name, so that only the
+// nodes responsible for an account can change it, and that the refresh is for the account of the
+// group routing says it came from.  This is only a close group check: routing authenticates the
+// group, but the sender's name is taken on trust from the envelope.
+fn validate_refresh(routing_node: &RoutingApi,
+                    src: &Authority,
+                    envelope: &RefreshEnvelope)
+                    -> Result<(), InternalError> {
+    let sender = envelope.sender;
+    let close_group = match try!(routing_node.close_group(envelope.refresh.name)) {
+        Some(close_group) => close_group,
+        None => return Err(InternalError::NotInCloseGroup),
+    };
+    // Our own refreshes are delivered back to us too
+    if sender != try!(routing_node.name()) && !close_group.contains(&sender) {
+        return Err(InternalError::RefreshSenderNotInCloseGroup(sender));
+    }
+    if *src.name() != envelope.refresh.name {
+        return Err(InternalError::RefreshNameMismatch(sender));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{STATE_DIR, VaultBuilder, validate_refresh};
+    use config_handler::Config;
+    use error::InternalError;
+    use maidsafe_utilities::serialisation;
+    use mock_routing::MockRoutingNode;
+    use rand::random;
+    use routing::{Authority, Event};
+    use routing_api::RoutingApi;
+    use rustc_serialize::hex::ToHex;
+    use sodiumoxide::randombytes;
+    use std::env;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use types::{Refresh, RefreshEnvelope, RefreshValue};
+    use utils::{ChurnEnvironment, churn_environment};
+    use xor_name::XorName;
+
+    fn envelope(sender: XorName, name: XorName) -> RefreshEnvelope {
+        RefreshEnvelope {
+            sender: sender,
+            refresh: Refresh::new(&name, RefreshValue::SyncRequest("maid_manager".to_owned())),
+        }
+    }
+
+    #[test]
+    fn refresh_must_come_from_close_group() {
+        let ChurnEnvironment { routing, our_name, member, near_names, far_name } =
+            churn_environment();
+        let src = Authority::NaeManager(our_name);
+
+        assert!(validate_refresh(&routing, &src, &envelope(member, our_name)).is_ok());
+        assert!(validate_refresh(&routing, &src, &envelope(our_name, our_name)).is_ok());
+        match validate_refresh(&routing, &src, &envelope(random(), our_name)) {
+            Err(InternalError::RefreshSenderNotInCloseGroup(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        match validate_refresh(&routing,
+                               &Authority::NaeManager(far_name),
+                               &envelope(member, far_name)) {
+            Err(InternalError::NotInCloseGroup) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // A member can't refresh an account other than that of the group it sent from
+        match validate_refresh(&routing,
+                               &Authority::NaeManager(near_names[1]),
+                               &envelope(member, our_name)) {
+            Err(InternalError::RefreshNameMismatch(sender)) => assert_eq!(sender, member),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejected_refreshes_are_counted_per_sender() {
+        let ChurnEnvironment { routing, our_name, member, near_names, .. } = churn_environment();
+        let mut vault = unwrap_result!(VaultBuilder::new().build());
+        let dst = Authority::NaeManager(our_name);
+        let outsider = random();
+        let from_member = unwrap_result!(serialisation::serialise(&envelope(member, our_name)));
+        let from_outsider = unwrap_result!(serialisation::serialise(&envelope(outsider,
+                                                                              our_name)));
+
+        // Every reason for rejecting a refresh counts against its claimed sender
+        let src = Authority::NaeManager(our_name);
+        assert!(vault.on_refresh(&routing, &src, &dst, &from_member).is_ok());
+        assert!(vault.on_refresh(&routing, &src, &dst, &from_outsider).is_err());
+        assert_eq!(vault.refresh_rejections[&outsider], 1);
+        assert!(!vault.refresh_rejections.contains_key(&member));
+        let wrong_src = Authority::NaeManager(near_names[1]);
+        for count in 1..3 {
+            assert!(vault.on_refresh(&routing, &wrong_src, &dst, &from_member).is_err());
+            assert_eq!(vault.refresh_rejections[&member], count);
+        }
+
+        unwrap_result!(vault.on_node_lost(member));
+        assert!(!vault.refresh_rejections.contains_key(&member));
+    }
+
+    #[test]
+    fn run_returns_once_stopped() {
+        let builder = VaultBuilder::new();
+        let stop_handle = builder.stop_handle();
+        let mut vault = unwrap_result!(builder.build());
+        stop_handle.stop();
+        unwrap_result!(vault.do_run(|routing_sender| {
+            let routing_node: Box<RoutingApi + Send> =
+                Box::new(try!(MockRoutingNode::new(routing_sender)));
+            Ok(routing_node)
+        }));
+    }
+
+    #[test]
+    fn config_handle_passes_on_latest_config() {
+        let builder = VaultBuilder::new();
+        let config_handle = builder.config_handle();
+        let vault = unwrap_result!(builder.build());
+        assert_eq!(vault.new_config(), None);
+
+        // Only the latest of the configs given since the last tick is applied
+        config_handle.update(Config { pmid_node_max_space: Some(1024), ..Config::default() });
+        config_handle.update(Config { pmid_node_max_space: Some(4096), ..Config::default() });
+        let config = unwrap_option!(vault.new_config(), "Config should have been passed on");
+        assert_eq!(config.pmid_node_max_space, Some(4096));
+        assert_eq!(vault.new_config(), None);
+    }
+
+    #[test]
+    fn status_port_is_released_once_stopped() {
+        // Find a free port, then release it for the vault to bind.
+        let port = unwrap_result!(unwrap_result!(TcpListener::bind("127.0.0.1:0")).local_addr())
+                       .port();
+        let config = Config {
+            status_port: Some(port),
+            use_mock_routing: Some(true),
+            ..Config::default()
+        };
+        for _ in 0..2 {
+            // The status is only served while the vault is running
+            let builder = VaultBuilder::new().config(config.clone());
+            let stop_handle = builder.stop_handle();
+            let mut vault = unwrap_result!(builder.build());
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+            stop_handle.stop();
+            unwrap_result!(vault.run());
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        }
+    }
+
+    #[test]
+    fn config_selects_mock_routing() {
+        let config = Config { use_mock_routing: Some(true), ..Config::default() };
+        let builder = VaultBuilder::new().config(config);
+        let stop_handle = builder.stop_handle();
+        let mut vault = unwrap_result!(builder.build());
+        stop_handle.stop();
+        unwrap_result!(vault.run());
+    }
+
+    #[test]
+    fn run_returns_if_routing_disconnects() {
+        let builder = VaultBuilder::new();
+        // Never used, but kept alive so the vault has a stop handle it could still hear from.
+        let _stop_handle = builder.stop_handle();
+        let mut vault = unwrap_result!(builder.build());
+        // The routing event sender is dropped straight away, as if routing had died.
+        unwrap_result!(vault.do_run(|_routing_sender| {
+            let routing_node: Box<RoutingApi + Send> =
+                Box::new(try!(MockRoutingNode::new(mpsc::channel().0)));
+            Ok(routing_node)
+        }));
+    }
+
+    #[test]
+    fn builder_uses_data_dir() {
+        let data_dir = env::temp_dir().join(format!("safe_vault_builder_{}",
+                                                    randombytes::randombytes(8).to_hex()));
+        let _ = unwrap_result!(VaultBuilder::new().data_dir(&data_dir).build());
+        assert!(data_dir.join(STATE_DIR).is_dir());
+        let _ = fs::remove_dir_all(data_dir);
+    }
+
+    #[test]
+    fn event_observer_sees_routing_events() {
+        let (observer, observed_events) = mpsc::channel();
+        let builder = VaultBuilder::new().event_observer(observer);
+        let stop_handle = builder.stop_handle();
+        let mut vault = unwrap_result!(builder.build());
+        stop_handle.stop();
+        unwrap_result!(vault.do_run(|routing_sender| {
+            unwrap_result!(routing_sender.send(Event::Connected));
+            let routing_node: Box<RoutingApi + Send> =
+                Box::new(try!(MockRoutingNode::new(routing_sender)));
+            Ok(routing_node)
+        }));
+
+        match observed_events.try_recv() {
+            Ok(Event::Connected) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
 
 
 // #[cfg(all(test, not(feature = "use-mock-routing")))]
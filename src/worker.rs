@@ -0,0 +1,665 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Runs each persona on its own thread, so that e.g. a slow chunk store write in the PmidNode
+//! doesn't hold up MPID messages or StructuredData Gets.  Every event is offered to every worker
+//! in the order it arrived, so each persona still sees the messages for any one account in order.
+//!
+//! There are limits to this:
+//!
+//! - Ordering only holds within a persona.  Two personas handling related messages, e.g. an
+//!   ImmutableDataManager and a PmidManager on the same vault, may act on them in either order,
+//!   and their actions may reach routing interleaved.
+//! - Each worker is sent every event, not just those its persona handles, and declines the rest
+//!   on its own thread.  A worker stuck on a slow event therefore queues up all the traffic behind
+//!   it, and the vault won't finish stopping until every queue has drained.
+//! - Every send goes through the one routing node, whose lock is taken for each call, so sends
+//!   from all the workers are serialised and a slow call into routing holds up all of them.
+
+use config_handler::Config;
+use error::InternalError;
+use maidsafe_utilities::serialisation;
+use metrics::Metrics;
+use personas::Persona;
+use routing::{Authority, RequestContent, RequestMessage, ResponseContent, ResponseMessage,
+              RoutingMessage};
+use routing_api::RoutingApi;
+use state_store::StateStore;
+use status::Status;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+use types::{Action, Refresh, RefreshEnvelope};
+use xor_name::XorName;
+
+/// The routing node, shared between the vault and its workers.  The lock is only held for the
+/// duration of each call into routing.
+pub type SharedRoutingNode = Arc<Mutex<Box<RoutingApi + Send>>>;
+
+/// A message offered to every worker.  The last worker to decline it reports it as unhandled.
+pub struct Dispatch<T> {
+    message: T,
+    declines_left: AtomicUsize,
+}
+
+impl<T> Dispatch<T> {
+    pub fn new(message: T, worker_count: usize) -> Arc<Dispatch<T>> {
+        Arc::new(Dispatch {
+            message: message,
+            declines_left: AtomicUsize::new(worker_count),
+        })
+    }
+
+    // Returns true if every worker has now declined the message.
+    fn decline(&self) -> bool {
+        self.declines_left.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+}
+
+pub struct RefreshMessage {
+    pub src: Authority,
+    pub dst: Authority,
+    pub sender: XorName,
+    pub refresh: Refresh,
+}
+
+pub enum WorkerEvent {
+    Request(Arc<Dispatch<RequestMessage>>),
+    Response(Arc<Dispatch<ResponseMessage>>),
+    Refresh(Arc<Dispatch<RefreshMessage>>),
+    NodeAdded(XorName),
+    NodeLost(XorName),
+    // Send a refresh for every account held, so the group can take them over.
+    Churn,
+    Connected,
+    ConfigChanged(Arc<Config>),
+    Tick,
+}
+
+/// Sent back to the vault once a worker has handled an event.
+pub struct WorkerReport {
+    pub index: usize,
+    pub idle: bool,
+    // The persona's latest status, if the event could have changed it.
+    pub status: Option<Status>,
+}
+
+/// A persona along with the store its state is persisted in.
+pub struct Worker {
+    persona: Box<Persona>,
+    state_store: Option<StateStore>,
+    // Whether the persona handled the current event, rather than declining it.
+    handled: bool,
+    // Whether the persona has handled anything since its state was last saved.
+    unsaved: bool,
+}
+
+impl Worker {
+    pub fn new(persona: Box<Persona>, state_store: Option<StateStore>) -> Worker {
+        Worker {
+            persona: persona,
+            state_store: state_store,
+            handled: false,
+            unsaved: false,
+        }
+    }
+
+    /// Moves the worker onto its own thread.  A `WorkerReport` tagged with `index` is sent after
+    /// each event is handled.
+    pub fn start(self,
+                 index: usize,
+                 routing_node: SharedRoutingNode,
+                 metrics: Metrics,
+                 report_sender: Sender<WorkerReport>)
+                 -> WorkerHandle {
+        let (event_sender, event_receiver) = mpsc::channel();
+        let thread_name = format!("{} worker", self.persona.name());
+        let joiner = thread!(thread_name, move || {
+            self.run(index, routing_node, metrics, event_receiver, report_sender)
+        });
+        WorkerHandle {
+            event_sender: event_sender,
+            joiner: joiner,
+        }
+    }
+
+    fn run(mut self,
+           index: usize,
+           routing_node: SharedRoutingNode,
+           metrics: Metrics,
+           event_receiver: Receiver<WorkerEvent>,
+           report_sender: Sender<WorkerReport>)
+           -> Worker {
+        for event in event_receiver.iter() {
+            self.handled = false;
+            // State is saved at most once per tick, rather than after every event.
+            let save = if let WorkerEvent::Tick = event {
+                true
+            } else {
+                false
+            };
+            match self.handle(&*routing_node, &metrics, event) {
+                Ok(actions) => {
+                    metrics.count_refreshes_sent(self.persona.name(), refresh_count(&actions));
+                    send_actions(&*routing_node, actions);
+                }
+                Err(error) => warn!("Failed to handle event: {:?}", error),
+            }
+            if save {
+                self.save_state();
+            }
+
+            // Every event is reported, so the vault knows when we've caught up, but the status
+            // only needs recomputing if the persona did something.
+            let status = if self.handled {
+                let mut status = Status::default();
+                self.persona.update_status(&mut status);
+                Some(status)
+            } else {
+                None
+            };
+            let report = WorkerReport {
+                index: index,
+                idle: self.persona.is_idle(),
+                status: status,
+            };
+            if report_sender.send(report).is_err() {
+                break;
+            }
+        }
+        self.save_state();
+        self
+    }
+
+    // Records that the persona handled the current event, so may have changed its state.
+    fn mark_handled(&mut self) {
+        self.handled = true;
+        self.unsaved = true;
+    }
+
+    fn save_state(&mut self) {
+        if !self.unsaved {
+            return;
+        }
+        if let Some(ref mut state_store) = self.state_store {
+            if let Err(error) = self.persona.save_state(state_store) {
+                error!("Failed to save {} state: {:?}", self.persona.name(), error);
+                return;
+            }
+        }
+        self.unsaved = false;
+    }
+
+    fn handle(&mut self,
+              routing_node: &RoutingApi,
+              metrics: &Metrics,
+              event: WorkerEvent)
+              -> Result<Vec<Action>, InternalError> {
+        let name = self.persona.name();
+        let actions = match event {
+            WorkerEvent::Request(dispatch) => {
+                let request = &dispatch.message;
+                let kind = request_kind(&request.content);
+                match self.persona.handle_request(routing_node, request) {
+                    Some(result) => {
+                        self.mark_handled();
+                        let succeeded = match result {
+                            Ok(ref actions) => !refuses(request, actions),
+                            Err(_) => false,
+                        };
+                        metrics.count_request(name, kind, succeeded);
+                        try!(result)
+                    }
+                    None => {
+                        if dispatch.decline() {
+                            metrics.count_request("unknown", kind, false);
+                            let message = RoutingMessage::Request(request.clone());
+                            return Err(InternalError::UnknownMessageType(message));
+                        }
+                        vec![]
+                    }
+                }
+            }
+            WorkerEvent::Response(dispatch) => {
+                let response = &dispatch.message;
+                let kind = response_kind(&response.content);
+                match self.persona.handle_response(routing_node, response) {
+                    Some(result) => {
+                        self.mark_handled();
+                        metrics.count_response(name, kind, result.is_ok());
+                        try!(result)
+                    }
+                    None => {
+                        if dispatch.decline() {
+                            metrics.count_response("unknown", kind, false);
+                            let message = RoutingMessage::Response(response.clone());
+                            return Err(InternalError::UnknownMessageType(message));
+                        }
+                        vec![]
+                    }
+                }
+            }
+            WorkerEvent::Refresh(dispatch) => {
+                let message = &dispatch.message;
+                match self.persona.handle_refresh_request(routing_node,
+                                                          &message.src,
+                                                          &message.dst,
+                                                          &message.sender,
+                                                          &message.refresh) {
+                    Some(result) => {
+                        self.mark_handled();
+                        metrics.count_refresh_received(name, result.is_ok());
+                        try!(result)
+                    }
+                    None => {
+                        if dispatch.decline() {
+                            metrics.count_refresh_received("unknown", false);
+                            return Err(InternalError::UnknownRefreshType(message.src.clone(),
+                                                                         message.dst.clone(),
+                                                                         message.refresh
+                                                                                .clone()));
+                        }
+                        vec![]
+                    }
+                }
+            }
+            WorkerEvent::NodeAdded(node_added) => {
+                self.mark_handled();
+                self.persona.handle_node_added(routing_node, node_added)
+            }
+            WorkerEvent::NodeLost(node_lost) => {
+                self.mark_handled();
+                self.persona.handle_node_lost(routing_node, node_lost)
+            }
+            WorkerEvent::Churn => {
+                self.mark_handled();
+                self.persona.handle_churn(routing_node)
+            }
+            WorkerEvent::Connected => {
+                self.mark_handled();
+                try!(self.persona.handle_connected(routing_node))
+            }
+            WorkerEvent::ConfigChanged(config) => {
+                self.mark_handled();
+                try!(self.persona.handle_config_changed(routing_node, &config))
+            }
+            WorkerEvent::Tick => {
+                // Most ticks find nothing to time out, and leave the persona as it was.
+                let actions = try!(self.persona.handle_tick(routing_node));
+                if !actions.is_empty() {
+                    self.mark_handled();
+                }
+                actions
+            }
+        };
+        Ok(actions)
+    }
+}
+
+pub struct WorkerHandle {
+    event_sender: Sender<WorkerEvent>,
+    joiner: JoinHandle<Worker>,
+}
+
+impl WorkerHandle {
+    pub fn send(&self, event: WorkerEvent) {
+        if self.event_sender.send(event).is_err() {
+            error!("Worker thread has gone away.");
+        }
+    }
+
+    /// Waits for the worker to handle all events sent so far, then returns it.  Returns `None` if
+    /// the worker's thread panicked.
+    pub fn stop(self) -> Option<Worker> {
+        drop(self.event_sender);
+        match self.joiner.join() {
+            Ok(worker) => Some(worker),
+            Err(_) => {
+                error!("Worker thread panicked.");
+                None
+            }
+        }
+    }
+}
+
+// Carries out the actions returned by a persona.  A failure to send one doesn't stop the rest.
+pub fn send_actions(routing_node: &RoutingApi, actions: Vec<Action>) {
+    for action in actions {
+        if let Err(error) = send(routing_node, action) {
+            warn!("Failed to send action: {:?}", error);
+        }
+    }
+}
+
+fn send(routing_node: &RoutingApi, action: Action) -> Result<(), InternalError> {
+    match action {
+        Action::SendRequest(RequestMessage { src, dst, content }) => {
+            match content {
+                RequestContent::Get(data_request, id) => {
+                    Ok(try!(routing_node.send_get_request(src, dst, data_request, id)))
+                }
+                RequestContent::Put(data, id) => {
+                    Ok(try!(routing_node.send_put_request(src, dst, data, id)))
+                }
+                RequestContent::Post(data, id) => {
+                    Ok(try!(routing_node.send_post_request(src, dst, data, id)))
+                }
+                RequestContent::Delete(data, id) => {
+                    Ok(try!(routing_node.send_delete_request(src, dst, data, id)))
+                }
+                content => {
+                    let request = RequestMessage {
+                        src: src,
+                        dst: dst,
+                        content: content,
+                    };
+                    Err(InternalError::UnknownMessageType(RoutingMessage::Request(request)))
+                }
+            }
+        }
+        Action::SendResponse(ResponseMessage { src, dst, content }) => {
+            match content {
+                ResponseContent::GetSuccess(data, id) => {
+                    Ok(try!(routing_node.send_get_success(src, dst, data, id)))
+                }
+                ResponseContent::PutSuccess(digest, id) => {
+                    Ok(try!(routing_node.send_put_success(src, dst, digest, id)))
+                }
+                ResponseContent::PostSuccess(digest, id) => {
+                    Ok(try!(routing_node.send_post_success(src, dst, digest, id)))
+                }
+                ResponseContent::DeleteSuccess(digest, id) => {
+                    Ok(try!(routing_node.send_delete_success(src, dst, digest, id)))
+                }
+                ResponseContent::GetFailure { id, request, external_error_indicator } => {
+                    Ok(try!(routing_node.send_get_failure(src,
+                                                          dst,
+                                                          request,
+                                                          external_error_indicator,
+                                                          id)))
+                }
+                ResponseContent::PutFailure { id, request, external_error_indicator } => {
+                    Ok(try!(routing_node.send_put_failure(src,
+                                                          dst,
+                                                          request,
+                                                          external_error_indicator,
+                                                          id)))
+                }
+                ResponseContent::PostFailure { id, request, external_error_indicator } => {
+                    Ok(try!(routing_node.send_post_failure(src,
+                                                           dst,
+                                                           request,
+                                                           external_error_indicator,
+                                                           id)))
+                }
+                ResponseContent::DeleteFailure { id, request, external_error_indicator } => {
+                    Ok(try!(routing_node.send_delete_failure(src,
+                                                             dst,
+                                                             request,
+                                                             external_error_indicator,
+                                                             id)))
+                }
+                content => {
+                    let response = ResponseMessage {
+                        src: src,
+                        dst: dst,
+                        content: content,
+                    };
+                    Err(InternalError::UnknownMessageType(RoutingMessage::Response(response)))
+                }
+            }
+        }
+        Action::SendRefresh(src, refresh) => {
+            let envelope = RefreshEnvelope {
+                sender: try!(routing_node.name()),
+                refresh: refresh,
+            };
+            let serialised_refresh = try!(serialisation::serialise(&envelope));
+            Ok(try!(routing_node.send_refresh_request(src, serialised_refresh)))
+        }
+    }
+}
+
+// Returns true if the actions include a failure response to `request`, i.e. the persona handled it
+// by refusing it.
+fn refuses(request: &RequestMessage, actions: &[Action]) -> bool {
+    actions.iter().any(|action| {
+        let response = match *action {
+            Action::SendResponse(ref response) => response,
+            _ => return false,
+        };
+        match response.content {
+            ResponseContent::GetFailure{ request: ref failed, .. } |
+            ResponseContent::PutFailure{ request: ref failed, .. } |
+            ResponseContent::PostFailure{ request: ref failed, .. } |
+            ResponseContent::DeleteFailure{ request: ref failed, .. } => failed == request,
+            _ => false,
+        }
+    })
+}
+
+// Number of the actions which are refreshes.  Churn can also prompt requests, e.g. to replicate
+// data held by a lost node.
+fn refresh_count(actions: &[Action]) -> usize {
+    actions.iter()
+           .filter(|action| {
+               match **action {
+                   Action::SendRefresh(..) => true,
+                   _ => false,
+               }
+           })
+           .count()
+}
+
+// Label used for a request in the metrics.
+fn request_kind(content: &RequestContent) -> &'static str {
+    match *content {
+        RequestContent::Get(..) => "get",
+        RequestContent::Put(..) => "put",
+        RequestContent::Post(..) => "post",
+        RequestContent::Delete(..) => "delete",
+        RequestContent::Refresh(..) => "refresh",
+        _ => "other",
+    }
+}
+
+// Label used for a response in the metrics.
+fn response_kind(content: &ResponseContent) -> &'static str {
+    match *content {
+        ResponseContent::GetSuccess(..) => "get_success",
+        ResponseContent::PutSuccess(..) => "put_success",
+        ResponseContent::PostSuccess(..) => "post_success",
+        ResponseContent::DeleteSuccess(..) => "delete_success",
+        ResponseContent::GetFailure{ .. } => "get_failure",
+        ResponseContent::PutFailure{ .. } => "put_failure",
+        ResponseContent::PostFailure{ .. } => "post_failure",
+        ResponseContent::DeleteFailure{ .. } => "delete_failure",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use error::InternalError;
+    use metrics::Metrics;
+    use mock_routing::MockRoutingNode;
+    use personas::Persona;
+    use rand::random;
+    use routing::{Authority, DataRequest, ImmutableDataType, MessageId, RequestContent,
+                  RequestMessage, ResponseContent};
+    use routing_api::RoutingApi;
+    use std::sync::{Arc, Barrier, Mutex, mpsc};
+    use types::Action;
+    use xor_name::XorName;
+
+    // Records the destination of each request it handles, optionally waiting at a barrier first.
+    struct RecordingPersona {
+        handled: Arc<Mutex<Vec<XorName>>>,
+        barrier: Option<Arc<Barrier>>,
+    }
+
+    impl Persona for RecordingPersona {
+        fn name(&self) -> &'static str {
+            "RecordingPersona"
+        }
+
+        fn handle_request(&mut self,
+                          _routing_node: &RoutingApi,
+                          request: &RequestMessage)
+                          -> Option<Result<Vec<Action>, InternalError>> {
+            if let Some(ref barrier) = self.barrier {
+                let _ = barrier.wait();
+            }
+            unwrap_result!(self.handled.lock()).push(request.dst.name().clone());
+            Some(Ok(vec![]))
+        }
+    }
+
+    // Declines every message.
+    struct DecliningPersona;
+
+    impl Persona for DecliningPersona {
+        fn name(&self) -> &'static str {
+            "DecliningPersona"
+        }
+    }
+
+    fn shared_routing_node() -> SharedRoutingNode {
+        let (sender, _receiver) = mpsc::channel();
+        let routing_node: Box<RoutingApi + Send> =
+            Box::new(unwrap_result!(MockRoutingNode::new(sender)));
+        Arc::new(Mutex::new(routing_node))
+    }
+
+    fn request(name: XorName) -> RequestMessage {
+        RequestMessage {
+            src: Authority::ClientManager(random()),
+            dst: Authority::NaeManager(name.clone()),
+            content: RequestContent::Get(DataRequest::Immutable(name, ImmutableDataType::Normal),
+                                         MessageId::new()),
+        }
+    }
+
+    #[test]
+    fn last_decline_reports_unhandled() {
+        let dispatch = Dispatch::new((), 3);
+        assert!(!dispatch.decline());
+        assert!(!dispatch.decline());
+        assert!(dispatch.decline());
+    }
+
+    #[test]
+    fn events_are_handled_in_order() {
+        let handled = Arc::new(Mutex::new(vec![]));
+        let persona = RecordingPersona {
+            handled: handled.clone(),
+            barrier: None,
+        };
+        let (report_sender, report_receiver) = mpsc::channel();
+        let handle = Worker::new(Box::new(persona), None)
+                         .start(0,
+                                shared_routing_node(),
+                                unwrap_result!(Metrics::new(None)),
+                                report_sender);
+
+        let names = (0..100).map(|_| random::<XorName>()).collect::<Vec<_>>();
+        for name in names.iter() {
+            handle.send(WorkerEvent::Request(Dispatch::new(request(name.clone()), 1)));
+        }
+        assert!(handle.stop().is_some());
+
+        assert_eq!(*unwrap_result!(handled.lock()), names);
+        let reports = report_receiver.iter().collect::<Vec<_>>();
+        assert_eq!(reports.len(), names.len());
+        assert!(reports.iter().all(|report| report.index == 0 && report.status.is_some()));
+    }
+
+    #[test]
+    fn workers_run_concurrently() {
+        // Each persona blocks until the other reaches the barrier too, so this only completes if
+        // both workers are handling the request at the same time.
+        let barrier = Arc::new(Barrier::new(2));
+        let routing_node = shared_routing_node();
+        let metrics = unwrap_result!(Metrics::new(None));
+        let (report_sender, report_receiver) = mpsc::channel();
+        let name = random::<XorName>();
+        let dispatch = Dispatch::new(request(name.clone()), 2);
+
+        let mut handled = vec![];
+        let mut handles = vec![];
+        for index in 0..2 {
+            let persona = RecordingPersona {
+                handled: Arc::new(Mutex::new(vec![])),
+                barrier: Some(barrier.clone()),
+            };
+            handled.push(persona.handled.clone());
+            let worker = Worker::new(Box::new(persona), None);
+            let handle = worker.start(index,
+                                      routing_node.clone(),
+                                      metrics.clone(),
+                                      report_sender.clone());
+            handle.send(WorkerEvent::Request(dispatch.clone()));
+            handles.push(handle);
+        }
+        drop(report_sender);
+
+        for handle in handles {
+            assert!(handle.stop().is_some());
+        }
+        for handled in handled {
+            assert_eq!(*unwrap_result!(handled.lock()), vec![name.clone()]);
+        }
+        assert_eq!(report_receiver.iter().count(), 2);
+    }
+
+    #[test]
+    fn declined_events_report_no_status() {
+        let (report_sender, report_receiver) = mpsc::channel();
+        let handle = Worker::new(Box::new(DecliningPersona), None)
+                         .start(0,
+                                shared_routing_node(),
+                                unwrap_result!(Metrics::new(None)),
+                                report_sender);
+        handle.send(WorkerEvent::Request(Dispatch::new(request(random()), 1)));
+        handle.send(WorkerEvent::Tick);
+        assert!(handle.stop().is_some());
+
+        let reports = report_receiver.iter().collect::<Vec<_>>();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|report| report.status.is_none() && report.idle));
+    }
+
+    #[test]
+    fn failure_responses_mark_requests_refused() {
+        let refused = request(random());
+        let failure = |request: &RequestMessage| {
+            let content = ResponseContent::GetFailure {
+                id: MessageId::new(),
+                request: request.clone(),
+                external_error_indicator: vec![],
+            };
+            Action::response(request.dst.clone(), request.src.clone(), content)
+        };
+        assert!(!super::refuses(&refused, &[]));
+        assert!(super::refuses(&refused, &[failure(&refused)]));
+
+        // A failure sent on behalf of some other request doesn't count
+        assert!(!super::refuses(&refused, &[failure(&request(random()))]));
+    }
+}